@@ -0,0 +1,252 @@
+use bevy::math::{Mat4, Vec3, Vec4, Vec4Swizzles};
+
+/// Axis aligned bounding box
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub center: Vec3,
+    pub half_extents: Vec3,
+}
+
+impl Aabb {
+    pub fn from_min_max(min: Vec3, max: Vec3) -> Self {
+        Self {
+            center: (min + max) * 0.5,
+            half_extents: (max - min) * 0.5,
+        }
+    }
+
+    /// An empty box at the origin if there are no points
+    pub fn from_points(points: impl Iterator<Item = Vec3>) -> Self {
+        let (min, max) = points.fold(
+            (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+            |(min, max), p| (min.min(p), max.max(p)),
+        );
+        if min.x > max.x {
+            return Self::from_min_max(Vec3::ZERO, Vec3::ZERO);
+        }
+        Self::from_min_max(min, max)
+    }
+
+    pub fn min(&self) -> Vec3 {
+        self.center - self.half_extents
+    }
+
+    pub fn max(&self) -> Vec3 {
+        self.center + self.half_extents
+    }
+
+    /// The box that contains this box once transformed
+    pub fn transform(&self, matrix: &Mat4) -> Self {
+        let abs_matrix = [matrix.x_axis, matrix.y_axis, matrix.z_axis].map(|axis| axis.xyz().abs());
+        Self {
+            center: matrix.transform_point3(self.center),
+            half_extents: abs_matrix[0] * self.half_extents.x
+                + abs_matrix[1] * self.half_extents.y
+                + abs_matrix[2] * self.half_extents.z,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sphere {
+    pub center: Vec3,
+    pub radius: f32,
+}
+
+impl Sphere {
+    /// A sphere around the center of the points
+    pub fn from_points(center: Vec3, points: impl Iterator<Item = Vec3>) -> Self {
+        Self {
+            center,
+            radius: points.map(|p| p.distance(center)).fold(0.0, f32::max),
+        }
+    }
+
+    /// The sphere that contains this sphere once transformed
+    pub fn transform(&self, matrix: &Mat4) -> Self {
+        let scale = matrix
+            .x_axis
+            .xyz()
+            .length()
+            .max(matrix.y_axis.xyz().length())
+            .max(matrix.z_axis.xyz().length());
+        Self {
+            center: matrix.transform_point3(self.center),
+            radius: self.radius * scale,
+        }
+    }
+}
+
+/// The planes of the volume seen by a camera.
+/// The normals point inside the frustum
+#[derive(Debug, Clone, Copy)]
+pub struct Frustum {
+    pub planes: [Vec4; 6],
+}
+
+impl Frustum {
+    /// Extracts the planes from a view projection matrix with a depth range of 0 to 1
+    /// <https://www.gamedevs.org/uploads/fast-extraction-viewing-frustum-planes-from-world-view-projection-matrix.pdf>
+    pub fn from_view_projection(view_projection: &Mat4) -> Self {
+        let row = |i| view_projection.row(i);
+        let planes = [
+            // Left
+            row(3) + row(0),
+            // Right
+            row(3) - row(0),
+            // Bottom
+            row(3) + row(1),
+            // Top
+            row(3) - row(1),
+            // Near
+            row(2),
+            // Far
+            row(3) - row(2),
+        ]
+        .map(|plane| plane / plane.xyz().length());
+        Self { planes }
+    }
+
+    pub fn intersects_sphere(&self, sphere: &Sphere) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| plane.xyz().dot(sphere.center) + plane.w >= -sphere.radius)
+    }
+
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // The extent of the box along the normal of the plane
+            let radius = aabb.half_extents.dot(plane.xyz().abs());
+            plane.xyz().dot(aabb.center) + plane.w >= -radius
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::math::Quat;
+
+    fn assert_vec3_eq(a: Vec3, b: Vec3) {
+        assert!(a.abs_diff_eq(b, 1e-5), "{a} != {b}");
+    }
+
+    #[test]
+    fn aabb_transform_translation_and_scale() {
+        let aabb = Aabb::from_min_max(Vec3::new(-1.0, 0.0, -2.0), Vec3::new(1.0, 2.0, 2.0));
+        let matrix = Mat4::from_scale_rotation_translation(
+            Vec3::new(2.0, 3.0, 0.5),
+            Quat::IDENTITY,
+            Vec3::X,
+        );
+        let transformed = aabb.transform(&matrix);
+        assert_vec3_eq(transformed.min(), Vec3::new(-1.0, 0.0, -1.0));
+        assert_vec3_eq(transformed.max(), Vec3::new(3.0, 6.0, 1.0));
+    }
+
+    #[test]
+    fn aabb_transform_rotation() {
+        let aabb = Aabb::from_min_max(Vec3::new(-1.0, -2.0, -3.0), Vec3::new(1.0, 2.0, 3.0));
+
+        // A quarter turn swaps the x and z extents
+        let quarter_turn = Mat4::from_rotation_y(std::f32::consts::FRAC_PI_2);
+        let transformed = aabb.transform(&quarter_turn);
+        assert_vec3_eq(transformed.center, Vec3::ZERO);
+        assert_vec3_eq(transformed.half_extents, Vec3::new(3.0, 2.0, 1.0));
+
+        // At 45 degrees the box must grow to contain every rotated corner
+        let matrix = Mat4::from_rotation_translation(
+            Quat::from_rotation_z(std::f32::consts::FRAC_PI_4),
+            Vec3::new(5.0, 0.0, 0.0),
+        );
+        let transformed = aabb.transform(&matrix);
+        let corners = (0..8).map(|i| {
+            let sign = Vec3::new(
+                if i & 1 == 0 { -1.0 } else { 1.0 },
+                if i & 2 == 0 { -1.0 } else { 1.0 },
+                if i & 4 == 0 { -1.0 } else { 1.0 },
+            );
+            matrix.transform_point3(aabb.center + aabb.half_extents * sign)
+        });
+        let expected = Aabb::from_points(corners);
+        assert_vec3_eq(transformed.center, expected.center);
+        assert_vec3_eq(transformed.half_extents, expected.half_extents);
+    }
+
+    /// A 90 degree frustum from the origin looking down -z, the half width is the distance to the camera
+    fn perspective_frustum() -> Frustum {
+        let projection = Mat4::perspective_rh(std::f32::consts::FRAC_PI_2, 1.0, 0.1, 100.0);
+        Frustum::from_view_projection(&projection)
+    }
+
+    #[test]
+    fn frustum_aabb_inside() {
+        let frustum = perspective_frustum();
+        let aabb = Aabb {
+            center: Vec3::new(0.0, 0.0, -10.0),
+            half_extents: Vec3::ONE,
+        };
+        assert!(frustum.intersects_aabb(&aabb));
+        let aabb = Aabb {
+            center: Vec3::new(8.0, -8.0, -10.0),
+            half_extents: Vec3::ONE,
+        };
+        assert!(frustum.intersects_aabb(&aabb));
+    }
+
+    #[test]
+    fn frustum_aabb_outside() {
+        let frustum = perspective_frustum();
+        for center in [
+            // Behind the camera
+            Vec3::new(0.0, 0.0, 10.0),
+            // Left, right, bottom and top
+            Vec3::new(-15.0, 0.0, -10.0),
+            Vec3::new(15.0, 0.0, -10.0),
+            Vec3::new(0.0, -15.0, -10.0),
+            Vec3::new(0.0, 15.0, -10.0),
+            // Past the far plane
+            Vec3::new(0.0, 0.0, -150.0),
+        ] {
+            let aabb = Aabb {
+                center,
+                half_extents: Vec3::ONE,
+            };
+            assert!(!frustum.intersects_aabb(&aabb), "{center} is visible");
+        }
+    }
+
+    #[test]
+    fn frustum_aabb_straddling() {
+        let frustum = perspective_frustum();
+        for center in [
+            // Across the right plane
+            Vec3::new(10.5, 0.0, -10.0),
+            // Across the near plane
+            Vec3::new(0.0, 0.0, 0.0),
+            // Across the far plane
+            Vec3::new(0.0, 0.0, -100.5),
+        ] {
+            let aabb = Aabb {
+                center,
+                half_extents: Vec3::ONE,
+            };
+            assert!(frustum.intersects_aabb(&aabb), "{center} is culled");
+        }
+    }
+
+    #[test]
+    fn frustum_follows_view() {
+        // Looking down +x from the origin
+        let view = Mat4::look_at_rh(Vec3::ZERO, Vec3::X, Vec3::Y);
+        let projection = Mat4::perspective_rh(std::f32::consts::FRAC_PI_2, 1.0, 0.1, 100.0);
+        let frustum = Frustum::from_view_projection(&(projection * view));
+        let aabb = |center| Aabb {
+            center,
+            half_extents: Vec3::ONE,
+        };
+        assert!(frustum.intersects_aabb(&aabb(Vec3::new(10.0, 0.0, 0.0))));
+        assert!(!frustum.intersects_aabb(&aabb(Vec3::new(0.0, 0.0, -10.0))));
+        assert!(!frustum.intersects_aabb(&aabb(Vec3::new(-10.0, 0.0, 0.0))));
+    }
+}
@@ -1,6 +1,8 @@
 use bevy::{input::mouse::MouseMotion, prelude::*};
 
-use crate::{renderer::bind_groups::mesh_view::CameraUniform, CameraSettings, CAMERRA_EYE};
+use crate::{
+    raycast::Ray, renderer::bind_groups::mesh_view::CameraUniform, CameraSettings, CAMERRA_EYE,
+};
 
 const FRICTION: f32 = 0.5;
 
@@ -53,9 +55,23 @@ impl Camera {
     }
 
     pub fn build_view_projection_matrix(&self) -> Mat4 {
-        let view = Mat4::from_rotation_translation(self.rotation, self.eye);
         let proj = self.projection.compute_matrix();
-        proj * view.inverse()
+        proj * self.build_view_matrix()
+    }
+
+    /// Transforms from world space to view space
+    pub fn build_view_matrix(&self) -> Mat4 {
+        Mat4::from_rotation_translation(self.rotation, self.eye).inverse()
+    }
+
+    /// The ray going through a position on the viewport, from the near plane to the far plane.
+    /// The position starts at the bottom left like the cursor position of a bevy `Window`
+    pub fn viewport_to_ray(&self, position: Vec2, viewport_size: Vec2) -> Ray {
+        let ndc = position / viewport_size * 2.0 - Vec2::ONE;
+        let ndc_to_world = self.build_view_projection_matrix().inverse();
+        let near = ndc_to_world.project_point3(ndc.extend(0.0));
+        let far = ndc_to_world.project_point3(ndc.extend(1.0));
+        Ray::new(near, far - near)
     }
 
     #[inline]
@@ -0,0 +1,812 @@
+use std::{borrow::Cow, io::Read};
+
+use basis_universal::{
+    DecodeFlags, LowLevelUastcTranscoder, SliceParametersUastc, TranscoderBlockFormat,
+};
+use image::{Rgba, RgbaImage};
+use ktx2::{
+    BasicDataFormatDescriptor, ColorModel, DataFormatDescriptorHeader, Format,
+    SupercompressionScheme, TransferFunction,
+};
+
+/// An image stored in a gpu block compressed format, usually loaded from a KTX2 file
+#[derive(Debug, Clone)]
+pub struct CompressedImage {
+    pub width: u32,
+    pub height: u32,
+    pub format: CompressedFormat,
+    /// The data of every mip level, starting with the full size image
+    pub levels: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompressedFormat {
+    /// A format that can be uploaded as is when the device supports it
+    Gpu(wgpu::TextureFormat),
+    /// Basis Universal UASTC, it's transcoded to a format supported by the device before uploading
+    Uastc { srgb: bool },
+}
+
+impl CompressedFormat {
+    /// The size in pixels of the blocks of the format
+    fn block_dimensions(self) -> (u32, u32) {
+        match self {
+            CompressedFormat::Gpu(format) => {
+                let (width, height) = format.describe().block_dimensions;
+                (width as u32, height as u32)
+            }
+            CompressedFormat::Uastc { .. } => (4, 4),
+        }
+    }
+}
+
+/// The data that will actually be uploaded for a `CompressedImage`
+pub enum UploadData<'a> {
+    Compressed {
+        format: wgpu::TextureFormat,
+        levels: Cow<'a, [Vec<u8>]>,
+    },
+    /// Used when the device doesn't support any compressed format usable by the image
+    Rgba(RgbaImage, wgpu::TextureFormat),
+}
+
+pub fn is_ktx2(path: &str) -> bool {
+    path.to_lowercase().ends_with(".ktx2")
+}
+
+/// Loads a 2d KTX2 texture with all its mip levels.
+/// The levels can be uncompressed or Zstandard supercompressed. Supported formats are rgba8,
+/// the BC, ETC2, EAC and ASTC block formats and Basis Universal UASTC.
+///
+/// Basis Universal ETC1S textures can't be loaded because the basis-universal crate only exposes
+/// a low level transcoder for UASTC. They return an error and need to be encoded as UASTC instead.
+pub fn load_ktx2(bytes: &[u8]) -> anyhow::Result<CompressedImage> {
+    let ktx2 = ktx2::Reader::new(bytes)?;
+    let header = ktx2.header();
+    if header.pixel_depth > 1 || header.layer_count > 1 || header.face_count > 1 {
+        anyhow::bail!("Only 2d KTX2 textures are supported");
+    }
+
+    let format = match header.format {
+        Some(format) => CompressedFormat::Gpu(texture_format(format)?),
+        // Basis Universal textures don't have a vulkan format, they are described by the data format descriptor
+        None => {
+            let descriptor = ktx2
+                .data_format_descriptors()
+                .find(|descriptor| descriptor.header == DataFormatDescriptorHeader::BASIC)
+                .ok_or_else(|| anyhow::anyhow!("Missing KTX2 data format descriptor"))?;
+            let descriptor = BasicDataFormatDescriptor::parse(descriptor.data)?;
+            let srgb = descriptor.transfer_function == Some(TransferFunction::SRGB);
+            match descriptor.color_model {
+                Some(ColorModel::UASTC) => CompressedFormat::Uastc { srgb },
+                Some(ColorModel::ETC1S) => {
+                    anyhow::bail!(
+                        "Basis Universal ETC1S can't be transcoded, encode the texture with UASTC instead"
+                    )
+                }
+                color_model => anyhow::bail!("Unsupported KTX2 color model {color_model:?}"),
+            }
+        }
+    };
+
+    let (width, height) = (header.pixel_width, header.pixel_height.max(1));
+    let (block_width, block_height) = format.block_dimensions();
+    if width % block_width != 0 || height % block_height != 0 {
+        anyhow::bail!(
+            "The size of the KTX2 texture {width}x{height} must be a multiple of the {block_width}x{block_height} blocks of {format:?}"
+        );
+    }
+
+    let levels = ktx2
+        .levels()
+        .map(|level| supercompression_decode(level, header.supercompression_scheme))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(CompressedImage {
+        width,
+        height,
+        format,
+        levels,
+    })
+}
+
+fn supercompression_decode(
+    level: &[u8],
+    scheme: Option<SupercompressionScheme>,
+) -> anyhow::Result<Vec<u8>> {
+    match scheme {
+        None => Ok(level.to_vec()),
+        Some(SupercompressionScheme::Zstandard) => {
+            let mut cursor = std::io::Cursor::new(level);
+            let mut decoder =
+                ruzstd::StreamingDecoder::new(&mut cursor).map_err(anyhow::Error::msg)?;
+            let mut data = Vec::new();
+            decoder.read_to_end(&mut data)?;
+            Ok(data)
+        }
+        Some(scheme) => anyhow::bail!("Unsupported KTX2 supercompression scheme {scheme:?}"),
+    }
+}
+
+fn texture_format(format: Format) -> anyhow::Result<wgpu::TextureFormat> {
+    use wgpu::TextureFormat::*;
+
+    Ok(match format {
+        Format::R8G8B8A8_UNORM => Rgba8Unorm,
+        Format::R8G8B8A8_SRGB => Rgba8UnormSrgb,
+        Format::BC1_RGB_UNORM_BLOCK | Format::BC1_RGBA_UNORM_BLOCK => Bc1RgbaUnorm,
+        Format::BC1_RGB_SRGB_BLOCK | Format::BC1_RGBA_SRGB_BLOCK => Bc1RgbaUnormSrgb,
+        Format::BC2_UNORM_BLOCK => Bc2RgbaUnorm,
+        Format::BC2_SRGB_BLOCK => Bc2RgbaUnormSrgb,
+        Format::BC3_UNORM_BLOCK => Bc3RgbaUnorm,
+        Format::BC3_SRGB_BLOCK => Bc3RgbaUnormSrgb,
+        Format::BC4_UNORM_BLOCK => Bc4RUnorm,
+        Format::BC4_SNORM_BLOCK => Bc4RSnorm,
+        Format::BC5_UNORM_BLOCK => Bc5RgUnorm,
+        Format::BC5_SNORM_BLOCK => Bc5RgSnorm,
+        Format::BC6H_UFLOAT_BLOCK => Bc6hRgbUfloat,
+        Format::BC6H_SFLOAT_BLOCK => Bc6hRgbSfloat,
+        Format::BC7_UNORM_BLOCK => Bc7RgbaUnorm,
+        Format::BC7_SRGB_BLOCK => Bc7RgbaUnormSrgb,
+        Format::ETC2_R8G8B8_UNORM_BLOCK => Etc2Rgb8Unorm,
+        Format::ETC2_R8G8B8_SRGB_BLOCK => Etc2Rgb8UnormSrgb,
+        Format::ETC2_R8G8B8A1_UNORM_BLOCK => Etc2Rgb8A1Unorm,
+        Format::ETC2_R8G8B8A1_SRGB_BLOCK => Etc2Rgb8A1UnormSrgb,
+        Format::ETC2_R8G8B8A8_UNORM_BLOCK => Etc2Rgba8Unorm,
+        Format::ETC2_R8G8B8A8_SRGB_BLOCK => Etc2Rgba8UnormSrgb,
+        Format::EAC_R11_UNORM_BLOCK => EacR11Unorm,
+        Format::EAC_R11_SNORM_BLOCK => EacR11Snorm,
+        Format::EAC_R11G11_UNORM_BLOCK => EacRg11Unorm,
+        Format::EAC_R11G11_SNORM_BLOCK => EacRg11Snorm,
+        Format::ASTC_4x4_UNORM_BLOCK => Astc4x4RgbaUnorm,
+        Format::ASTC_4x4_SRGB_BLOCK => Astc4x4RgbaUnormSrgb,
+        Format::ASTC_5x4_UNORM_BLOCK => Astc5x4RgbaUnorm,
+        Format::ASTC_5x4_SRGB_BLOCK => Astc5x4RgbaUnormSrgb,
+        Format::ASTC_5x5_UNORM_BLOCK => Astc5x5RgbaUnorm,
+        Format::ASTC_5x5_SRGB_BLOCK => Astc5x5RgbaUnormSrgb,
+        Format::ASTC_6x5_UNORM_BLOCK => Astc6x5RgbaUnorm,
+        Format::ASTC_6x5_SRGB_BLOCK => Astc6x5RgbaUnormSrgb,
+        Format::ASTC_6x6_UNORM_BLOCK => Astc6x6RgbaUnorm,
+        Format::ASTC_6x6_SRGB_BLOCK => Astc6x6RgbaUnormSrgb,
+        Format::ASTC_8x5_UNORM_BLOCK => Astc8x5RgbaUnorm,
+        Format::ASTC_8x5_SRGB_BLOCK => Astc8x5RgbaUnormSrgb,
+        Format::ASTC_8x6_UNORM_BLOCK => Astc8x6RgbaUnorm,
+        Format::ASTC_8x6_SRGB_BLOCK => Astc8x6RgbaUnormSrgb,
+        Format::ASTC_8x8_UNORM_BLOCK => Astc8x8RgbaUnorm,
+        Format::ASTC_8x8_SRGB_BLOCK => Astc8x8RgbaUnormSrgb,
+        Format::ASTC_10x5_UNORM_BLOCK => Astc10x5RgbaUnorm,
+        Format::ASTC_10x5_SRGB_BLOCK => Astc10x5RgbaUnormSrgb,
+        Format::ASTC_10x6_UNORM_BLOCK => Astc10x6RgbaUnorm,
+        Format::ASTC_10x6_SRGB_BLOCK => Astc10x6RgbaUnormSrgb,
+        Format::ASTC_10x8_UNORM_BLOCK => Astc10x8RgbaUnorm,
+        Format::ASTC_10x8_SRGB_BLOCK => Astc10x8RgbaUnormSrgb,
+        Format::ASTC_10x10_UNORM_BLOCK => Astc10x10RgbaUnorm,
+        Format::ASTC_10x10_SRGB_BLOCK => Astc10x10RgbaUnormSrgb,
+        Format::ASTC_12x10_UNORM_BLOCK => Astc12x10RgbaUnorm,
+        Format::ASTC_12x10_SRGB_BLOCK => Astc12x10RgbaUnormSrgb,
+        Format::ASTC_12x12_UNORM_BLOCK => Astc12x12RgbaUnorm,
+        Format::ASTC_12x12_SRGB_BLOCK => Astc12x12RgbaUnormSrgb,
+        format => anyhow::bail!("Unsupported KTX2 format {format:?}"),
+    })
+}
+
+impl CompressedImage {
+    /// Returns an error if `prepare` can't upload the image on a device with these features
+    pub fn check_supported(&self, features: wgpu::Features) -> anyhow::Result<()> {
+        match self.format {
+            CompressedFormat::Gpu(format)
+                if !features.contains(format.describe().required_features)
+                    && !can_decompress(format) =>
+            {
+                anyhow::bail!(
+                    "{format:?} is not supported by the device and can't be decompressed on the cpu"
+                )
+            }
+            // UASTC can always be transcoded to rgba
+            _ => Ok(()),
+        }
+    }
+
+    /// Picks the data to upload based on the features of the device.
+    /// UASTC is transcoded to ASTC, BC7 or ETC2, in that order of preference.
+    /// If the device doesn't support the required compressed format, the image is decompressed on the cpu.
+    /// Only BC1 to BC5 and ETC2/EAC unorm formats can be decompressed, the others return an error.
+    pub fn prepare(&self, features: wgpu::Features) -> anyhow::Result<UploadData> {
+        match self.format {
+            CompressedFormat::Gpu(format) => {
+                if features.contains(format.describe().required_features) {
+                    Ok(UploadData::Compressed {
+                        format,
+                        levels: Cow::Borrowed(&self.levels),
+                    })
+                } else {
+                    log::warn!(
+                        "{format:?} is not supported by the device, decompressing on the cpu"
+                    );
+                    Ok(UploadData::Rgba(
+                        decompress(format, self.width, self.height, &self.levels[0])?,
+                        rgba_format(format.describe().srgb),
+                    ))
+                }
+            }
+            CompressedFormat::Uastc { srgb } => {
+                use wgpu::TextureFormat::*;
+
+                let targets = [
+                    (
+                        wgpu::Features::TEXTURE_COMPRESSION_ASTC_LDR,
+                        TranscoderBlockFormat::ASTC_4x4,
+                        if srgb {
+                            Astc4x4RgbaUnormSrgb
+                        } else {
+                            Astc4x4RgbaUnorm
+                        },
+                    ),
+                    (
+                        wgpu::Features::TEXTURE_COMPRESSION_BC,
+                        TranscoderBlockFormat::BC7,
+                        if srgb { Bc7RgbaUnormSrgb } else { Bc7RgbaUnorm },
+                    ),
+                    (
+                        wgpu::Features::TEXTURE_COMPRESSION_ETC2,
+                        TranscoderBlockFormat::ETC2_RGBA,
+                        if srgb {
+                            Etc2Rgba8UnormSrgb
+                        } else {
+                            Etc2Rgba8Unorm
+                        },
+                    ),
+                ];
+                match targets
+                    .into_iter()
+                    .find(|(feature, ..)| features.contains(*feature))
+                {
+                    Some((_, block_format, format)) => Ok(UploadData::Compressed {
+                        format,
+                        levels: Cow::Owned(self.transcode_uastc(block_format, self.levels.len())?),
+                    }),
+                    None => {
+                        log::warn!("No compressed format is supported by the device, transcoding UASTC to rgba");
+                        let mut levels = self.transcode_uastc(TranscoderBlockFormat::RGBA32, 1)?;
+                        let rgba =
+                            RgbaImage::from_raw(self.width, self.height, levels.remove(0))
+                                .ok_or_else(|| anyhow::anyhow!("Invalid transcoded UASTC image"))?;
+                        Ok(UploadData::Rgba(rgba, rgba_format(srgb)))
+                    }
+                }
+            }
+        }
+    }
+
+    /// Transcodes the first `level_count` mip levels
+    fn transcode_uastc(
+        &self,
+        block_format: TranscoderBlockFormat,
+        level_count: usize,
+    ) -> anyhow::Result<Vec<Vec<u8>>> {
+        // UASTC always uses 4x4 blocks of 16 bytes
+        const BLOCK_SIZE: usize = 16;
+
+        let transcoder = LowLevelUastcTranscoder::new();
+        let mut levels = Vec::with_capacity(level_count);
+        for (level, data) in self.levels.iter().take(level_count).enumerate() {
+            let width = (self.width >> level).max(1);
+            let height = (self.height >> level).max(1);
+            let num_blocks_x = (width + 3) / 4;
+            let num_blocks_y = (height + 3) / 4;
+            let level_size = num_blocks_x as usize * num_blocks_y as usize * BLOCK_SIZE;
+            let data = data
+                .get(..level_size)
+                .ok_or_else(|| anyhow::anyhow!("UASTC level {level} is too short"))?;
+            let transcoded = transcoder
+                .transcode_slice(
+                    data,
+                    SliceParametersUastc {
+                        num_blocks_x,
+                        num_blocks_y,
+                        has_alpha: true,
+                        original_width: width,
+                        original_height: height,
+                    },
+                    DecodeFlags::HIGH_QUALITY,
+                    block_format,
+                )
+                .map_err(|err| {
+                    anyhow::anyhow!("Failed to transcode UASTC level {level}: {err:?}")
+                })?;
+            levels.push(transcoded);
+        }
+        Ok(levels)
+    }
+}
+
+fn rgba_format(srgb: bool) -> wgpu::TextureFormat {
+    if srgb {
+        wgpu::TextureFormat::Rgba8UnormSrgb
+    } else {
+        wgpu::TextureFormat::Rgba8Unorm
+    }
+}
+
+/// The formats supported by `decompress`
+fn can_decompress(format: wgpu::TextureFormat) -> bool {
+    use wgpu::TextureFormat::*;
+
+    matches!(
+        format,
+        Bc1RgbaUnorm
+            | Bc1RgbaUnormSrgb
+            | Bc2RgbaUnorm
+            | Bc2RgbaUnormSrgb
+            | Bc3RgbaUnorm
+            | Bc3RgbaUnormSrgb
+            | Bc4RUnorm
+            | Bc5RgUnorm
+            | Etc2Rgb8Unorm
+            | Etc2Rgb8UnormSrgb
+            | Etc2Rgb8A1Unorm
+            | Etc2Rgb8A1UnormSrgb
+            | Etc2Rgba8Unorm
+            | Etc2Rgba8UnormSrgb
+            | EacR11Unorm
+            | EacRg11Unorm
+    )
+}
+
+/// Decodes a block compressed image on the cpu. Only BC1 to BC5 and ETC2/EAC unorm formats are supported.
+fn decompress(
+    format: wgpu::TextureFormat,
+    width: u32,
+    height: u32,
+    data: &[u8],
+) -> anyhow::Result<RgbaImage> {
+    use wgpu::TextureFormat::*;
+
+    let block_size = format.describe().block_size as usize;
+    let blocks_x = (width + 3) / 4;
+    let blocks_y = (height + 3) / 4;
+
+    let mut image = RgbaImage::new(width, height);
+    for block_y in 0..blocks_y {
+        for block_x in 0..blocks_x {
+            let offset = (block_y * blocks_x + block_x) as usize * block_size;
+            let block = data
+                .get(offset..offset + block_size)
+                .ok_or_else(|| anyhow::anyhow!("Compressed image data is too short"))?;
+
+            let texels = match format {
+                Bc1RgbaUnorm | Bc1RgbaUnormSrgb => decode_bc1(block, true),
+                Bc2RgbaUnorm | Bc2RgbaUnormSrgb => {
+                    let mut texels = decode_bc1(&block[8..], false);
+                    for (i, texel) in texels.iter_mut().enumerate() {
+                        // 4 bits per alpha value
+                        texel[3] = ((block[i / 2] >> (4 * (i % 2))) & 0xF) * 17;
+                    }
+                    texels
+                }
+                Bc3RgbaUnorm | Bc3RgbaUnormSrgb => {
+                    let mut texels = decode_bc1(&block[8..], false);
+                    for (texel, alpha) in texels.iter_mut().zip(decode_bc4(&block[..8])) {
+                        texel[3] = alpha;
+                    }
+                    texels
+                }
+                Bc4RUnorm => decode_bc4(block).map(|r| [r, 0, 0, 255]),
+                Bc5RgUnorm => {
+                    let mut texels = [[0, 0, 0, 255]; 16];
+                    let red = decode_bc4(&block[..8]);
+                    let green = decode_bc4(&block[8..]);
+                    for (i, texel) in texels.iter_mut().enumerate() {
+                        texel[0] = red[i];
+                        texel[1] = green[i];
+                    }
+                    texels
+                }
+                Etc2Rgb8Unorm | Etc2Rgb8UnormSrgb => decode_etc2(block, false),
+                Etc2Rgb8A1Unorm | Etc2Rgb8A1UnormSrgb => decode_etc2(block, true),
+                Etc2Rgba8Unorm | Etc2Rgba8UnormSrgb => {
+                    let mut texels = decode_etc2(&block[8..], false);
+                    for (texel, alpha) in texels.iter_mut().zip(decode_eac(&block[..8], false)) {
+                        texel[3] = alpha;
+                    }
+                    texels
+                }
+                EacR11Unorm => decode_eac(block, true).map(|r| [r, 0, 0, 255]),
+                EacRg11Unorm => {
+                    let mut texels = [[0, 0, 0, 255]; 16];
+                    let red = decode_eac(&block[..8], true);
+                    let green = decode_eac(&block[8..], true);
+                    for (i, texel) in texels.iter_mut().enumerate() {
+                        texel[0] = red[i];
+                        texel[1] = green[i];
+                    }
+                    texels
+                }
+                _ => anyhow::bail!("{format:?} can't be decompressed on the cpu"),
+            };
+
+            for (i, texel) in texels.into_iter().enumerate() {
+                let x = block_x * 4 + i as u32 % 4;
+                let y = block_y * 4 + i as u32 / 4;
+                // Blocks on the edges can go past the size of the image
+                if x < width && y < height {
+                    image.put_pixel(x, y, Rgba(texel));
+                }
+            }
+        }
+    }
+    Ok(image)
+}
+
+/// Decodes the 8 bytes color block used by BC1, BC2 and BC3
+fn decode_bc1(block: &[u8], allow_transparent: bool) -> [[u8; 4]; 16] {
+    let color0 = u16::from_le_bytes([block[0], block[1]]);
+    let color1 = u16::from_le_bytes([block[2], block[3]]);
+    let rgb565 = |color: u16| {
+        [
+            ((color >> 11) & 0x1F) as u32 * 255 / 31,
+            ((color >> 5) & 0x3F) as u32 * 255 / 63,
+            (color & 0x1F) as u32 * 255 / 31,
+        ]
+    };
+    let (a, b) = (rgb565(color0), rgb565(color1));
+    let mix = |weight_a: u32, weight_b: u32| {
+        let mut color = [0, 0, 0, 255];
+        for (i, channel) in color.iter_mut().take(3).enumerate() {
+            *channel = ((a[i] * weight_a + b[i] * weight_b) / (weight_a + weight_b)) as u8;
+        }
+        color
+    };
+
+    let palette = if color0 > color1 || !allow_transparent {
+        [mix(1, 0), mix(0, 1), mix(2, 1), mix(1, 2)]
+    } else {
+        [mix(1, 0), mix(0, 1), mix(1, 1), [0, 0, 0, 0]]
+    };
+
+    let indices = u32::from_le_bytes([block[4], block[5], block[6], block[7]]);
+    let mut texels = [[0; 4]; 16];
+    for (i, texel) in texels.iter_mut().enumerate() {
+        *texel = palette[((indices >> (2 * i)) & 0b11) as usize];
+    }
+    texels
+}
+
+/// Decodes the 8 bytes single channel block used by BC3, BC4 and BC5
+fn decode_bc4(block: &[u8]) -> [u8; 16] {
+    let (r0, r1) = (block[0] as u32, block[1] as u32);
+    let palette = if r0 > r1 {
+        [
+            r0,
+            r1,
+            (6 * r0 + r1) / 7,
+            (5 * r0 + 2 * r1) / 7,
+            (4 * r0 + 3 * r1) / 7,
+            (3 * r0 + 4 * r1) / 7,
+            (2 * r0 + 5 * r1) / 7,
+            (r0 + 6 * r1) / 7,
+        ]
+    } else {
+        [
+            r0,
+            r1,
+            (4 * r0 + r1) / 5,
+            (3 * r0 + 2 * r1) / 5,
+            (2 * r0 + 3 * r1) / 5,
+            (r0 + 4 * r1) / 5,
+            0,
+            255,
+        ]
+    };
+
+    // 16 indices of 3 bits
+    let mut indices = 0u64;
+    for (i, byte) in block[2..8].iter().enumerate() {
+        indices |= (*byte as u64) << (8 * i);
+    }
+    let mut values = [0; 16];
+    for (i, value) in values.iter_mut().enumerate() {
+        *value = palette[((indices >> (3 * i)) & 0b111) as usize] as u8;
+    }
+    values
+}
+
+// Reference: <https://registry.khronos.org/DataFormat/specs/1.3/dataformat.1.3.html#ETC2>
+
+const ETC1_MODIFIERS: [[i32; 2]; 8] = [
+    [2, 8],
+    [5, 17],
+    [9, 29],
+    [13, 42],
+    [18, 60],
+    [24, 80],
+    [33, 106],
+    [47, 183],
+];
+
+const ETC2_DISTANCES: [i32; 8] = [3, 6, 11, 16, 23, 32, 41, 64];
+
+const EAC_MODIFIERS: [[i32; 8]; 16] = [
+    [-3, -6, -9, -15, 2, 5, 8, 14],
+    [-3, -7, -10, -13, 2, 6, 9, 12],
+    [-2, -5, -8, -13, 1, 4, 7, 12],
+    [-2, -4, -6, -13, 1, 3, 5, 12],
+    [-3, -6, -8, -12, 2, 5, 7, 11],
+    [-3, -7, -9, -11, 2, 6, 8, 10],
+    [-4, -7, -8, -11, 3, 6, 7, 10],
+    [-3, -5, -8, -11, 2, 4, 7, 10],
+    [-2, -6, -8, -10, 1, 5, 7, 9],
+    [-2, -5, -8, -10, 1, 4, 7, 9],
+    [-2, -4, -8, -10, 1, 3, 7, 9],
+    [-2, -5, -7, -10, 1, 4, 6, 9],
+    [-3, -4, -7, -10, 2, 3, 6, 9],
+    [-1, -2, -3, -10, 0, 1, 2, 9],
+    [-4, -6, -8, -9, 3, 5, 7, 8],
+    [-3, -5, -7, -9, 2, 4, 6, 8],
+];
+
+/// Expands a color channel of `bits` bits to 8 bits
+fn extend_bits(value: u64, bits: u32) -> i32 {
+    let value = value as i32;
+    (value << (8 - bits)) | (value >> (2 * bits - 8))
+}
+
+fn clamp_color(color: [i32; 3]) -> [u8; 4] {
+    let [r, g, b] = color.map(|channel| channel.clamp(0, 255) as u8);
+    [r, g, b, 255]
+}
+
+/// Decodes the 8 bytes color block used by ETC2 RGB8, RGB8A1 and RGBA8.
+/// `punchthrough` is used by RGB8A1, where the differential bit is replaced by an opaque bit
+fn decode_etc2(block: &[u8], punchthrough: bool) -> [[u8; 4]; 16] {
+    let bits = u64::from_be_bytes(block[..8].try_into().unwrap());
+    let field = |shift: u32, len: u32| (bits >> shift) & ((1 << len) - 1);
+    let differential = field(33, 1) == 1;
+    let opaque = !punchthrough || differential;
+
+    // The pixels are stored column by column, each index is split in a lsb and msb half
+    let index = |i: usize| {
+        let j = (i % 4) * 4 + i / 4;
+        (field(j as u32 + 16, 1) << 1 | field(j as u32, 1)) as usize
+    };
+    let paint = |paints: [[i32; 3]; 4]| {
+        let mut texels = [[0; 4]; 16];
+        for (i, texel) in texels.iter_mut().enumerate() {
+            let index = index(i);
+            if !opaque && index == 2 {
+                continue;
+            }
+            *texel = clamp_color(paints[index]);
+        }
+        texels
+    };
+
+    if !differential && !punchthrough {
+        // Individual mode, two 4 bits colors
+        let color = |shift: u32| {
+            [
+                field(shift + 4, 4),
+                field(shift - 4, 4),
+                field(shift - 12, 4),
+            ]
+            .map(|channel| extend_bits(channel, 4))
+        };
+        return decode_etc1(bits, [color(56), color(52)], opaque, index);
+    }
+
+    // Differential mode, a 5 bits color and a 3 bits signed difference for the second color.
+    // The differences that overflow are used to select the T, H and planar modes
+    let base = [field(59, 5), field(51, 5), field(43, 5)].map(|channel| channel as i32);
+    let delta = [field(56, 3), field(48, 3), field(40, 3)].map(|delta| (delta as i32 ^ 4) - 4);
+    let overflows = |channel: usize| !(0..32).contains(&(base[channel] + delta[channel]));
+
+    if overflows(0) {
+        // T mode
+        let color0 = [field(59, 2) << 2 | field(56, 2), field(52, 4), field(48, 4)]
+            .map(|channel| extend_bits(channel, 4));
+        let color1 =
+            [field(44, 4), field(40, 4), field(36, 4)].map(|channel| extend_bits(channel, 4));
+        let distance = ETC2_DISTANCES[(field(34, 2) << 1 | field(32, 1)) as usize];
+        paint([
+            color0,
+            color1.map(|channel| channel + distance),
+            color1,
+            color1.map(|channel| channel - distance),
+        ])
+    } else if overflows(1) {
+        // H mode
+        let color0 = [
+            field(59, 4),
+            field(56, 3) << 1 | field(52, 1),
+            field(51, 1) << 3 | field(47, 3),
+        ];
+        let color1 = [field(43, 4), field(39, 4), field(35, 4)];
+        let packed = |[r, g, b]: [u64; 3]| r << 8 | g << 4 | b;
+        let order = (packed(color0) >= packed(color1)) as u64;
+        let distance = ETC2_DISTANCES[(field(34, 1) << 2 | field(32, 1) << 1 | order) as usize];
+        let color0 = color0.map(|channel| extend_bits(channel, 4));
+        let color1 = color1.map(|channel| extend_bits(channel, 4));
+        paint([
+            color0.map(|channel| channel + distance),
+            color0.map(|channel| channel - distance),
+            color1.map(|channel| channel + distance),
+            color1.map(|channel| channel - distance),
+        ])
+    } else if overflows(2) {
+        // Planar mode, the colors are interpolated from the origin, horizontal and vertical colors.
+        // It doesn't have indices so it's always opaque
+        let origin = [
+            extend_bits(field(57, 6), 6),
+            extend_bits(field(56, 1) << 6 | field(49, 6), 7),
+            extend_bits(field(48, 1) << 5 | field(43, 2) << 3 | field(39, 3), 6),
+        ];
+        let horizontal = [
+            extend_bits(field(34, 5) << 1 | field(32, 1), 6),
+            extend_bits(field(25, 7), 7),
+            extend_bits(field(19, 6), 6),
+        ];
+        let vertical = [
+            extend_bits(field(13, 6), 6),
+            extend_bits(field(6, 7), 7),
+            extend_bits(field(0, 6), 6),
+        ];
+        let mut texels = [[0; 4]; 16];
+        for (i, texel) in texels.iter_mut().enumerate() {
+            let (x, y) = ((i % 4) as i32, (i / 4) as i32);
+            let mut color = [0; 3];
+            for (c, channel) in color.iter_mut().enumerate() {
+                *channel = (x * (horizontal[c] - origin[c])
+                    + y * (vertical[c] - origin[c])
+                    + 4 * origin[c]
+                    + 2)
+                    >> 2;
+            }
+            *texel = clamp_color(color);
+        }
+        texels
+    } else {
+        let color0 = base.map(|channel| extend_bits(channel as u64, 5));
+        let mut color1 = [0; 3];
+        for (c, channel) in color1.iter_mut().enumerate() {
+            *channel = extend_bits((base[c] + delta[c]) as u64, 5);
+        }
+        decode_etc1(bits, [color0, color1], opaque, index)
+    }
+}
+
+/// The two sub blocks of the individual and differential modes
+fn decode_etc1(
+    bits: u64,
+    colors: [[i32; 3]; 2],
+    opaque: bool,
+    index: impl Fn(usize) -> usize,
+) -> [[u8; 4]; 16] {
+    let tables = [(bits >> 37) & 0b111, (bits >> 34) & 0b111];
+    let flip = (bits >> 32) & 1 == 1;
+
+    let mut texels = [[0; 4]; 16];
+    for (i, texel) in texels.iter_mut().enumerate() {
+        let (x, y) = (i % 4, i / 4);
+        // The sub blocks are either 2x4 side by side or 4x2 on top of each other
+        let sub_block = if flip { y / 2 } else { x / 2 };
+        let [small, large] = ETC1_MODIFIERS[tables[sub_block] as usize];
+        let modifier = match index(i) {
+            // Without the opaque bit, the index 0 doesn't modify the color and 2 is transparent
+            0 if opaque => small,
+            0 => 0,
+            1 => large,
+            2 if opaque => -small,
+            2 => continue,
+            _ => -large,
+        };
+        *texel = clamp_color(colors[sub_block].map(|channel| channel + modifier));
+    }
+    texels
+}
+
+/// Decodes the 8 bytes single channel block used by ETC2 RGBA8 for the alpha and by EAC.
+/// EAC R11 and RG11 have more precision than the 8 bits alpha, it's lost when decoding
+fn decode_eac(block: &[u8], eac_11: bool) -> [u8; 16] {
+    let base = block[0] as i32;
+    let multiplier = (block[1] >> 4) as i32;
+    let modifiers = EAC_MODIFIERS[(block[1] & 0xF) as usize];
+    // 16 indices of 3 bits stored column by column, starting with the most significant bits
+    let indices = u64::from_be_bytes(block[..8].try_into().unwrap());
+
+    let mut values = [0; 16];
+    for (i, value) in values.iter_mut().enumerate() {
+        let j = (i % 4) * 4 + i / 4;
+        let modifier = modifiers[((indices >> (45 - 3 * j)) & 0b111) as usize];
+        *value = if eac_11 {
+            // A multiplier of 0 is treated as 1/8
+            let modifier = if multiplier == 0 {
+                modifier
+            } else {
+                modifier * multiplier * 8
+            };
+            ((base * 8 + 4 + modifier).clamp(0, 2047) >> 3) as u8
+        } else {
+            (base + modifier * multiplier).clamp(0, 255) as u8
+        };
+    }
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bc1() {
+        // Red and blue endpoints, the first row uses the 4 indices
+        let texels = decode_bc1(&[0x00, 0xF8, 0x1F, 0x00, 0b11_10_01_00, 0, 0, 0], true);
+        assert_eq!(texels[0], [255, 0, 0, 255]);
+        assert_eq!(texels[1], [0, 0, 255, 255]);
+        assert_eq!(texels[2], [170, 0, 85, 255]);
+        assert_eq!(texels[3], [85, 0, 170, 255]);
+        assert_eq!(texels[15], [255, 0, 0, 255]);
+
+        // Swapped endpoints use the 3 colors and transparent palette
+        let block = [0x1F, 0x00, 0x00, 0xF8, 0b11_10_01_00, 0, 0, 0];
+        let texels = decode_bc1(&block, true);
+        assert_eq!(texels[2], [127, 0, 127, 255]);
+        assert_eq!(texels[3], [0, 0, 0, 0]);
+        // BC2 and BC3 always use 4 colors
+        assert_eq!(decode_bc1(&block, false)[3], [170, 0, 85, 255]);
+    }
+
+    #[test]
+    fn bc4() {
+        // The first 3 texels use the indices 0, 1 and 2
+        let indices = [1 << 3 | 2 << 6, 0, 0, 0, 0, 0];
+        let block = [[255, 0].as_slice(), &indices].concat();
+        let values = decode_bc4(&block);
+        assert_eq!(values[..4], [255, 0, 218, 255]);
+
+        // r0 <= r1 interpolates 4 values and has explicit 0 and 255
+        let indices = [6 | 7 << 3 | 2 << 6, 0, 0, 0, 0, 0];
+        let block = [[0, 255].as_slice(), &indices].concat();
+        assert_eq!(decode_bc4(&block)[..3], [0, 255, 51]);
+    }
+
+    #[test]
+    fn etc1_individual() {
+        // Two 4 bits colors, white on the left and black on the right, modified by +2
+        let texels = decode_etc2(&[0xF0, 0xF0, 0xF0, 0, 0, 0, 0, 0], false);
+        for (i, texel) in texels.iter().enumerate() {
+            let expected = if i % 4 < 2 { 255 } else { 2 };
+            assert_eq!(*texel, [expected, expected, expected, 255], "texel {i}");
+        }
+    }
+
+    #[test]
+    fn etc1_differential() {
+        // A 5 bits base color of 16 without difference, modified by +2
+        let texels = decode_etc2(&[0x80, 0x80, 0x80, 0b10, 0, 0, 0, 0], false);
+        assert!(texels.iter().all(|texel| *texel == [134, 134, 134, 255]));
+    }
+
+    #[test]
+    fn etc2_punchthrough() {
+        // Without the opaque bit the index 0 doesn't modify the color and 2 is transparent
+        let texels = decode_etc2(&[0x80, 0x80, 0x80, 0, 0, 0, 0, 0], true);
+        assert!(texels.iter().all(|texel| *texel == [132, 132, 132, 255]));
+
+        // The msb of the index of the first texel is the bit 16
+        let texels = decode_etc2(&[0x80, 0x80, 0x80, 0, 0, 1, 0, 0], true);
+        assert_eq!(texels[0], [0, 0, 0, 0]);
+        assert_eq!(texels[1], [132, 132, 132, 255]);
+    }
+
+    #[test]
+    fn eac() {
+        // Base 128, multiplier 1, first modifier table and every index 0
+        let block = [128, 0x10, 0, 0, 0, 0, 0, 0];
+        assert!(decode_eac(&block, false).iter().all(|value| *value == 125));
+
+        // Every index 7 adds 14, scaled by 8 for the 11 bits formats
+        let block = [128, 0x10, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+        assert!(decode_eac(&block, true).iter().all(|value| *value == 142));
+        assert!(decode_eac(&block, false).iter().all(|value| *value == 142));
+    }
+}
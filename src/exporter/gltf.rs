@@ -0,0 +1,472 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::Context;
+use bevy::math::{Mat3, Mat4};
+use gltf::json::{self, validation::Checked, Index};
+
+use crate::{
+    mesh::{Mesh, MeshVertexAttribute, VertexAttributeValues},
+    model::{AlphaMode, Material, MaterialTexture},
+    texture::SamplerDescriptor,
+};
+
+use super::{encode_png, is_white_pixel, ExportMesh};
+
+// References:
+// <https://registry.khronos.org/glTF/specs/2.0/glTF-2.0.html>
+// <https://github.com/KhronosGroup/glTF/tree/main/extensions/2.0/Khronos/KHR_texture_transform>
+
+/// Writes the meshes and materials to a glTF file, the format is chosen from the extension of the path.
+/// A .glb file contains everything, including the textures.
+/// A .gltf file is written with a .bin file for the vertices and a png file for each texture.
+/// Each mesh is a node of the scene with its transform
+pub fn export_gltf(
+    path: &Path,
+    meshes: &[ExportMesh],
+    materials: &[Material],
+) -> anyhow::Result<()> {
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+    let name = path
+        .file_stem()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("Invalid gltf path {path:?}"))?;
+    let binary = matches!(
+        path.extension().and_then(|extension| extension.to_str()),
+        Some(extension) if extension.eq_ignore_ascii_case("glb")
+    );
+
+    let mut builder = GltfBuilder {
+        root: json::Root {
+            asset: json::Asset {
+                generator: Some(env!("CARGO_PKG_NAME").to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        buffer: vec![],
+        name: name.to_string(),
+        binary,
+        images: vec![],
+    };
+    // The materials keep their index so the material_id of the meshes stays valid
+    for material in materials {
+        builder.push_material(material)?;
+    }
+    let nodes = meshes
+        .iter()
+        .map(|mesh| builder.push_node(mesh, materials.len()))
+        .collect();
+    builder.root.scenes.push(json::Scene {
+        extensions: None,
+        extras: Default::default(),
+        name: None,
+        nodes,
+    });
+    builder.root.scene = Some(Index::new(0));
+
+    let GltfBuilder {
+        mut root,
+        buffer,
+        images,
+        ..
+    } = builder;
+    if !buffer.is_empty() {
+        root.buffers.push(json::Buffer {
+            byte_length: buffer.len() as u32,
+            name: None,
+            // The buffer of a .glb is its binary chunk
+            uri: (!binary).then(|| format!("{name}.bin")),
+            extensions: None,
+            extras: Default::default(),
+        });
+    }
+
+    if binary {
+        let json = json::serialize::to_vec(&root)?;
+        let glb = gltf::binary::Glb {
+            // The length is computed when writing
+            header: gltf::binary::Header {
+                magic: *b"glTF",
+                version: 2,
+                length: 0,
+            },
+            json: json.into(),
+            bin: (!buffer.is_empty()).then(|| buffer.into()),
+        };
+        fs::write(path, glb.to_vec()?).with_context(|| format!("Failed to write {path:?}"))?;
+    } else {
+        if !buffer.is_empty() {
+            let bin_path = directory.join(format!("{name}.bin"));
+            fs::write(&bin_path, buffer)
+                .with_context(|| format!("Failed to write {bin_path:?}"))?;
+        }
+        for (file, png) in images {
+            let image_path = directory.join(file);
+            fs::write(&image_path, png)
+                .with_context(|| format!("Failed to write {image_path:?}"))?;
+        }
+        let json = json::serialize::to_string_pretty(&root)?;
+        fs::write(path, json).with_context(|| format!("Failed to write {path:?}"))?;
+    }
+
+    log::info!("Exported {} meshes to {path:?}", meshes.len());
+    Ok(())
+}
+
+struct GltfBuilder {
+    root: json::Root,
+    /// The content of the only buffer of the file
+    buffer: Vec<u8>,
+    /// The name of the exported file, used to name the external files
+    name: String,
+    /// Images are stored in the buffer for .glb files
+    binary: bool,
+    /// The png files to write next to a .gltf file
+    images: Vec<(String, Vec<u8>)>,
+}
+
+impl GltfBuilder {
+    fn push_view(
+        &mut self,
+        bytes: &[u8],
+        target: Option<json::buffer::Target>,
+    ) -> Index<json::buffer::View> {
+        // Accessors must be aligned to the size of their components
+        let padding = (4 - self.buffer.len() % 4) % 4;
+        self.buffer.resize(self.buffer.len() + padding, 0);
+        self.root.buffer_views.push(json::buffer::View {
+            buffer: Index::new(0),
+            byte_length: bytes.len() as u32,
+            byte_offset: Some(self.buffer.len() as u32),
+            byte_stride: None,
+            name: None,
+            target: target.map(Checked::Valid),
+            extensions: None,
+            extras: Default::default(),
+        });
+        self.buffer.extend_from_slice(bytes);
+        Index::new(self.root.buffer_views.len() as u32 - 1)
+    }
+
+    fn push_accessor(
+        &mut self,
+        bytes: &[u8],
+        count: usize,
+        component_type: json::accessor::ComponentType,
+        type_: json::accessor::Type,
+        target: json::buffer::Target,
+    ) -> Index<json::Accessor> {
+        let view = self.push_view(bytes, Some(target));
+        self.root.accessors.push(json::Accessor {
+            buffer_view: Some(view),
+            byte_offset: 0,
+            count: count as u32,
+            component_type: Checked::Valid(json::accessor::GenericComponentType(component_type)),
+            extensions: None,
+            extras: Default::default(),
+            type_: Checked::Valid(type_),
+            min: None,
+            max: None,
+            name: None,
+            normalized: false,
+            sparse: None,
+        });
+        Index::new(self.root.accessors.len() as u32 - 1)
+    }
+
+    fn push_attribute(&mut self, values: &VertexAttributeValues) -> Index<json::Accessor> {
+        use json::accessor::{ComponentType, Type};
+
+        let (component_type, type_) = match values {
+            VertexAttributeValues::Float32x2(_) => (ComponentType::F32, Type::Vec2),
+            VertexAttributeValues::Float32x3(_) => (ComponentType::F32, Type::Vec3),
+            VertexAttributeValues::Float32x4(_) => (ComponentType::F32, Type::Vec4),
+            VertexAttributeValues::Uint16x4(_) => (ComponentType::U16, Type::Vec4),
+        };
+        self.push_accessor(
+            values.as_bytes(),
+            values.len(),
+            component_type,
+            type_,
+            json::buffer::Target::ArrayBuffer,
+        )
+    }
+
+    /// Adds a node with a mesh of a single primitive, the lods aren't exported
+    fn push_node(&mut self, export_mesh: &ExportMesh, material_count: usize) -> Index<json::Node> {
+        let mesh = export_mesh.mesh;
+
+        let mut attributes = HashMap::new();
+        for (attribute, values) in mesh.attributes() {
+            let semantic = match attribute_semantic(attribute) {
+                Some(semantic) => semantic,
+                None => {
+                    log::warn!("{} can't be exported to gltf", attribute.name);
+                    continue;
+                }
+            };
+            let accessor = self.push_attribute(values);
+            attributes.insert(Checked::Valid(semantic), accessor);
+        }
+
+        // The spec requires the bounds of the positions
+        let aabb = mesh.compute_aabb();
+        let positions = attributes[&Checked::Valid(json::mesh::Semantic::Positions)];
+        let position = &mut self.root.accessors[positions.value()];
+        position.min = Some(json::Value::from(aabb.min().to_array().to_vec()));
+        position.max = Some(json::Value::from(aabb.max().to_array().to_vec()));
+
+        let indices = mesh.indices.as_ref().map(|indices| {
+            self.push_accessor(
+                bytemuck::cast_slice(indices),
+                indices.len(),
+                json::accessor::ComponentType::U32,
+                json::accessor::Type::Scalar,
+                json::buffer::Target::ElementArrayBuffer,
+            )
+        });
+
+        self.root.meshes.push(json::Mesh {
+            extensions: None,
+            extras: Default::default(),
+            name: Some(export_mesh.name.clone()),
+            primitives: vec![json::mesh::Primitive {
+                attributes,
+                extensions: None,
+                extras: Default::default(),
+                indices,
+                material: mesh
+                    .material_id
+                    .filter(|id| *id < material_count)
+                    .map(|id| Index::new(id as u32)),
+                mode: Checked::Valid(json::mesh::Mode::Triangles),
+                targets: None,
+            }],
+            weights: None,
+        });
+
+        self.root.nodes.push(json::Node {
+            camera: None,
+            children: None,
+            extensions: None,
+            extras: Default::default(),
+            matrix: if export_mesh.transform == Mat4::IDENTITY {
+                None
+            } else {
+                Some(export_mesh.transform.to_cols_array())
+            },
+            mesh: Some(Index::new(self.root.meshes.len() as u32 - 1)),
+            name: Some(export_mesh.name.clone()),
+            rotation: None,
+            scale: None,
+            translation: None,
+            skin: None,
+            weights: None,
+        });
+        Index::new(self.root.nodes.len() as u32 - 1)
+    }
+
+    fn push_material(&mut self, material: &Material) -> anyhow::Result<()> {
+        let base_color_texture = if is_white_pixel(&material.diffuse_texture) {
+            None
+        } else {
+            self.push_texture_info(&material.diffuse_texture)?
+        };
+        let metallic_roughness_texture = match &material.specular_texture {
+            Some(texture) => self.push_texture_info(texture)?,
+            None => None,
+        };
+        let emissive_texture = match &material.emissive_texture {
+            Some(texture) => self.push_texture_info(texture)?,
+            None => None,
+        };
+        let normal_texture = match &material.normal_texture {
+            Some(texture) => {
+                self.push_texture(texture)?
+                    .map(|index| json::material::NormalTexture {
+                        index,
+                        scale: 1.0,
+                        tex_coord: texture.uv_channel,
+                        extensions: None,
+                        extras: Default::default(),
+                    })
+            }
+            None => None,
+        };
+
+        let (alpha_mode, alpha_cutoff) = match material.alpha_mode {
+            AlphaMode::Opaque => (json::material::AlphaMode::Opaque, None),
+            AlphaMode::Mask(cutoff) => (
+                json::material::AlphaMode::Mask,
+                Some(json::material::AlphaCutoff(cutoff)),
+            ),
+            AlphaMode::Blend => (json::material::AlphaMode::Blend, None),
+        };
+        self.root.materials.push(json::Material {
+            alpha_cutoff,
+            alpha_mode: Checked::Valid(alpha_mode),
+            double_sided: material.double_sided,
+            name: Some(material.name.clone()),
+            pbr_metallic_roughness: json::material::PbrMetallicRoughness {
+                base_color_factor: json::material::PbrBaseColorFactor(
+                    material
+                        .base_color
+                        .truncate()
+                        .extend(material.alpha)
+                        .to_array(),
+                ),
+                base_color_texture,
+                // The loader reads the gloss from the metallic factor which must be between 0 and 1
+                metallic_factor: json::material::StrengthFactor(material.gloss.clamp(0.0, 1.0)),
+                roughness_factor: json::material::StrengthFactor(1.0),
+                metallic_roughness_texture,
+                extensions: None,
+                extras: Default::default(),
+            },
+            normal_texture,
+            occlusion_texture: None,
+            emissive_texture,
+            emissive_factor: json::material::EmissiveFactor(
+                (material.emissive * material.emissive_intensity).to_array(),
+            ),
+            extensions: None,
+            extras: Default::default(),
+        });
+        Ok(())
+    }
+
+    /// A texture with its uv set and KHR_texture_transform
+    fn push_texture_info(
+        &mut self,
+        texture: &MaterialTexture,
+    ) -> anyhow::Result<Option<json::texture::Info>> {
+        let index = match self.push_texture(texture)? {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+        let extensions = if texture.uv_transform == Mat3::IDENTITY {
+            None
+        } else {
+            let extension = "KHR_texture_transform".to_string();
+            if !self.root.extensions_used.contains(&extension) {
+                self.root.extensions_used.push(extension);
+            }
+            Some(json::extensions::texture::Info {
+                texture_transform: Some(texture_transform(&texture.uv_transform)),
+            })
+        };
+        Ok(Some(json::texture::Info {
+            index,
+            tex_coord: texture.uv_channel,
+            extensions,
+            extras: Default::default(),
+        }))
+    }
+
+    /// Adds the image, its sampler and the texture using them.
+    /// Returns `None` if the image can't be exported
+    fn push_texture(
+        &mut self,
+        texture: &MaterialTexture,
+    ) -> anyhow::Result<Option<Index<json::Texture>>> {
+        let png = match encode_png(texture)? {
+            Some(png) => png,
+            None => return Ok(None),
+        };
+        let image_index = self.root.images.len();
+        let (buffer_view, uri) = if self.binary {
+            (Some(self.push_view(&png, None)), None)
+        } else {
+            let file = format!("{}_image{image_index}.png", self.name);
+            self.images.push((file.clone(), png));
+            (None, Some(file))
+        };
+        self.root.images.push(json::Image {
+            buffer_view,
+            mime_type: Some(json::image::MimeType("image/png".to_string())),
+            name: None,
+            uri,
+            extensions: None,
+            extras: Default::default(),
+        });
+
+        let sampler = self.push_sampler(&texture.sampler);
+        self.root.textures.push(json::Texture {
+            name: None,
+            sampler: Some(sampler),
+            source: Index::new(image_index as u32),
+            extensions: None,
+            extras: Default::default(),
+        });
+        Ok(Some(Index::new(self.root.textures.len() as u32 - 1)))
+    }
+
+    fn push_sampler(&mut self, sampler: &SamplerDescriptor) -> Index<json::texture::Sampler> {
+        use json::texture::{MagFilter, MinFilter, WrappingMode};
+
+        let wrapping_mode = |mode: wgpu::AddressMode| match mode {
+            wgpu::AddressMode::ClampToEdge | wgpu::AddressMode::ClampToBorder => {
+                WrappingMode::ClampToEdge
+            }
+            wgpu::AddressMode::MirrorRepeat => WrappingMode::MirroredRepeat,
+            wgpu::AddressMode::Repeat => WrappingMode::Repeat,
+        };
+        let mag_filter = match sampler.mag_filter {
+            wgpu::FilterMode::Nearest => MagFilter::Nearest,
+            wgpu::FilterMode::Linear => MagFilter::Linear,
+        };
+        let min_filter = match (sampler.min_filter, sampler.mipmap_filter) {
+            (wgpu::FilterMode::Nearest, wgpu::FilterMode::Nearest) => {
+                MinFilter::NearestMipmapNearest
+            }
+            (wgpu::FilterMode::Linear, wgpu::FilterMode::Nearest) => MinFilter::LinearMipmapNearest,
+            (wgpu::FilterMode::Nearest, wgpu::FilterMode::Linear) => MinFilter::NearestMipmapLinear,
+            (wgpu::FilterMode::Linear, wgpu::FilterMode::Linear) => MinFilter::LinearMipmapLinear,
+        };
+
+        self.root.samplers.push(json::texture::Sampler {
+            mag_filter: Some(Checked::Valid(mag_filter)),
+            min_filter: Some(Checked::Valid(min_filter)),
+            name: None,
+            wrap_s: Checked::Valid(wrapping_mode(sampler.address_mode_u)),
+            wrap_t: Checked::Valid(wrapping_mode(sampler.address_mode_v)),
+            extensions: None,
+            extras: Default::default(),
+        });
+        Index::new(self.root.samplers.len() as u32 - 1)
+    }
+}
+
+/// The glTF name of the attributes that have one
+fn attribute_semantic(attribute: &MeshVertexAttribute) -> Option<json::mesh::Semantic> {
+    use json::mesh::Semantic;
+
+    [
+        (Mesh::ATTRIBUTE_POSITION, Semantic::Positions),
+        (Mesh::ATTRIBUTE_NORMAL, Semantic::Normals),
+        (Mesh::ATTRIBUTE_TANGENT, Semantic::Tangents),
+        (Mesh::ATTRIBUTE_COLOR, Semantic::Colors(0)),
+        (Mesh::ATTRIBUTE_UV_0, Semantic::TexCoords(0)),
+        (Mesh::ATTRIBUTE_UV_1, Semantic::TexCoords(1)),
+        (Mesh::ATTRIBUTE_JOINT_INDEX, Semantic::Joints(0)),
+        (Mesh::ATTRIBUTE_JOINT_WEIGHT, Semantic::Weights(0)),
+    ]
+    .into_iter()
+    .find(|(other, _)| other == attribute)
+    .map(|(_, semantic)| semantic)
+}
+
+/// The inverse of `MaterialTexture::uv_transform`
+fn texture_transform(uv_transform: &Mat3) -> json::extensions::texture::TextureTransform {
+    let x_axis = uv_transform.x_axis.truncate();
+    let y_axis = uv_transform.y_axis.truncate();
+    let offset = uv_transform.z_axis.truncate();
+    json::extensions::texture::TextureTransform {
+        offset: json::extensions::texture::TextureTransformOffset(offset.to_array()),
+        // The rotation of the uv_transform is clockwise
+        rotation: json::extensions::texture::TextureTransformRotation(-x_axis.y.atan2(x_axis.x)),
+        scale: json::extensions::texture::TextureTransformScale([x_axis.length(), y_axis.length()]),
+        tex_coord: None,
+        extras: Default::default(),
+    }
+}
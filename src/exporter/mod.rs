@@ -0,0 +1,276 @@
+use std::io::Cursor;
+
+use bevy::math::Mat4;
+use image::{DynamicImage, ImageOutputFormat, Rgba};
+
+use crate::{mesh::Mesh, model::MaterialTexture, texture::TextureData};
+
+mod gltf;
+mod obj;
+
+pub use self::{gltf::export_gltf, obj::export_obj};
+
+/// A mesh to export and its place in the exported scene.
+/// The material is the one at `Mesh::material_id` in the materials given to the exporter
+pub struct ExportMesh<'a> {
+    pub name: String,
+    pub mesh: &'a Mesh,
+    pub transform: Mat4,
+}
+
+impl<'a> ExportMesh<'a> {
+    pub fn new(name: impl Into<String>, mesh: &'a Mesh) -> Self {
+        Self {
+            name: name.into(),
+            mesh,
+            transform: Mat4::IDENTITY,
+        }
+    }
+
+    pub fn with_transform(mut self, transform: Mat4) -> Self {
+        self.transform = transform;
+        self
+    }
+}
+
+/// The loaders use a 1x1 white texture when a material doesn't have a diffuse texture.
+/// It doesn't need to be exported since it doesn't change the color
+fn is_white_pixel(texture: &MaterialTexture) -> bool {
+    match &texture.image {
+        TextureData::Rgba(image) => {
+            image.dimensions() == (1, 1) && *image.get_pixel(0, 0) == Rgba([255; 4])
+        }
+        TextureData::Compressed(_) => false,
+    }
+}
+
+/// Encodes the texture as a png.
+/// Compressed textures can't be decoded so they are skipped
+fn encode_png(texture: &MaterialTexture) -> anyhow::Result<Option<Vec<u8>>> {
+    let image = match &texture.image {
+        TextureData::Rgba(image) => image,
+        TextureData::Compressed(_) => {
+            log::warn!("Compressed textures can't be exported, the texture is skipped");
+            return Ok(None);
+        }
+    };
+    let mut bytes = Cursor::new(vec![]);
+    DynamicImage::ImageRgba8(image.clone()).write_to(&mut bytes, ImageOutputFormat::Png)?;
+    Ok(Some(bytes.into_inner()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Duration};
+
+    use bevy::{
+        asset::{Asset, AssetPlugin, LoadState},
+        math::{Mat3, Mat4, Quat, Vec2, Vec3, Vec4},
+        prelude::*,
+    };
+    use image::{Rgba, RgbaImage};
+
+    use super::{export_gltf, export_obj, ExportMesh};
+    use crate::{
+        gltf_loader::{GltfLoader, LoadedGltf},
+        mesh::Mesh,
+        mesh_optimizer::MeshOptimizationSettings,
+        model::{AlphaMode, Material, MaterialTexture},
+        obj_loader::{LoadedObj, ObjLoader},
+        shapes::cube::Cube,
+        texture::TextureData,
+    };
+
+    fn textured_material() -> Material {
+        let image = RgbaImage::from_fn(2, 2, |x, y| Rgba([x as u8 * 255, y as u8 * 255, 64, 255]));
+        Material {
+            name: "textured".to_string(),
+            base_color: Vec4::new(0.8, 0.6, 0.4, 1.0),
+            alpha: 1.0,
+            alpha_mode: AlphaMode::Opaque,
+            double_sided: false,
+            gloss: 0.5,
+            specular: Vec3::splat(0.5),
+            emissive: Vec3::ZERO,
+            emissive_intensity: 1.0,
+            diffuse_texture: MaterialTexture {
+                uv_transform: MaterialTexture::uv_transform(
+                    Vec2::new(0.25, 0.5),
+                    0.0,
+                    Vec2::new(2.0, 3.0),
+                ),
+                ..MaterialTexture::from(image)
+            },
+            normal_texture: None,
+            specular_texture: None,
+            emissive_texture: None,
+        }
+    }
+
+    /// An app with only the asset server and the model loaders.
+    /// The meshes aren't optimized so the loaded vertices match the exported ones
+    fn loader_app() -> App {
+        let mut app = App::new();
+        app.insert_resource(MeshOptimizationSettings {
+            enabled: false,
+            lod_levels: 0,
+            ..default()
+        })
+        .add_plugins(MinimalPlugins)
+        .add_plugin(AssetPlugin)
+        .add_asset::<Material>()
+        .add_asset::<LoadedObj>()
+        .add_asset::<LoadedGltf>()
+        .init_asset_loader::<ObjLoader>()
+        .init_asset_loader::<GltfLoader>();
+        app
+    }
+
+    /// Updates the app until the asset and its materials are loaded
+    fn load<T: Asset>(
+        app: &mut App,
+        path: &Path,
+        materials: impl Fn(&T) -> Vec<Handle<Material>>,
+    ) -> Handle<T> {
+        let handle: Handle<T> = app.world.resource::<AssetServer>().load(path);
+        for _ in 0..500 {
+            app.update();
+            if let Some(asset) = app.world.resource::<Assets<T>>().get(&handle) {
+                let loaded_materials = app.world.resource::<Assets<Material>>();
+                if materials(asset)
+                    .iter()
+                    .all(|material| loaded_materials.get(material).is_some())
+                {
+                    return handle;
+                }
+            }
+            let load_state = app.world.resource::<AssetServer>().get_load_state(&handle);
+            assert_ne!(load_state, LoadState::Failed, "Failed to load {path:?}");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("Timed out loading {path:?}");
+    }
+
+    fn assert_round_trip(
+        app: &App,
+        path: &Path,
+        exported: &Mesh,
+        transform: &Mat4,
+        exported_material: &Material,
+        loaded_meshes: &[Mesh],
+        loaded_materials: &[Handle<Material>],
+    ) {
+        assert_eq!(loaded_meshes.len(), 1, "{path:?}");
+        let loaded = &loaded_meshes[0];
+        assert_eq!(
+            loaded.count_vertices(),
+            exported.count_vertices(),
+            "{path:?}"
+        );
+
+        // The loaders apply the transform to the vertices but they may be reordered
+        for position in exported.positions() {
+            let expected = transform.transform_point3(Vec3::from(*position));
+            assert!(
+                loaded
+                    .positions()
+                    .iter()
+                    .any(|p| Vec3::from(*p).abs_diff_eq(expected, 1e-5)),
+                "{path:?} is missing the vertex {expected}"
+            );
+        }
+
+        let material = loaded
+            .material_id
+            .and_then(|id| loaded_materials.get(id))
+            .and_then(|handle| app.world.resource::<Assets<Material>>().get(handle))
+            .unwrap_or_else(|| panic!("{path:?} lost the material of the mesh"));
+        assert_eq!(material.name, exported_material.name, "{path:?}");
+        assert!(
+            material
+                .base_color
+                .truncate()
+                .abs_diff_eq(exported_material.base_color.truncate(), 1e-5),
+            "{path:?} base color {}",
+            material.base_color
+        );
+        match (
+            &material.diffuse_texture.image,
+            &exported_material.diffuse_texture.image,
+        ) {
+            (TextureData::Rgba(loaded), TextureData::Rgba(exported)) => {
+                assert_eq!(loaded, exported, "{path:?} diffuse texture");
+            }
+            _ => panic!("{path:?} diffuse texture isn't rgba"),
+        }
+        assert!(
+            material
+                .diffuse_texture
+                .uv_transform
+                .abs_diff_eq(exported_material.diffuse_texture.uv_transform, 1e-5),
+            "{path:?} uv transform {:?}",
+            material.diffuse_texture.uv_transform
+        );
+    }
+
+    #[test]
+    fn export_round_trip() {
+        let directory = std::env::temp_dir().join("learn_wgpu_export_round_trip");
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let mut cube = Cube::new(1.0, 2.0, 3.0).to_mesh();
+        cube.material_id = Some(0);
+        let transform = Mat4::from_scale_rotation_translation(
+            Vec3::new(1.0, 2.0, 0.5),
+            Quat::from_rotation_y(0.5),
+            Vec3::new(1.0, -2.0, 3.0),
+        );
+        let meshes = [ExportMesh::new("cube", &cube).with_transform(transform)];
+        let materials = [textured_material()];
+        assert_ne!(
+            materials[0].diffuse_texture.uv_transform,
+            Mat3::IDENTITY,
+            "The uv transform should be exported"
+        );
+
+        let mut app = loader_app();
+
+        let obj_path = directory.join("cube.obj");
+        export_obj(&obj_path, &meshes, &materials).unwrap();
+        let handle = load(&mut app, &obj_path, |obj: &LoadedObj| obj.materials.clone());
+        let obj = app
+            .world
+            .resource::<Assets<LoadedObj>>()
+            .get(&handle)
+            .unwrap();
+        assert_round_trip(
+            &app,
+            &obj_path,
+            &cube,
+            &transform,
+            &materials[0],
+            &obj.meshes,
+            &obj.materials,
+        );
+
+        for file in ["cube.gltf", "cube.glb"] {
+            let path = directory.join(file);
+            export_gltf(&path, &meshes, &materials).unwrap();
+            let handle = load(&mut app, &path, |gltf: &LoadedGltf| gltf.materials.clone());
+            let gltf = app
+                .world
+                .resource::<Assets<LoadedGltf>>()
+                .get(&handle)
+                .unwrap();
+            assert_round_trip(
+                &app,
+                &path,
+                &cube,
+                &transform,
+                &materials[0],
+                &gltf.meshes,
+                &gltf.materials,
+            );
+        }
+    }
+}
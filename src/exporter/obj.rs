@@ -0,0 +1,262 @@
+use std::{fmt::Write as _, fs, path::Path};
+
+use anyhow::Context;
+use bevy::math::Vec2;
+use image::{Rgba, RgbaImage};
+
+use crate::{
+    mesh::{Mesh, VertexAttributeValues},
+    model::{AlphaMode, Material, MaterialTexture},
+    texture::TextureData,
+};
+
+use super::{encode_png, is_white_pixel, ExportMesh};
+
+// References:
+// <http://paulbourke.net/dataformats/obj/>
+// <http://paulbourke.net/dataformats/mtl/>
+
+/// Writes the meshes to an obj file and the materials to a mtl file with the same name.
+/// obj doesn't have a scene hierarchy so the transforms are applied to the vertices.
+/// The textures are written as png files next to the obj file
+pub fn export_obj(
+    path: &Path,
+    meshes: &[ExportMesh],
+    materials: &[Material],
+) -> anyhow::Result<()> {
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+    let name = path
+        .file_stem()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("Invalid obj path {path:?}"))?;
+    let material_names = material_names(materials);
+
+    let mut obj = String::new();
+    let mtl_file = format!("{name}.mtl");
+    if !materials.is_empty() {
+        writeln!(obj, "mtllib {mtl_file}")?;
+    }
+
+    // The indices are shared by every object of the file and start at 1
+    let mut first_index = ObjIndices {
+        position: 1,
+        uv: 1,
+        normal: 1,
+    };
+    for export_mesh in meshes {
+        let mut mesh = export_mesh.mesh.clone();
+        mesh.transform(&export_mesh.transform);
+        let written = write_mesh(&mut obj, &export_mesh.name, &mesh)?;
+        if let Some(name) = mesh.material_id.and_then(|id| material_names.get(id)) {
+            writeln!(obj, "usemtl {name}")?;
+        }
+        write_faces(&mut obj, &mesh, &first_index)?;
+        first_index.position += written.position;
+        first_index.uv += written.uv;
+        first_index.normal += written.normal;
+    }
+    fs::write(path, obj).with_context(|| format!("Failed to write {path:?}"))?;
+
+    if !materials.is_empty() {
+        let mut mtl = String::new();
+        for (i, (material, material_name)) in materials.iter().zip(&material_names).enumerate() {
+            let texture_name = format!("{name}_material{i}");
+            write_material(&mut mtl, directory, &texture_name, material_name, material)?;
+        }
+        let mtl_path = directory.join(mtl_file);
+        fs::write(&mtl_path, mtl).with_context(|| format!("Failed to write {mtl_path:?}"))?;
+    }
+
+    log::info!("Exported {} meshes to {path:?}", meshes.len());
+    Ok(())
+}
+
+/// usemtl refers to materials by name so each name must be unique
+fn material_names(materials: &[Material]) -> Vec<String> {
+    let mut names: Vec<String> = vec![];
+    for (i, material) in materials.iter().enumerate() {
+        let name = material.name.trim();
+        if name.is_empty() || names.iter().any(|other| other == name) {
+            names.push(format!("{name}material{i}"));
+        } else {
+            names.push(name.to_string());
+        }
+    }
+    names
+}
+
+/// Positions, uvs and normals are indexed separately.
+/// A mesh without uvs or normals doesn't write any so their indices don't advance
+struct ObjIndices {
+    position: usize,
+    uv: usize,
+    normal: usize,
+}
+
+/// Returns the number of positions, uvs and normals written
+fn write_mesh(obj: &mut String, name: &str, mesh: &Mesh) -> anyhow::Result<ObjIndices> {
+    writeln!(obj, "o {name}")?;
+    let mut written = ObjIndices {
+        position: 0,
+        uv: 0,
+        normal: 0,
+    };
+
+    // Vertex colors are an extension of the format supported by most tools, the alpha is lost
+    let colors = mesh
+        .attribute(Mesh::ATTRIBUTE_COLOR)
+        .and_then(VertexAttributeValues::as_float4);
+    let positions = mesh.positions();
+    for (i, [x, y, z]) in positions.iter().enumerate() {
+        match colors {
+            Some(colors) => {
+                let [r, g, b, _] = colors[i];
+                writeln!(obj, "v {x} {y} {z} {r} {g} {b}")?;
+            }
+            None => writeln!(obj, "v {x} {y} {z}")?,
+        }
+    }
+    written.position = positions.len();
+    // The loader flips the uvs
+    if let Some(uvs) = mesh
+        .attribute(Mesh::ATTRIBUTE_UV_0)
+        .and_then(VertexAttributeValues::as_float2)
+    {
+        for [u, v] in uvs {
+            writeln!(obj, "vt {u} {}", 1.0 - v)?;
+        }
+        written.uv = uvs.len();
+    }
+    if let Some(normals) = mesh
+        .attribute(Mesh::ATTRIBUTE_NORMAL)
+        .and_then(VertexAttributeValues::as_float3)
+    {
+        for [x, y, z] in normals {
+            writeln!(obj, "vn {x} {y} {z}")?;
+        }
+        written.normal = normals.len();
+    }
+    Ok(written)
+}
+
+/// Every vertex of the mesh has a position, uv and normal at the same offset from `first_index`
+fn write_faces(obj: &mut String, mesh: &Mesh, first_index: &ObjIndices) -> anyhow::Result<()> {
+    let has_uvs = mesh
+        .attribute(Mesh::ATTRIBUTE_UV_0)
+        .and_then(VertexAttributeValues::as_float2)
+        .is_some();
+    let has_normals = mesh
+        .attribute(Mesh::ATTRIBUTE_NORMAL)
+        .and_then(VertexAttributeValues::as_float3)
+        .is_some();
+    for triangle in mesh.triangles() {
+        obj.push('f');
+        for v in triangle {
+            let p = v + first_index.position;
+            let t = v + first_index.uv;
+            let n = v + first_index.normal;
+            match (has_uvs, has_normals) {
+                (true, true) => write!(obj, " {p}/{t}/{n}")?,
+                (true, false) => write!(obj, " {p}/{t}")?,
+                (false, true) => write!(obj, " {p}//{n}")?,
+                (false, false) => write!(obj, " {p}")?,
+            }
+        }
+        obj.push('\n');
+    }
+    Ok(())
+}
+
+fn write_material(
+    mtl: &mut String,
+    directory: &Path,
+    texture_name: &str,
+    name: &str,
+    material: &Material,
+) -> anyhow::Result<()> {
+    let [x, y, z] = material.base_color.truncate().to_array();
+    writeln!(mtl, "newmtl {name}")?;
+    writeln!(mtl, "Kd {x} {y} {z}")?;
+    let [x, y, z] = material.specular.to_array();
+    writeln!(mtl, "Ks {x} {y} {z}")?;
+    writeln!(mtl, "Ns {}", material.gloss)?;
+    // The loader only blends materials with a dissolve lower than 1
+    let dissolve = match material.alpha_mode {
+        AlphaMode::Blend => material.alpha,
+        AlphaMode::Opaque | AlphaMode::Mask(_) => 1.0,
+    };
+    writeln!(mtl, "d {dissolve}")?;
+    let [x, y, z] = (material.emissive * material.emissive_intensity).to_array();
+    writeln!(mtl, "Ke {x} {y} {z}")?;
+
+    let mut write_texture =
+        |key: &str, suffix: &str, texture: &MaterialTexture| -> anyhow::Result<()> {
+            if let Some(png) = encode_png(texture)? {
+                let file = format!("{texture_name}_{suffix}.png");
+                fs::write(directory.join(&file), png)
+                    .with_context(|| format!("Failed to write {file}"))?;
+                writeln!(mtl, "{key} {}{file}", texture_options(texture))?;
+            }
+            Ok(())
+        };
+
+    if !is_white_pixel(&material.diffuse_texture) {
+        write_texture("map_Kd", "diffuse", &material.diffuse_texture)?;
+    }
+    // The loader merges map_d in the diffuse alpha and uses it as a cutout
+    if let (AlphaMode::Mask(_), TextureData::Rgba(diffuse)) =
+        (&material.alpha_mode, &material.diffuse_texture.image)
+    {
+        let mask = MaterialTexture {
+            image: TextureData::Rgba(alpha_mask(diffuse)),
+            ..material.diffuse_texture.clone()
+        };
+        write_texture("map_d", "mask", &mask)?;
+    }
+    if let Some(texture) = &material.specular_texture {
+        write_texture("map_Ks", "specular", texture)?;
+    }
+    if let Some(texture) = &material.normal_texture {
+        write_texture("map_Bump", "normal", texture)?;
+    }
+    if let Some(texture) = &material.emissive_texture {
+        write_texture("map_Ke", "emissive", texture)?;
+    }
+    mtl.push('\n');
+    Ok(())
+}
+
+/// A grayscale image of the alpha channel
+fn alpha_mask(image: &RgbaImage) -> RgbaImage {
+    RgbaImage::from_fn(image.width(), image.height(), |x, y| {
+        let alpha = image.get_pixel(x, y)[3];
+        Rgba([alpha, alpha, alpha, 255])
+    })
+}
+
+/// The `-clamp`, `-o` and `-s` options read by the loader.
+/// mtl can't describe a rotation so it's ignored
+fn texture_options(texture: &MaterialTexture) -> String {
+    let mut options = String::new();
+    if texture.sampler.address_mode_u == wgpu::AddressMode::ClampToEdge {
+        options.push_str("-clamp on ");
+    }
+
+    let transform = texture.uv_transform;
+    if transform.x_axis.y != 0.0 || transform.y_axis.x != 0.0 {
+        log::warn!("Texture rotations can't be exported to mtl");
+    }
+    let scale = Vec2::new(
+        transform.x_axis.truncate().length(),
+        transform.y_axis.truncate().length(),
+    );
+    // The loader moves the origin of the uvs to the top left corner
+    let offset = Vec2::new(transform.z_axis.x, 1.0 - scale.y - transform.z_axis.y);
+    if offset != Vec2::ZERO {
+        options.push_str(&format!("-o {} {} ", offset.x, offset.y));
+    }
+    if scale != Vec2::ONE {
+        options.push_str(&format!("-s {} {} ", scale.x, scale.y));
+    }
+    options
+}
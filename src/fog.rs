@@ -0,0 +1,173 @@
+use bevy::{
+    math::Vec4,
+    prelude::*,
+    render::render_resource::{encase, ShaderType},
+};
+
+use crate::renderer::{bind_groups::mesh_view::FogBuffer, WgpuRenderer};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FogFalloff {
+    /// The fog goes from 0 at `start` to 1 at `end`
+    Linear {
+        start: f32,
+        end: f32,
+    },
+    Exponential {
+        density: f32,
+    },
+    ExponentialSquared {
+        density: f32,
+    },
+}
+
+/// The fog gets exponentially thinner the higher it is above `offset`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeightFalloff {
+    pub offset: f32,
+    pub density: f32,
+}
+
+/// Distance fog applied to every lit mesh.
+/// The clear color is tinted with the same fog color so far away meshes blend with the background.
+#[derive(Debug, Clone)]
+pub struct Fog {
+    pub enabled: bool,
+    pub color: Color,
+    pub falloff: FogFalloff,
+    pub height_falloff: Option<HeightFalloff>,
+}
+
+impl Default for Fog {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            color: Color::rgb(0.5, 0.5, 0.5),
+            falloff: FogFalloff::Linear {
+                start: 5.0,
+                end: 50.0,
+            },
+            height_falloff: None,
+        }
+    }
+}
+
+impl Fog {
+    /// Every fog mode reaches full density at an infinite distance,
+    /// so this is the color the background should have to match the fogged meshes.
+    /// The colors are mixed in linear space like in the shader and converted back to sRGB
+    pub fn apply_to_clear_color(&self, clear_color: Color) -> Color {
+        if !self.enabled {
+            return clear_color;
+        }
+        let clear = Vec4::from(clear_color.as_linear_rgba_f32());
+        let fog = Vec4::from(self.color.as_linear_rgba_f32());
+        let result = clear.lerp(fog.truncate().extend(clear.w), fog.w);
+        Color::rgba_linear(result.x, result.y, result.z, result.w).as_rgba()
+    }
+}
+
+// WARN these must match the modes in fog.wgsl
+const FOG_MODE_OFF: u32 = 0;
+const FOG_MODE_LINEAR: u32 = 1;
+const FOG_MODE_EXPONENTIAL: u32 = 2;
+const FOG_MODE_EXPONENTIAL_SQUARED: u32 = 3;
+
+#[derive(ShaderType)]
+pub struct FogUniform {
+    pub color: Vec4,
+    pub mode: u32,
+    pub start: f32,
+    pub end: f32,
+    pub density: f32,
+    pub height_offset: f32,
+    /// 0 means no height falloff
+    pub height_density: f32,
+}
+
+impl From<&Fog> for FogUniform {
+    fn from(fog: &Fog) -> Self {
+        let (mode, start, end, density) = match fog.falloff {
+            _ if !fog.enabled => (FOG_MODE_OFF, 0.0, 0.0, 0.0),
+            FogFalloff::Linear { start, end } => {
+                // The shader divides by end - start, the epsilon is scaled so it isn't lost
+                // when added to a large start
+                let min_end = start + f32::EPSILON * start.abs().max(1.0);
+                (FOG_MODE_LINEAR, start, end.max(min_end), 0.0)
+            }
+            FogFalloff::Exponential { density } => (FOG_MODE_EXPONENTIAL, 0.0, 0.0, density),
+            FogFalloff::ExponentialSquared { density } => {
+                (FOG_MODE_EXPONENTIAL_SQUARED, 0.0, 0.0, density)
+            }
+        };
+        let (height_offset, height_density) = fog
+            .height_falloff
+            .map(|h| (h.offset, h.density))
+            .unwrap_or((0.0, 0.0));
+        Self {
+            color: Vec4::from(fog.color.as_linear_rgba_f32()),
+            mode,
+            start,
+            end,
+            density,
+            height_offset,
+            height_density,
+        }
+    }
+}
+
+impl FogUniform {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = encase::UniformBuffer::new(Vec::new());
+        buffer.write(self).expect("failed to write fog uniform");
+        let bytes: &Vec<u8> = buffer.as_ref();
+        bytes.clone()
+    }
+}
+
+pub fn update_fog_buffer(renderer: Res<WgpuRenderer>, fog: Res<Fog>, fog_buffer: Res<FogBuffer>) {
+    if fog.is_changed() {
+        renderer
+            .queue
+            .write_buffer(&fog_buffer.0, 0, &FogUniform::from(&*fog).to_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn linear_fog(start: f32, end: f32) -> Fog {
+        Fog {
+            enabled: true,
+            falloff: FogFalloff::Linear { start, end },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn linear_fog_end_is_after_start() {
+        for (start, end) in [(0.0, 0.0), (5.0, 5.0), (50.0, 50.0), (1000.0, 10.0)] {
+            let uniform = FogUniform::from(&linear_fog(start, end));
+            assert!(uniform.end - uniform.start > 0.0, "{start} {end}");
+        }
+        let uniform = FogUniform::from(&linear_fog(5.0, 50.0));
+        assert_eq!((uniform.start, uniform.end), (5.0, 50.0));
+    }
+
+    #[test]
+    fn clear_color_is_mixed_in_linear_space() {
+        let fog = Fog {
+            enabled: true,
+            color: Color::rgba(1.0, 1.0, 1.0, 0.5),
+            ..Default::default()
+        };
+        let color = fog.apply_to_clear_color(Color::BLACK);
+        // Half way between black and white in linear space is 0.5, which is ~0.735 in sRGB
+        let expected = Color::rgba_linear(0.5, 0.5, 0.5, 1.0).as_rgba_f32();
+        for (channel, expected) in color.as_rgba_f32().iter().zip(expected) {
+            assert!((channel - expected).abs() < 1e-4, "{color:?}");
+        }
+        assert!((color.r() - 0.735).abs() < 1e-3, "{color:?}");
+    }
+}
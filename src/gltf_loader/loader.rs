@@ -1,23 +1,34 @@
+use crate::{
+    compressed_texture::{is_ktx2, load_ktx2},
+    mesh::{Mesh, Vertex},
+    mesh_optimizer::MeshOptimizationSettings,
+    model::{AlphaMode, Material, MaterialTexture},
+    static_batching::merge_meshes,
+    texture::{ColorSpace, DeviceFeatures, SamplerDescriptor, TextureData},
+};
 use bevy::{
-    asset::LoadContext,
+    asset::{LoadContext, LoadedAsset},
     prelude::*,
     tasks::IoTaskPool,
     utils::{HashMap, Instant},
 };
-use image::RgbaImage;
-
-use crate::{image_utils::image_from_color, mesh::Vertex, model::Material};
+use gltf::json::extensions::texture::TextureTransform;
+use std::borrow::Cow;
 
 use super::LoadedGltf;
 
 pub async fn load_gltf<'a, 'b>(
     bytes: &'a [u8],
     load_context: &'a mut LoadContext<'b>,
+    mesh_optimization: &MeshOptimizationSettings,
+    device_features: &DeviceFeatures,
 ) -> anyhow::Result<LoadedGltf> {
     let gltf = gltf::Gltf::from_slice(bytes)?;
 
+    let buffer_data = load_buffers(&gltf, load_context).await?;
+
     let start = Instant::now();
-    let textures = load_textures(&gltf, load_context);
+    let textures = load_textures(&gltf, load_context, &buffer_data, device_features);
 
     log::info!(
         "Loaded all textures in {}ms",
@@ -25,34 +36,94 @@ pub async fn load_gltf<'a, 'b>(
     );
 
     let start = Instant::now();
-    let materials = load_materials(&gltf, textures);
+    let normal_texture_transforms = normal_texture_transforms(bytes)?;
+    let materials = load_materials(&gltf, textures, &normal_texture_transforms)
+        .into_iter()
+        .enumerate()
+        .map(|(i, material)| {
+            load_context.set_labeled_asset(&format!("Material{i}"), LoadedAsset::new(material))
+        })
+        .collect();
     log::info!(
         "Loaded all materials in {}ms",
         (Instant::now() - start).as_millis()
     );
 
-    let buffer_data = load_buffers(&gltf, load_context).await?;
-
     let mut meshes = vec![];
-    for mesh in gltf.meshes() {
-        for primitive in mesh.primitives() {
-            meshes.push(generate_mesh(primitive, &buffer_data)?);
+    match gltf.default_scene().or_else(|| gltf.scenes().next()) {
+        Some(scene) => {
+            for node in scene.nodes() {
+                load_node(&node, Mat4::IDENTITY, &buffer_data, &mut meshes)?;
+            }
+        }
+        None => {
+            for mesh in gltf.meshes() {
+                for primitive in mesh.primitives() {
+                    meshes.push(generate_mesh(primitive, &buffer_data)?);
+                }
+            }
         }
     }
 
+    if mesh_optimization.static_batching {
+        let mesh_count = meshes.len();
+        meshes = merge_meshes(meshes);
+        log::info!("Merged {mesh_count} meshes in {} batches", meshes.len());
+    }
+
+    let start = Instant::now();
+    for mesh in &mut meshes {
+        mesh_optimization.apply(mesh);
+    }
+    log::info!(
+        "Optimized all meshes in {}ms",
+        (Instant::now() - start).as_millis()
+    );
+
     Ok(LoadedGltf { materials, meshes })
 }
 
+/// Generates the meshes of the node and its children.
+/// The meshes are moved to the space of the scene since a `Model` only has a single transform
+fn load_node(
+    node: &gltf::Node,
+    parent_transform: Mat4,
+    buffer_data: &[Vec<u8>],
+    meshes: &mut Vec<crate::mesh::Mesh>,
+) -> anyhow::Result<()> {
+    let transform = parent_transform * Mat4::from_cols_array_2d(&node.transform().matrix());
+    if let Some(mesh) = node.mesh() {
+        for primitive in mesh.primitives() {
+            let mut mesh = generate_mesh(primitive, buffer_data)?;
+            if transform != Mat4::IDENTITY {
+                mesh.transform(&transform);
+            }
+            meshes.push(mesh);
+        }
+    }
+    for child in node.children() {
+        load_node(&child, transform, buffer_data, meshes)?;
+    }
+    Ok(())
+}
+
 fn load_textures<'a>(
     gltf: &gltf::Gltf,
     load_context: &LoadContext<'a>,
-) -> HashMap<usize, RgbaImage> {
+    buffer_data: &[Vec<u8>],
+    device_features: &DeviceFeatures,
+) -> HashMap<usize, TextureData> {
     IoTaskPool::get()
         .scope(|scope| {
             gltf.textures().for_each(|gltf_texture| {
                 let load_context: &LoadContext = load_context;
                 scope.spawn(async move {
-                    let texture_image = load_texture(&gltf_texture, load_context).await;
+                    let texture_image = load_texture(&gltf_texture, load_context, buffer_data)
+                        .await
+                        .and_then(|image| {
+                            device_features.check(&image)?;
+                            Ok(image)
+                        });
                     log::info!("loading {:?} completed", gltf_texture.name());
                     (gltf_texture.index(), texture_image)
                 });
@@ -68,16 +139,48 @@ fn load_textures<'a>(
         .collect()
 }
 
-fn load_materials(gltf: &gltf::Gltf, textures: HashMap<usize, RgbaImage>) -> Vec<Material> {
+/// The KHR_texture_transform of the normal texture of every material.
+/// The gltf crate drops the extensions of normal textures so they are read from the raw json
+fn normal_texture_transforms(bytes: &[u8]) -> anyhow::Result<Vec<Option<TextureTransform>>> {
+    let json = if bytes.starts_with(b"glTF") {
+        gltf::Glb::from_slice(bytes)?.json
+    } else {
+        Cow::Borrowed(bytes)
+    };
+    let root: gltf::json::Value = gltf::json::deserialize::from_slice(&json)?;
+    let materials = match root
+        .get("materials")
+        .and_then(|materials| materials.as_array())
+    {
+        Some(materials) => materials,
+        None => return Ok(vec![]),
+    };
+    materials
+        .iter()
+        .map(|material| {
+            material
+                .pointer("/normalTexture/extensions/KHR_texture_transform")
+                .map(|transform| gltf::json::deserialize::from_value(transform.clone()))
+                .transpose()
+                .map_err(anyhow::Error::from)
+        })
+        .collect()
+}
+
+fn load_materials(
+    gltf: &gltf::Gltf,
+    textures: HashMap<usize, TextureData>,
+    normal_texture_transforms: &[Option<TextureTransform>],
+) -> Vec<Material> {
     let mut materials = vec![];
     for material in gltf.materials() {
         log::info!("loading material: {:?}", material.name());
         let base_color_texture =
             if let Some(info) = material.pbr_metallic_roughness().base_color_texture() {
                 // TODO this should use an asset handle instead
-                textures[&info.texture().index()].clone()
+                texture_info(&textures, &info, ColorSpace::Srgb)
             } else {
-                image_from_color(Color::WHITE)
+                MaterialTexture::from_color(Color::WHITE, ColorSpace::Srgb)
             };
         // let base_color_texture = image_from_color(Color::CYAN);
         let base_color = material.pbr_metallic_roughness().base_color_factor();
@@ -85,10 +188,17 @@ fn load_materials(gltf: &gltf::Gltf, textures: HashMap<usize, RgbaImage>) -> Vec
         let metallic_roughness_texture = material
             .pbr_metallic_roughness()
             .metallic_roughness_texture()
-            .map(|info| textures[&info.texture().index()].clone());
-        let normal_texture = material
-            .normal_texture()
-            .map(|texture| textures[&texture.texture().index()].clone());
+            .map(|info| texture_info(&textures, &info, ColorSpace::Linear));
+        let normal_texture = material.normal_texture().map(|texture| {
+            let transform = material
+                .index()
+                .and_then(|index| normal_texture_transforms.get(index))
+                .and_then(Option::as_ref);
+            normal_texture_info(&textures, &texture, transform)
+        });
+        let emissive_texture = material
+            .emissive_texture()
+            .map(|info| texture_info(&textures, &info, ColorSpace::Srgb));
 
         materials.push(Material {
             name: material
@@ -97,19 +207,155 @@ fn load_materials(gltf: &gltf::Gltf, textures: HashMap<usize, RgbaImage>) -> Vec
                 .to_string(),
             base_color: Vec4::from(base_color),
             diffuse_texture: base_color_texture,
-            alpha: match material.alpha_mode() {
-                gltf::material::AlphaMode::Opaque => 1.0,
-                gltf::material::AlphaMode::Mask | gltf::material::AlphaMode::Blend => 0.5,
+            alpha: base_color[3],
+            alpha_mode: match material.alpha_mode() {
+                gltf::material::AlphaMode::Opaque => AlphaMode::Opaque,
+                // The spec defines 0.5 as the default cutoff
+                gltf::material::AlphaMode::Mask => {
+                    AlphaMode::Mask(material.alpha_cutoff().unwrap_or(0.5))
+                }
+                gltf::material::AlphaMode::Blend => AlphaMode::Blend,
             },
+            double_sided: material.double_sided(),
             gloss: metallic,
             specular_texture: metallic_roughness_texture,
             specular: Vec3::new(1.0, 1.0, 1.0),
+            emissive: Vec3::from(material.emissive_factor()),
+            emissive_intensity: 1.0,
             normal_texture,
+            emissive_texture,
         });
     }
     materials
 }
 
+fn material_texture(
+    textures: &HashMap<usize, TextureData>,
+    texture: gltf::Texture,
+    color_space: ColorSpace,
+) -> MaterialTexture {
+    // Textures that failed to load aren't in the map
+    let image = match textures.get(&texture.index()) {
+        Some(image) => image.clone(),
+        None => {
+            log::error!(
+                "glTF texture {} is missing, using a white texture instead",
+                texture.index()
+            );
+            MaterialTexture::from_color(Color::WHITE, color_space).image
+        }
+    };
+    MaterialTexture {
+        image,
+        sampler: sampler_descriptor(&texture.sampler()),
+        color_space,
+        uv_channel: 0,
+        uv_transform: Mat3::IDENTITY,
+    }
+}
+
+/// A material texture with its uv set and KHR_texture_transform
+fn texture_info(
+    textures: &HashMap<usize, TextureData>,
+    info: &gltf::texture::Info,
+    color_space: ColorSpace,
+) -> MaterialTexture {
+    let transform = info.texture_transform();
+    MaterialTexture {
+        // The extension can override the uv set of the texture
+        uv_channel: uv_channel(
+            transform
+                .as_ref()
+                .and_then(|transform| transform.tex_coord())
+                .unwrap_or_else(|| info.tex_coord()),
+        ),
+        uv_transform: transform
+            .map(|transform| {
+                MaterialTexture::uv_transform(
+                    Vec2::from(transform.offset()),
+                    transform.rotation(),
+                    Vec2::from(transform.scale()),
+                )
+            })
+            .unwrap_or(Mat3::IDENTITY),
+        ..material_texture(textures, info.texture(), color_space)
+    }
+}
+
+/// The normal texture with its uv set and KHR_texture_transform
+fn normal_texture_info(
+    textures: &HashMap<usize, TextureData>,
+    texture: &gltf::material::NormalTexture,
+    transform: Option<&TextureTransform>,
+) -> MaterialTexture {
+    MaterialTexture {
+        // The extension can override the uv set of the texture
+        uv_channel: uv_channel(
+            transform
+                .and_then(|transform| transform.tex_coord)
+                .unwrap_or_else(|| texture.tex_coord()),
+        ),
+        uv_transform: transform
+            .map(|transform| {
+                MaterialTexture::uv_transform(
+                    Vec2::from(transform.offset.0),
+                    transform.rotation.0,
+                    Vec2::from(transform.scale.0),
+                )
+            })
+            .unwrap_or(Mat3::IDENTITY),
+        ..material_texture(textures, texture.texture(), ColorSpace::Normal)
+    }
+}
+
+/// Only the first 2 uv sets are loaded
+fn uv_channel(tex_coord: u32) -> u32 {
+    if tex_coord > 1 {
+        log::warn!("TEXCOORD_{tex_coord} isn't supported, TEXCOORD_0 is used instead");
+        0
+    } else {
+        tex_coord
+    }
+}
+
+fn sampler_descriptor(sampler: &gltf::texture::Sampler) -> SamplerDescriptor {
+    use gltf::texture::{MagFilter, MinFilter, WrappingMode};
+
+    let address_mode = |mode: WrappingMode| match mode {
+        WrappingMode::ClampToEdge => wgpu::AddressMode::ClampToEdge,
+        WrappingMode::MirroredRepeat => wgpu::AddressMode::MirrorRepeat,
+        WrappingMode::Repeat => wgpu::AddressMode::Repeat,
+    };
+    let mag_filter = match sampler.mag_filter() {
+        Some(MagFilter::Nearest) => wgpu::FilterMode::Nearest,
+        Some(MagFilter::Linear) | None => wgpu::FilterMode::Linear,
+    };
+    // Filters without mipmaps still use the closest mip
+    let (min_filter, mipmap_filter) = match sampler.min_filter() {
+        Some(MinFilter::Nearest) | Some(MinFilter::NearestMipmapNearest) => {
+            (wgpu::FilterMode::Nearest, wgpu::FilterMode::Nearest)
+        }
+        Some(MinFilter::Linear) | Some(MinFilter::LinearMipmapNearest) => {
+            (wgpu::FilterMode::Linear, wgpu::FilterMode::Nearest)
+        }
+        Some(MinFilter::NearestMipmapLinear) => {
+            (wgpu::FilterMode::Nearest, wgpu::FilterMode::Linear)
+        }
+        Some(MinFilter::LinearMipmapLinear) | None => {
+            (wgpu::FilterMode::Linear, wgpu::FilterMode::Linear)
+        }
+    };
+
+    SamplerDescriptor {
+        address_mode_u: address_mode(sampler.wrap_s()),
+        address_mode_v: address_mode(sampler.wrap_t()),
+        mag_filter,
+        min_filter,
+        mipmap_filter,
+        ..Default::default()
+    }
+}
+
 fn generate_mesh(
     primitive: gltf::Primitive,
     buffer_data: &[Vec<u8>],
@@ -119,7 +365,7 @@ fn generate_mesh(
         _ => anyhow::bail!("Only triangle list are currently supported"),
     };
 
-    let reader = primitive.reader(|buffer| Some(&buffer_data[buffer.index()]));
+    let reader = primitive.reader(|buffer| buffer_data.get(buffer.index()).map(Vec::as_slice));
 
     let positions = if let Some(positions) = reader.read_positions() {
         positions.map(Vec3::from).collect::<Vec<_>>()
@@ -137,6 +383,16 @@ fn generate_mesh(
         .map(|uvs| uvs.into_f32().map(Vec2::from).collect::<Vec<_>>())
         .unwrap_or_default();
 
+    let uvs1 = reader
+        .read_tex_coords(1)
+        .map(|uvs| uvs.into_f32().map(Vec2::from).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    let colors = reader
+        .read_colors(0)
+        .map(|colors| colors.into_rgba_f32().map(Vec4::from).collect::<Vec<_>>())
+        .unwrap_or_default();
+
     let indices: Option<Vec<_>> = reader
         .read_indices()
         .map(|indices| indices.into_u32().collect());
@@ -150,23 +406,51 @@ fn generate_mesh(
                 normals[i]
             },
             uv: if uvs.is_empty() { Vec2::ZERO } else { uvs[i] },
-            tangent: Vec3::ZERO,
-            bitangent: Vec3::ZERO,
+            tangent: Vec4::ZERO,
+            color: if colors.is_empty() {
+                Vec4::ONE
+            } else {
+                colors[i]
+            },
+            uv1: match (uvs1.is_empty(), uvs.is_empty()) {
+                (false, _) => uvs1[i],
+                (true, false) => uvs[i],
+                (true, true) => Vec2::ZERO,
+            },
         })
         .collect();
 
-    let mut mesh = crate::mesh::Mesh {
-        vertices,
-        indices,
-        material_id: primitive.material().index(),
-    };
+    let mut mesh = Mesh::from_vertices(&vertices, indices);
+    mesh.material_id = primitive.material().index();
 
+    // Skinning isn't supported yet but the attributes are kept so they can be used by custom shaders
+    if let Some(joints) = reader.read_joints(0) {
+        mesh.insert_attribute(
+            Mesh::ATTRIBUTE_JOINT_INDEX,
+            joints.into_u16().collect::<Vec<_>>(),
+        );
+    }
+    if let Some(weights) = reader.read_weights(0) {
+        mesh.insert_attribute(
+            Mesh::ATTRIBUTE_JOINT_WEIGHT,
+            weights.into_f32().collect::<Vec<_>>(),
+        );
+    }
+
+    // The spec requires flat normals when they aren't specified
     if normals.is_empty() {
-        mesh.compute_normals();
+        mesh.duplicate_vertices();
+        mesh.compute_flat_normals();
     }
 
-    // TODO should use tangents if present instead of computing it
-    if !normals.is_empty() && primitive.material().normal_texture().is_some() {
+    // The spec requires ignoring the tangents when the normals aren't specified
+    let tangents = reader
+        .read_tangents()
+        .filter(|_| !normals.is_empty())
+        .map(|tangents| tangents.collect::<Vec<_>>());
+    if let Some(tangents) = tangents {
+        mesh.insert_attribute(Mesh::ATTRIBUTE_TANGENT, tangents);
+    } else if !uvs.is_empty() && primitive.material().normal_texture().is_some() {
         mesh.compute_tangents();
     }
 
@@ -207,15 +491,40 @@ async fn load_buffers<'a>(
 async fn load_texture<'a>(
     gltf_texture: &gltf::Texture<'a>,
     load_context: &LoadContext<'a>,
-) -> anyhow::Result<RgbaImage> {
+    buffer_data: &[Vec<u8>],
+) -> anyhow::Result<TextureData> {
     let source = gltf_texture.source().source();
     Ok(match source {
-        gltf::image::Source::View { .. } => todo!("Gltf view not supported"),
+        // Images embedded in a buffer, usually in the binary chunk of a .glb
+        gltf::image::Source::View { view, mime_type } => {
+            let start = view.offset();
+            let bytes = buffer_data
+                .get(view.buffer().index())
+                .and_then(|buffer| buffer.get(start..start + view.length()))
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Buffer view {} of image {:?} is out of bounds",
+                        view.index(),
+                        gltf_texture.source().name()
+                    )
+                })?;
+            if mime_type == "image/ktx2" {
+                TextureData::Compressed(load_ktx2(bytes)?)
+            } else {
+                TextureData::Rgba(image::load_from_memory(bytes)?.to_rgba8())
+            }
+        }
         gltf::image::Source::Uri { uri, mime_type } => {
             let image_path = load_context.path().parent().unwrap().join(uri);
             log::info!("uri: {uri} mime: {mime_type:?} path: {image_path:?}");
             let bytes = load_context.read_asset_bytes(image_path).await?;
-            image::load_from_memory(&bytes)?.to_rgba8()
+            // The gltf crate doesn't expose KHR_texture_basisu,
+            // so KTX2 images are only supported when they are the source of the texture
+            if mime_type == Some("image/ktx2") || is_ktx2(uri) {
+                TextureData::Compressed(load_ktx2(&bytes)?)
+            } else {
+                TextureData::Rgba(image::load_from_memory(&bytes)?.to_rgba8())
+            }
         }
     })
 }
@@ -7,8 +7,10 @@ use bevy::{
 
 use crate::{
     gltf_loader::loader::load_gltf,
+    mesh_optimizer::MeshOptimizationSettings,
     model::{Material, Model, ModelMesh},
     renderer::WgpuRenderer,
+    texture::DeviceFeatures,
 };
 
 mod loader;
@@ -26,19 +28,36 @@ impl Plugin for GltfLoaderPlugin {
     }
 }
 
-#[derive(Default)]
-pub struct GltfLoader;
+pub struct GltfLoader {
+    mesh_optimization: MeshOptimizationSettings,
+    device_features: DeviceFeatures,
+}
+
+impl FromWorld for GltfLoader {
+    fn from_world(world: &mut World) -> Self {
+        Self {
+            mesh_optimization: world
+                .get_resource::<MeshOptimizationSettings>()
+                .cloned()
+                .unwrap_or_default(),
+            device_features: world
+                .get_resource::<DeviceFeatures>()
+                .cloned()
+                .unwrap_or_default(),
+        }
+    }
+}
 
 #[derive(Debug, TypeUuid)]
 #[uuid = "d87cb7a6-21b0-4c5a-933e-9edfe42e653b"]
 pub struct LoadedGltf {
-    materials: Vec<Material>,
-    meshes: Vec<crate::mesh::Mesh>,
+    pub materials: Vec<Handle<Material>>,
+    pub meshes: Vec<crate::mesh::Mesh>,
 }
 
 impl AssetLoader for GltfLoader {
     fn extensions(&self) -> &[&str] {
-        &["gltf"]
+        &["gltf", "glb"]
     }
 
     fn load<'a>(
@@ -51,7 +70,13 @@ impl AssetLoader for GltfLoader {
 
             log::info!("Loading {:?}", load_context.path());
 
-            let loaded_gltf = load_gltf(bytes, load_context).await?;
+            let loaded_gltf = load_gltf(
+                bytes,
+                load_context,
+                &self.mesh_optimization,
+                &self.device_features,
+            )
+            .await?;
             load_context.set_default_asset(LoadedAsset::new(loaded_gltf));
 
             log::info!(
@@ -81,13 +106,12 @@ fn gltf_spawner(
             let LoadedGltf { materials, meshes } = gltf;
 
             // TODO mesh label for gltf
-            let model_meshes = meshes
-                .iter()
-                .map(|mesh| ModelMesh::from_mesh("", &renderer.device, mesh))
-                .collect();
+            let mut model_meshes = ModelMesh::from_meshes("", &renderer.device, meshes);
+            for (model_mesh, mesh) in model_meshes.iter_mut().zip(meshes) {
+                model_mesh.material = mesh.material_id.map(|id| materials[id].clone());
+            }
 
             commands.entity(entity).insert(Model {
-                materials: materials.clone(),
                 meshes: model_meshes,
             });
 
@@ -1,17 +1,172 @@
 use bevy::prelude::Color;
-use image::{DynamicImage, Rgba, RgbaImage};
+use image::{imageops::FilterType, Rgba, RgbaImage};
 
-pub fn image_from_color(color: Color) -> RgbaImage {
-    let mut rgba = RgbaImage::new(1, 1);
-    rgba.put_pixel(
-        0,
-        0,
+use crate::texture::ColorSpace;
+
+/// A 1x1 image of the color encoded in the given color space.
+/// The image must be uploaded with the same color space to get the color back in the shader.
+pub fn image_from_color(color: Color, color_space: ColorSpace) -> RgbaImage {
+    let [r, g, b, a] = match color_space {
+        ColorSpace::Srgb => color.as_rgba_f32(),
+        ColorSpace::Linear | ColorSpace::Normal => color.as_linear_rgba_f32(),
+    };
+    let to_u8 = |v: f32| (v * 255.0).round().clamp(0.0, 255.0) as u8;
+    RgbaImage::from_pixel(1, 1, Rgba([to_u8(r), to_u8(g), to_u8(b), to_u8(a)]))
+}
+
+/// Multiplies the alpha of the image by the red channel of the mask.
+/// If the sizes don't match, the mask is resized to the size of the image,
+/// unless the image is a single color in which case the result has the size of the mask.
+pub fn apply_alpha_mask(image: &RgbaImage, mask: &RgbaImage) -> RgbaImage {
+    let (width, height) = if image.dimensions() == (1, 1) {
+        mask.dimensions()
+    } else {
+        image.dimensions()
+    };
+    let resize = |img: &RgbaImage| {
+        if img.dimensions() == (width, height) {
+            img.clone()
+        } else {
+            image::imageops::resize(img, width, height, FilterType::Triangle)
+        }
+    };
+
+    let mut result = resize(image);
+    let mask = resize(mask);
+    for (pixel, mask_pixel) in result.pixels_mut().zip(mask.pixels()) {
+        pixel[3] = ((pixel[3] as u32 * mask_pixel[0] as u32) / 255) as u8;
+    }
+    result
+}
+
+/// Returns true when the red channel of the mask is only fully black or fully white
+pub fn is_binary_mask(mask: &RgbaImage) -> bool {
+    mask.pixels().all(|pixel| pixel[0] == 0 || pixel[0] == 255)
+}
+
+/// Number of mip levels needed to go from the full size down to 1x1
+pub fn mip_level_count(width: u32, height: u32) -> u32 {
+    32 - width.max(height).max(1).leading_zeros()
+}
+
+/// Generates every mip level after the base level by averaging blocks of 2x2 pixels.
+/// The color channels of sRGB images are averaged in linear space to avoid darkening the mips
+/// and the averaged normals of normal maps are renormalized. The alpha channel is always linear.
+pub fn generate_mips(image: &RgbaImage, color_space: ColorSpace) -> Vec<RgbaImage> {
+    let (width, height) = image.dimensions();
+    let mut mips: Vec<RgbaImage> = vec![];
+    for _ in 1..mip_level_count(width, height) {
+        let mip = downsample(mips.last().unwrap_or(image), color_space);
+        mips.push(mip);
+    }
+    mips
+}
+
+fn downsample(image: &RgbaImage, color_space: ColorSpace) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    let (mip_width, mip_height) = ((width / 2).max(1), (height / 2).max(1));
+    let srgb = color_space == ColorSpace::Srgb;
+
+    let to_linear = |v: u8| {
+        let v = v as f32 / 255.0;
+        if srgb {
+            srgb_to_linear(v)
+        } else {
+            v
+        }
+    };
+    let from_linear = |v: f32| {
+        let v = if srgb { linear_to_srgb(v) } else { v };
+        (v * 255.0).round().clamp(0.0, 255.0) as u8
+    };
+
+    RgbaImage::from_fn(mip_width, mip_height, |x, y| {
+        let mut sum = [0.0f32; 4];
+        // Odd sizes are clamped so the last row or column is reused
+        for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            let px = (x * 2 + dx).min(width - 1);
+            let py = (y * 2 + dy).min(height - 1);
+            let pixel = image.get_pixel(px, py);
+            for (sum, value) in sum.iter_mut().zip(pixel.0).take(3) {
+                *sum += to_linear(value);
+            }
+            sum[3] += pixel[3] as f32 / 255.0;
+        }
+        let mut color = [sum[0] / 4.0, sum[1] / 4.0, sum[2] / 4.0];
+        if color_space == ColorSpace::Normal {
+            color = renormalize(color);
+        }
         Rgba([
-            (color.r() * 255.0) as u8,
-            (color.g() * 255.0) as u8,
-            (color.b() * 255.0) as u8,
-            (color.a() * 255.0) as u8,
-        ]),
-    );
-    DynamicImage::ImageRgba8(rgba).to_rgba8()
+            from_linear(color[0]),
+            from_linear(color[1]),
+            from_linear(color[2]),
+            (sum[3] / 4.0 * 255.0).round() as u8,
+        ])
+    })
+}
+
+/// Averaging unit vectors shortens them, the xyz stored in 0..1 is scaled back to a length of 1
+fn renormalize(color: [f32; 3]) -> [f32; 3] {
+    let normal = color.map(|v| v * 2.0 - 1.0);
+    let length = normal.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if length <= f32::EPSILON {
+        return color;
+    }
+    normal.map(|v| v / length * 0.5 + 0.5)
+}
+
+fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.003_130_8 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mip_level_count_goes_down_to_1x1() {
+        assert_eq!(mip_level_count(1, 1), 1);
+        assert_eq!(mip_level_count(2, 2), 2);
+        assert_eq!(mip_level_count(5, 3), 3);
+        assert_eq!(mip_level_count(256, 64), 9);
+        assert_eq!(mip_level_count(0, 0), 1);
+    }
+
+    #[test]
+    fn srgb_downsample_averages_in_linear_space() {
+        let mut image = RgbaImage::from_pixel(2, 2, Rgba([0, 0, 0, 0]));
+        image.put_pixel(1, 0, Rgba([255, 255, 255, 255]));
+        image.put_pixel(0, 1, Rgba([255, 255, 255, 255]));
+
+        let mips = generate_mips(&image, ColorSpace::Srgb);
+        assert_eq!(mips.len(), 1);
+        assert_eq!(mips[0].dimensions(), (1, 1));
+        // Half the light is 188 in sRGB, averaging the sRGB values would give 128
+        assert_eq!(mips[0].get_pixel(0, 0), &Rgba([188, 188, 188, 128]));
+    }
+
+    #[test]
+    fn normal_downsample_is_renormalized() {
+        let mut image = RgbaImage::from_pixel(2, 2, Rgba([255, 128, 128, 255]));
+        image.put_pixel(1, 0, Rgba([128, 255, 128, 255]));
+        image.put_pixel(1, 1, Rgba([128, 255, 128, 255]));
+
+        let mip = &generate_mips(&image, ColorSpace::Normal)[0];
+        let [x, y, z, _] = mip.get_pixel(0, 0).0.map(|v| v as f32 / 255.0 * 2.0 - 1.0);
+        let length = (x * x + y * y + z * z).sqrt();
+        assert!((length - 1.0).abs() < 0.01, "length {length}");
+        assert!((x - y).abs() < 0.01);
+    }
 }
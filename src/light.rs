@@ -5,7 +5,10 @@ use bevy::{
     prelude::{Color, Component},
 };
 
-use crate::model::{Model, ModelMesh};
+use crate::{
+    model::{Model, ModelMesh},
+    renderer::render_phase_3d::LayoutPipelines,
+};
 
 #[derive(Component)]
 pub struct Light {
@@ -28,27 +31,30 @@ fn draw_light_mesh_instanced<'a>(
     instances: Range<u32>,
     mesh_view_bind_group: &'a wgpu::BindGroup,
 ) {
-    render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
-    render_pass.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
     render_pass.set_bind_group(0, mesh_view_bind_group, &[]);
-    render_pass.draw_indexed(0..mesh.num_elements, 0, instances);
+    mesh.draw_vertices(render_pass, instances);
 }
 
 pub fn draw_light_model<'a>(
     render_pass: &mut wgpu::RenderPass<'a>,
     model: &'a Model,
+    pipelines: &'a LayoutPipelines<wgpu::RenderPipeline>,
     mesh_view_bind_group: &'a wgpu::BindGroup,
 ) {
-    draw_light_model_instanced(render_pass, model, 0..1, mesh_view_bind_group);
+    draw_light_model_instanced(render_pass, model, 0..1, pipelines, mesh_view_bind_group);
 }
 
 fn draw_light_model_instanced<'a>(
     render_pass: &mut wgpu::RenderPass<'a>,
     model: &'a Model,
     instances: Range<u32>,
+    pipelines: &'a LayoutPipelines<wgpu::RenderPipeline>,
     mesh_view_bind_group: &'a wgpu::BindGroup,
 ) {
     for mesh in &model.meshes {
-        draw_light_mesh_instanced(render_pass, mesh, instances.clone(), mesh_view_bind_group);
+        if let Some(pipeline) = pipelines.get(&mesh.layout) {
+            render_pass.set_pipeline(pipeline);
+            draw_light_mesh_instanced(render_pass, mesh, instances.clone(), mesh_view_bind_group);
+        }
     }
 }
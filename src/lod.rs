@@ -0,0 +1,120 @@
+use bevy::prelude::*;
+
+use crate::{bounds::Sphere, camera::Camera, model::Model, transform::Transform};
+
+/// Selects the lod of the meshes of a `Model` based on its size on screen.
+/// The lods are generated when loading the model, see `MeshOptimizationSettings`
+pub struct LodPlugin;
+
+impl Plugin for LodPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(update_lods);
+    }
+}
+
+/// Add this to an entity with a `Model` and a `Transform` to draw simplified meshes when it gets smaller on screen.
+/// Instanced models use the `Transform` of the entity for every instance
+#[derive(Component)]
+pub struct Lod {
+    /// The fraction of the screen height under which each lod is used.
+    /// `screen_sizes[0]` is where the first simplified mesh starts being used
+    pub screen_sizes: Vec<f32>,
+    /// How far past a threshold the size must go before switching lod.
+    /// This avoids flickering when the model stays close to a threshold
+    pub hysteresis: f32,
+    /// The selected lod, 0 is the full mesh
+    pub level: usize,
+}
+
+impl Default for Lod {
+    fn default() -> Self {
+        Self {
+            screen_sizes: vec![0.5, 0.25, 0.125],
+            hysteresis: 0.1,
+            level: 0,
+        }
+    }
+}
+
+impl Lod {
+    /// The lod to use for the given screen size starting from the current one
+    pub fn select(&self, screen_size: f32) -> usize {
+        let mut level = self.level.min(self.screen_sizes.len());
+        while level < self.screen_sizes.len()
+            && screen_size < self.screen_sizes[level] * (1.0 - self.hysteresis)
+        {
+            level += 1;
+        }
+        while level > 0 && screen_size > self.screen_sizes[level - 1] * (1.0 + self.hysteresis) {
+            level -= 1;
+        }
+        level
+    }
+}
+
+fn update_lods(camera: Res<Camera>, mut query: Query<(&mut Lod, &mut Model, &Transform)>) {
+    // Scales a size at a distance of 1 to a fraction of the screen height
+    let projection_scale = camera.projection.compute_matrix().y_axis.y.abs();
+
+    for (mut lod, mut model, transform) in query.iter_mut() {
+        let Sphere { center, radius } = model
+            .bounding_sphere()
+            .transform(&transform.compute_matrix());
+
+        let distance = center.distance(camera.eye);
+        let screen_size = if distance > radius {
+            radius * projection_scale / distance
+        } else {
+            f32::MAX
+        };
+
+        let level = lod.select(screen_size);
+        if level != lod.level {
+            lod.level = level;
+            for mesh in &mut model.meshes {
+                mesh.lod = level.min(mesh.lods.len());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select_switches_past_hysteresis() {
+        let lod = Lod::default();
+        assert_eq!(lod.select(1.0), 0);
+        assert_eq!(lod.select(0.46), 0);
+        assert_eq!(lod.select(0.44), 1);
+        assert_eq!(lod.select(0.01), 3);
+
+        let lod = Lod {
+            level: 1,
+            ..Default::default()
+        };
+        assert_eq!(lod.select(0.54), 1);
+        assert_eq!(lod.select(0.56), 0);
+    }
+
+    #[test]
+    fn select_does_not_oscillate_around_threshold() {
+        let mut lod = Lod::default();
+        lod.level = lod.select(0.4);
+        assert_eq!(lod.level, 1);
+
+        // Going back and forth around 0.5 stays on the same lod
+        for screen_size in [0.52, 0.48, 0.54, 0.46, 0.5, 0.53] {
+            lod.level = lod.select(screen_size);
+            assert_eq!(lod.level, 1);
+        }
+
+        lod.level = lod.select(0.6);
+        assert_eq!(lod.level, 0);
+        for screen_size in [0.48, 0.52, 0.46, 0.54, 0.5] {
+            lod.level = lod.select(screen_size);
+            assert_eq!(lod.level, 0);
+        }
+    }
+}
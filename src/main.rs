@@ -2,7 +2,7 @@
 
 use bevy::{
     app::AppExit,
-    asset::AssetPlugin,
+    asset::{AssetPlugin, AssetServerSettings},
     input::{Input, InputPlugin},
     math::{const_vec3, Quat, Vec3},
     prelude::*,
@@ -12,31 +12,53 @@ use bevy::{
 };
 
 use crate::{
+    camera::Camera,
     egui_plugin::EguiPlugin,
+    exporter::ExportMesh,
+    fog::{Fog, FogFalloff, HeightFalloff},
     gltf_loader::{GltfBundle, GltfLoaderPlugin},
-    image_utils::image_from_color,
     instances::Instances,
     light::Light,
-    model::Model,
+    lod::{Lod, LodPlugin},
+    material_loader::MaterialLoaderPlugin,
+    model::{AlphaMode, MaterialTexture, Model},
     obj_loader::{ObjBundle, ObjLoaderPlugin},
     renderer::{
-        plugin::WgpuRendererPlugin, render_phase_3d::RenderPhase3dDescriptor, WgpuRenderer,
+        culling::{CullingSettings, CullingStats},
+        custom_material::CustomMaterialPlugin,
+        plugin::WgpuRendererPlugin,
+        render_phase_3d::{RenderPhase3dDescriptor, TransparencyMode},
+        ssao_pass::{SsaoSettings, MAX_SSAO_SAMPLES},
+        WgpuRenderer,
     },
+    texture::ColorSpace,
+    toon_material::ToonMaterial,
     transform::Transform,
 };
 
+mod bounds;
 mod camera;
+mod compressed_texture;
 mod egui_plugin;
+mod exporter;
+mod fog;
 mod gltf_loader;
 mod image_utils;
 mod instances;
 mod light;
+mod lod;
+mod material_loader;
 mod mesh;
+mod mesh_optimizer;
+mod mesh_simplifier;
 mod model;
 mod obj_loader;
+mod raycast;
 mod renderer;
 mod shapes;
+mod static_batching;
 mod texture;
+mod toon_material;
 mod transform;
 
 const NUM_INSTANCES_PER_ROW: u32 = 6;
@@ -110,6 +132,11 @@ fn main() {
             clear_color: Color::rgba(0.1, 0.1, 0.1, 1.0),
             ..default()
         })
+        // Used to hot reload .material.ron files
+        .insert_resource(AssetServerSettings {
+            watch_for_changes: true,
+            ..default()
+        })
         .insert_resource(CameraSettings { speed: 10.0 })
         .insert_resource(LightSettings {
             rotate: true,
@@ -123,12 +150,16 @@ fn main() {
         .add_plugins(MinimalPlugins)
         .add_plugin(WindowPlugin::default())
         .add_plugin(WinitPlugin)
+        // The renderer registers the Material asset so it needs the AssetPlugin
+        .add_plugin(AssetPlugin)
         .add_plugin(WgpuRendererPlugin)
         .add_plugin(InputPlugin::default())
-        .add_plugin(AssetPlugin)
         .add_plugin(ObjLoaderPlugin)
         .add_plugin(EguiPlugin)
         .add_plugin(GltfLoaderPlugin)
+        .add_plugin(MaterialLoaderPlugin)
+        .add_plugin(LodPlugin)
+        .add_plugin(CustomMaterialPlugin::<ToonMaterial>::default())
         .add_startup_system(spawn_light)
         // .add_startup_system(spawn_shapes)
         .add_startup_system(spawn_obj_asset)
@@ -139,6 +170,8 @@ fn main() {
         .add_system(move_instances)
         .add_system(update_light)
         .add_system(exit_on_esc)
+        .add_system(pick_model)
+        .add_system(export_shapes)
         .add_system(settings_ui)
         .add_system(update_materials)
         .run();
@@ -147,10 +180,7 @@ fn main() {
 fn spawn_light(mut commands: Commands, renderer: Res<WgpuRenderer>) {
     let cube = shapes::cube::Cube::new(1.0, 1.0, 1.0);
     let mesh = cube.mesh(&renderer.device);
-    let model = Model {
-        meshes: vec![mesh],
-        materials: vec![],
-    };
+    let model = Model { meshes: vec![mesh] };
 
     let light = Light {
         position: LIGHT_POSITION,
@@ -160,7 +190,13 @@ fn spawn_light(mut commands: Commands, renderer: Res<WgpuRenderer>) {
     commands.spawn().insert(light).insert(model);
 }
 
-fn spawn_shapes(mut commands: Commands, renderer: Res<WgpuRenderer>) {
+fn spawn_shapes(
+    mut commands: Commands,
+    renderer: Res<WgpuRenderer>,
+    asset_server: Res<AssetServer>,
+    mut materials: ResMut<Assets<model::Material>>,
+    mut toon_materials: ResMut<Assets<ToonMaterial>>,
+) {
     let diffuse_texture_bytes =
         std::fs::read("assets/rock_plane/Rock-Albedo.png").expect("failed to read rock_albedo");
     let diffuse_texture = image::load_from_memory(&diffuse_texture_bytes)
@@ -173,22 +209,35 @@ fn spawn_shapes(mut commands: Commands, renderer: Res<WgpuRenderer>) {
         .unwrap()
         .to_rgba8();
 
+    let rock_material = materials.add(model::Material {
+        name: "rock_material".to_string(),
+        diffuse_texture: diffuse_texture.into(),
+        alpha: 1.0,
+        alpha_mode: AlphaMode::Opaque,
+        double_sided: false,
+        gloss: 1.0,
+        specular: Vec3::new(1.0, 1.0, 1.0),
+        base_color: Color::WHITE.as_linear_rgba_f32().into(),
+        emissive: Vec3::ZERO,
+        emissive_intensity: 1.0,
+        normal_texture: Some(MaterialTexture {
+            color_space: ColorSpace::Normal,
+            ..MaterialTexture::from(normal_texture)
+        }),
+        specular_texture: None,
+        emissive_texture: None,
+    });
+    // The sphere and capsule share the same material
+    let default_material = materials.add(get_default_material(Color::WHITE));
+
+    let mut plane_mesh = shapes::plane::Plane {
+        resolution: 1,
+        size: 5.0,
+    }
+    .mesh(&renderer.device);
+    plane_mesh.material = Some(rock_material);
     let plane = Model {
-        meshes: vec![shapes::plane::Plane {
-            resolution: 1,
-            size: 5.0,
-        }
-        .mesh(&renderer.device)],
-        materials: vec![model::Material {
-            name: "rock_material".to_string(),
-            diffuse_texture,
-            alpha: 1.0,
-            gloss: 1.0,
-            specular: Vec3::new(1.0, 1.0, 1.0),
-            base_color: Color::WHITE.as_rgba_f32().into(),
-            normal_texture: Some(normal_texture),
-            specular_texture: None,
-        }],
+        meshes: vec![plane_mesh],
     };
     commands.spawn_bundle((
         plane,
@@ -198,9 +247,11 @@ fn spawn_shapes(mut commands: Commands, renderer: Res<WgpuRenderer>) {
         },
     ));
 
+    let mut cube_mesh = shapes::cube::Cube::new(1.0, 1.0, 1.0).mesh(&renderer.device);
+    // Edit the file while the app is running to see the changes
+    cube_mesh.material = Some(asset_server.load("learn_opengl/container2/container2.material.ron"));
     let cube = Model {
-        meshes: vec![shapes::cube::Cube::new(1.0, 1.0, 1.0).mesh(&renderer.device)],
-        materials: vec![get_default_material(Color::WHITE)],
+        meshes: vec![cube_mesh],
     };
     commands.spawn_bundle((
         cube,
@@ -210,9 +261,10 @@ fn spawn_shapes(mut commands: Commands, renderer: Res<WgpuRenderer>) {
         },
     ));
 
+    let mut sphere_mesh = shapes::sphere::UVSphere::default().mesh(&renderer.device);
+    sphere_mesh.material = Some(default_material.clone());
     let sphere = Model {
-        meshes: vec![shapes::sphere::UVSphere::default().mesh(&renderer.device)],
-        materials: vec![get_default_material(Color::WHITE)],
+        meshes: vec![sphere_mesh],
     };
     commands.spawn_bundle((
         sphere,
@@ -222,9 +274,10 @@ fn spawn_shapes(mut commands: Commands, renderer: Res<WgpuRenderer>) {
         },
     ));
 
+    let mut capsule_mesh = shapes::capsule::Capsule::default().mesh(&renderer.device);
+    capsule_mesh.material = Some(default_material.clone());
     let capsule = Model {
-        meshes: vec![shapes::capsule::Capsule::default().mesh(&renderer.device)],
-        materials: vec![get_default_material(Color::WHITE)],
+        meshes: vec![capsule_mesh],
     };
     commands.spawn_bundle((
         capsule,
@@ -233,18 +286,38 @@ fn spawn_shapes(mut commands: Commands, renderer: Res<WgpuRenderer>) {
             ..default()
         },
     ));
+
+    let toon_sphere = Model {
+        meshes: vec![shapes::sphere::UVSphere::default().mesh(&renderer.device)],
+    };
+    commands.spawn_bundle((
+        toon_sphere,
+        toon_materials.add(ToonMaterial {
+            color: Color::rgb(0.2, 0.6, 1.0),
+            bands: 4,
+        }),
+        Transform {
+            translation: Vec3::ZERO + (Vec3::X * 3.0),
+            ..default()
+        },
+    ));
 }
 
 fn get_default_material(base_color: Color) -> model::Material {
     model::Material {
         name: "default_material".to_string(),
-        diffuse_texture: image_from_color(Color::WHITE),
+        diffuse_texture: MaterialTexture::from_color(Color::WHITE, ColorSpace::Srgb),
         alpha: 1.0,
+        alpha_mode: AlphaMode::Opaque,
+        double_sided: false,
         gloss: 1.0,
         specular: Vec3::new(1.0, 1.0, 1.0),
-        base_color: base_color.as_rgba_f32().into(),
+        base_color: base_color.as_linear_rgba_f32().into(),
+        emissive: Vec3::ZERO,
+        emissive_intensity: 1.0,
         normal_texture: None,
         specular_texture: None,
+        emissive_texture: None,
     }
 }
 
@@ -284,7 +357,8 @@ fn spawn_obj_asset(mut commands: Commands, asset_server: Res<AssetServer>) {
         .insert(Transform {
             scale: SCALE,
             ..default()
-        });
+        })
+        .insert(Lod::default());
 }
 
 fn spawn_gltf(mut commands: Commands, asset_server: Res<AssetServer>) {
@@ -298,7 +372,8 @@ fn spawn_gltf(mut commands: Commands, asset_server: Res<AssetServer>) {
             scale: SCALE,
             // translation: Vec3::new(2.0, 0.0, 0.0),
             ..default()
-        });
+        })
+        .insert(Lod::default());
 }
 
 fn update_window_title(time: Res<Time>, mut windows: ResMut<Windows>) {
@@ -331,6 +406,80 @@ fn cursor_moved(
     }
 }
 
+/// Logs the mesh under the cursor when clicking outside of the ui
+fn pick_model(
+    mouse_input: Res<Input<MouseButton>>,
+    windows: Res<Windows>,
+    camera: Res<Camera>,
+    ctx: Res<egui::Context>,
+    query: Query<(Entity, &Model, Option<&Transform>, Option<&Instances>), Without<Light>>,
+) {
+    if !mouse_input.just_pressed(MouseButton::Left) || ctx.wants_pointer_input() {
+        return;
+    }
+    let window = match windows.get_primary() {
+        Some(window) => window,
+        None => return,
+    };
+    let cursor = match window.cursor_position() {
+        Some(cursor) => cursor,
+        None => return,
+    };
+
+    let ray = camera.viewport_to_ray(cursor, Vec2::new(window.width(), window.height()));
+    match raycast::raycast(&ray, query.iter()) {
+        Some(hit) => log::info!(
+            "Picked {:?} instance {} mesh {} at {} normal {} uv {:?}",
+            hit.entity,
+            hit.instance,
+            hit.mesh,
+            hit.point,
+            hit.normal,
+            hit.uv
+        ),
+        None => log::info!("Nothing picked"),
+    }
+}
+
+/// Exports a few shapes to every supported format when pressing P.
+/// The files can be loaded back with the obj and gltf loaders
+fn export_shapes(key_input: Res<Input<KeyCode>>) {
+    if !key_input.just_pressed(KeyCode::P) {
+        return;
+    }
+
+    let materials = [get_default_material(Color::WHITE)];
+    let mut meshes = [
+        shapes::cube::Cube::new(1.0, 1.0, 1.0).to_mesh(),
+        shapes::sphere::UVSphere::default().to_mesh(),
+        shapes::capsule::Capsule::default().to_mesh(),
+    ];
+    for mesh in &mut meshes {
+        mesh.material_id = Some(0);
+    }
+    let export_meshes: Vec<_> = ["cube", "sphere", "capsule"]
+        .into_iter()
+        .zip(&meshes)
+        .enumerate()
+        .map(|(i, (name, mesh))| {
+            ExportMesh::new(name, mesh)
+                .with_transform(Mat4::from_translation(Vec3::X * 1.5 * i as f32))
+        })
+        .collect();
+
+    let export = || -> anyhow::Result<()> {
+        let directory = std::path::Path::new("exports");
+        std::fs::create_dir_all(directory)?;
+        exporter::export_obj(&directory.join("shapes.obj"), &export_meshes, &materials)?;
+        exporter::export_gltf(&directory.join("shapes.gltf"), &export_meshes, &materials)?;
+        exporter::export_gltf(&directory.join("shapes.glb"), &export_meshes, &materials)?;
+        Ok(())
+    };
+    if let Err(err) = export() {
+        log::error!("Failed to export the shapes: {err:?}");
+    }
+}
+
 fn move_instances(
     time: Res<Time>,
     mut query: Query<(&mut Instances, &mut Wave)>,
@@ -397,24 +546,36 @@ fn update_light(mut query: Query<&mut Light>, time: Res<Time>, settings: Res<Lig
     }
 }
 
-fn update_materials(mut query: Query<&mut Model>, settings: Res<GlobalMaterialSettings>) {
-    if !settings.is_changed() {
+fn update_materials(
+    mut materials: ResMut<Assets<model::Material>>,
+    settings: Res<GlobalMaterialSettings>,
+    mut applied_gloss: Local<Option<f32>>,
+) {
+    // The settings ui borrows the settings mutably every frame so they are always changed.
+    // The gloss of the materials is only overridden once the slider is moved
+    let previous = applied_gloss.replace(settings.gloss);
+    if previous.is_none() || previous == Some(settings.gloss) {
         return;
     }
 
-    // for mut model in query.iter_mut() {
-    //     for mut material in model.materials.iter_mut() {
-    //         material.gloss = settings.gloss;
-    //     }
-    // }
+    // Every material is marked as modified so the gpu materials are prepared again
+    for (_, material) in materials.iter_mut() {
+        material.gloss = settings.gloss;
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn settings_ui(
     ctx: Res<egui::Context>,
     mut camera_settings: ResMut<CameraSettings>,
     mut light_settings: ResMut<LightSettings>,
     mut global_material_settings: ResMut<GlobalMaterialSettings>,
     mut instance_settings: ResMut<InstanceSettings>,
+    mut fog: ResMut<Fog>,
+    mut ssao_settings: ResMut<SsaoSettings>,
+    mut render_phase_descriptor: ResMut<RenderPhase3dDescriptor>,
+    mut culling_settings: ResMut<CullingSettings>,
+    culling_stats: Res<CullingStats>,
 ) {
     egui::Window::new("Settings")
         .resizable(true)
@@ -450,5 +611,112 @@ fn settings_ui(
             ui.heading("Instances");
 
             ui.checkbox(&mut instance_settings.move_instances, "Move");
+
+            ui.separator();
+
+            ui.heading("Transparency");
+
+            ui.horizontal(|ui| {
+                ui.radio_value(
+                    &mut render_phase_descriptor.transparency_mode,
+                    TransparencyMode::Sorted,
+                    "Sorted",
+                );
+                ui.radio_value(
+                    &mut render_phase_descriptor.transparency_mode,
+                    TransparencyMode::WeightedBlended,
+                    "Weighted blended",
+                );
+            });
+
+            ui.separator();
+
+            ui.heading("Culling");
+
+            ui.checkbox(&mut culling_settings.enabled, "Frustum culling");
+            ui.label(format!(
+                "Visible draws: {} / {}",
+                culling_stats.visible_draws, culling_stats.total_draws
+            ));
+
+            ui.separator();
+
+            ui.heading("SSAO");
+
+            ui.checkbox(&mut ssao_settings.enabled, "Enabled");
+            ui.label("Radius");
+            ui.add(egui::Slider::new(&mut ssao_settings.radius, 0.05..=2.0));
+            ui.label("Intensity");
+            ui.add(egui::Slider::new(&mut ssao_settings.intensity, 0.1..=4.0));
+            ui.label("Bias");
+            ui.add(egui::Slider::new(&mut ssao_settings.bias, 0.0..=0.1));
+            ui.label("Sample count");
+            ui.add(egui::Slider::new(
+                &mut ssao_settings.sample_count,
+                1..=MAX_SSAO_SAMPLES as u32,
+            ));
+
+            ui.separator();
+
+            ui.heading("Fog");
+
+            ui.checkbox(&mut fog.enabled, "Enabled");
+            ui.horizontal(|ui| {
+                if ui
+                    .radio(matches!(fog.falloff, FogFalloff::Linear { .. }), "Linear")
+                    .clicked()
+                {
+                    fog.falloff = FogFalloff::Linear {
+                        start: 5.0,
+                        end: 50.0,
+                    };
+                }
+                if ui
+                    .radio(matches!(fog.falloff, FogFalloff::Exponential { .. }), "Exp")
+                    .clicked()
+                {
+                    fog.falloff = FogFalloff::Exponential { density: 0.05 };
+                }
+                if ui
+                    .radio(
+                        matches!(fog.falloff, FogFalloff::ExponentialSquared { .. }),
+                        "Exp2",
+                    )
+                    .clicked()
+                {
+                    fog.falloff = FogFalloff::ExponentialSquared { density: 0.05 };
+                }
+            });
+            match &mut fog.falloff {
+                FogFalloff::Linear { start, end } => {
+                    ui.label("Start");
+                    ui.add(egui::Slider::new(start, 0.0..=100.0));
+                    ui.label("End");
+                    ui.add(egui::Slider::new(end, 0.0..=200.0));
+                }
+                FogFalloff::Exponential { density }
+                | FogFalloff::ExponentialSquared { density } => {
+                    ui.label("Density");
+                    ui.add(egui::Slider::new(density, 0.0..=0.5));
+                }
+            }
+            let mut height_enabled = fog.height_falloff.is_some();
+            if ui.checkbox(&mut height_enabled, "Height falloff").changed() {
+                fog.height_falloff = height_enabled.then(|| HeightFalloff {
+                    offset: 0.0,
+                    density: 0.2,
+                });
+            }
+            if let Some(height_falloff) = fog.height_falloff.as_mut() {
+                ui.label("Height offset");
+                ui.add(egui::Slider::new(&mut height_falloff.offset, -10.0..=10.0));
+                ui.label("Height density");
+                ui.add(egui::Slider::new(&mut height_falloff.density, 0.0..=2.0));
+            }
+            ui.label("Color");
+            let mut color = [fog.color.r(), fog.color.g(), fog.color.b()];
+            if ui.color_edit_button_rgb(&mut color).changed() {
+                fog.color = Color::rgb(color[0], color[1], color[2]);
+            }
         });
 }
@@ -0,0 +1,236 @@
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    prelude::*,
+};
+use serde::Deserialize;
+
+use crate::{
+    model::{AlphaMode, Material, MaterialTexture},
+    texture::{ColorSpace, DeviceFeatures, SamplerDescriptor, TextureData},
+};
+
+/// Loads a `Material` from a `.material.ron` file.
+/// The material is reloaded every time the file is modified if the AssetServer watches for changes.
+pub struct MaterialLoaderPlugin;
+
+impl Plugin for MaterialLoaderPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset_loader::<MaterialLoader>();
+    }
+}
+
+/// The content of a `.material.ron` file. Every field is optional.
+///
+/// ```ron
+/// (
+///     base_color: (1.0, 0.5, 0.5, 1.0),
+///     alpha_mode: Mask(0.5),
+///     gloss: 0.8,
+///     diffuse_texture: Some((path: "diffuse.png")),
+///     normal_texture: Some((path: "normal.png", clamp: true)),
+///     emissive_texture: Some((path: "emissive.png", uv_channel: 1, transform: Some((scale: (2.0, 2.0))))),
+/// )
+/// ```
+#[derive(Deserialize)]
+#[serde(default)]
+struct MaterialDescriptor {
+    /// Uses the file name if it isn't specified
+    name: Option<String>,
+    base_color: (f32, f32, f32, f32),
+    alpha_mode: AlphaMode,
+    double_sided: bool,
+    gloss: f32,
+    specular: (f32, f32, f32),
+    emissive: (f32, f32, f32),
+    emissive_intensity: f32,
+    diffuse_texture: Option<TextureDescriptor>,
+    normal_texture: Option<TextureDescriptor>,
+    specular_texture: Option<TextureDescriptor>,
+    emissive_texture: Option<TextureDescriptor>,
+}
+
+impl Default for MaterialDescriptor {
+    fn default() -> Self {
+        Self {
+            name: None,
+            base_color: (1.0, 1.0, 1.0, 1.0),
+            alpha_mode: AlphaMode::Opaque,
+            double_sided: false,
+            gloss: 0.5,
+            specular: (1.0, 1.0, 1.0),
+            emissive: (0.0, 0.0, 0.0),
+            emissive_intensity: 1.0,
+            diffuse_texture: None,
+            normal_texture: None,
+            specular_texture: None,
+            emissive_texture: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TextureDescriptor {
+    /// Relative to the material file
+    path: String,
+    /// Clamps the uvs to the edges of the texture instead of repeating it
+    #[serde(default)]
+    clamp: bool,
+    /// The uv set of the mesh used by the texture
+    #[serde(default)]
+    uv_channel: u32,
+    #[serde(default)]
+    transform: Option<UvTransformDescriptor>,
+}
+
+/// Same as KHR_texture_transform, see `MaterialTexture::uv_transform`
+#[derive(Deserialize)]
+#[serde(default)]
+struct UvTransformDescriptor {
+    offset: (f32, f32),
+    rotation: f32,
+    scale: (f32, f32),
+}
+
+impl Default for UvTransformDescriptor {
+    fn default() -> Self {
+        Self {
+            offset: (0.0, 0.0),
+            rotation: 0.0,
+            scale: (1.0, 1.0),
+        }
+    }
+}
+
+pub struct MaterialLoader {
+    device_features: DeviceFeatures,
+}
+
+impl FromWorld for MaterialLoader {
+    fn from_world(world: &mut World) -> Self {
+        Self {
+            device_features: world
+                .get_resource::<DeviceFeatures>()
+                .cloned()
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl AssetLoader for MaterialLoader {
+    fn extensions(&self) -> &[&str] {
+        &["material.ron"]
+    }
+
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> bevy::asset::BoxedFuture<'a, anyhow::Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            log::info!("Loading {:?}", load_context.path());
+
+            let descriptor: MaterialDescriptor = ron::de::from_bytes(bytes)?;
+            let material = load_material(descriptor, load_context, &self.device_features).await?;
+            load_context.set_default_asset(LoadedAsset::new(material));
+
+            Ok(())
+        })
+    }
+}
+
+async fn load_material<'a>(
+    descriptor: MaterialDescriptor,
+    load_context: &LoadContext<'a>,
+    device_features: &DeviceFeatures,
+) -> anyhow::Result<Material> {
+    let name = descriptor.name.unwrap_or_else(|| {
+        load_context
+            .path()
+            .file_name()
+            .map(|name| {
+                name.to_string_lossy()
+                    .trim_end_matches(".material.ron")
+                    .to_string()
+            })
+            .unwrap_or_default()
+    });
+    let (r, g, b, a) = descriptor.base_color;
+
+    Ok(Material {
+        name,
+        base_color: Vec4::new(r, g, b, a),
+        alpha: a,
+        alpha_mode: descriptor.alpha_mode,
+        double_sided: descriptor.double_sided,
+        gloss: descriptor.gloss,
+        specular: Vec3::from(descriptor.specular),
+        emissive: Vec3::from(descriptor.emissive),
+        emissive_intensity: descriptor.emissive_intensity,
+        diffuse_texture: load_texture(
+            load_context,
+            descriptor.diffuse_texture,
+            ColorSpace::Srgb,
+            device_features,
+        )
+        .await?
+        .unwrap_or_else(|| MaterialTexture::from_color(Color::WHITE, ColorSpace::Srgb)),
+        normal_texture: load_texture(
+            load_context,
+            descriptor.normal_texture,
+            ColorSpace::Normal,
+            device_features,
+        )
+        .await?,
+        specular_texture: load_texture(
+            load_context,
+            descriptor.specular_texture,
+            ColorSpace::Srgb,
+            device_features,
+        )
+        .await?,
+        emissive_texture: load_texture(
+            load_context,
+            descriptor.emissive_texture,
+            ColorSpace::Srgb,
+            device_features,
+        )
+        .await?,
+    })
+}
+
+async fn load_texture<'a>(
+    load_context: &LoadContext<'a>,
+    descriptor: Option<TextureDescriptor>,
+    color_space: ColorSpace,
+    device_features: &DeviceFeatures,
+) -> anyhow::Result<Option<MaterialTexture>> {
+    let descriptor = match descriptor {
+        Some(descriptor) => descriptor,
+        None => return Ok(None),
+    };
+    let bytes = load_context
+        .read_asset_bytes(load_context.path().parent().unwrap().join(&descriptor.path))
+        .await?;
+    let image = TextureData::from_file_bytes(&descriptor.path, &bytes)?;
+    device_features.check(&image)?;
+    Ok(Some(MaterialTexture {
+        image,
+        sampler: if descriptor.clamp {
+            SamplerDescriptor::clamp_to_edge()
+        } else {
+            SamplerDescriptor::default()
+        },
+        color_space,
+        uv_channel: descriptor.uv_channel,
+        uv_transform: descriptor
+            .transform
+            .map(|transform| {
+                MaterialTexture::uv_transform(
+                    Vec2::from(transform.offset),
+                    transform.rotation,
+                    Vec2::from(transform.scale),
+                )
+            })
+            .unwrap_or(Mat3::IDENTITY),
+    }))
+}
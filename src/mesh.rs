@@ -1,13 +1,23 @@
-use bevy::math::{Vec2, Vec3};
+use std::collections::{BTreeMap, HashMap};
 
-#[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+use bevy::math::{Mat3, Mat4, Vec2, Vec3, Vec4, Vec4Swizzles};
+
+use crate::bounds::{Aabb, Sphere};
+
+/// A single vertex with every attribute used by the default shader.
+/// This is only used to build meshes, see `Mesh::from_vertices`
+#[derive(Copy, Clone, Debug)]
 pub struct Vertex {
     pub position: Vec3,
     pub normal: Vec3,
     pub uv: Vec2,
-    pub tangent: Vec3,
-    pub bitangent: Vec3,
+    /// The w component is the sign of the bitangent, `bitangent = cross(normal, tangent.xyz) * tangent.w`
+    pub tangent: Vec4,
+    /// Multiplied with the base color of the material. Meshes without vertex colors use white
+    pub color: Vec4,
+    /// The second uv set, a `MaterialTexture` chooses which set it uses.
+    /// Meshes with a single set duplicate `uv`
+    pub uv1: Vec2,
 }
 
 impl Vertex {
@@ -16,8 +26,9 @@ impl Vertex {
             position,
             normal,
             uv,
-            tangent: Vec3::ZERO,
-            bitangent: Vec3::ZERO,
+            tangent: Vec4::ZERO,
+            color: Vec4::ONE,
+            uv1: uv,
         }
     }
 
@@ -26,162 +37,669 @@ impl Vertex {
             position: Vec3::from(position),
             normal: Vec3::from(normal),
             uv: Vec2::from(uv),
-            tangent: Vec3::ZERO,
-            bitangent: Vec3::ZERO,
+            tangent: Vec4::ZERO,
+            color: Vec4::ONE,
+            uv1: Vec2::from(uv),
+        }
+    }
+}
+
+/// Describes an attribute of a mesh.
+/// Every attribute has a fixed location so the same shader can be used with any mesh.
+/// Shaders can check if an attribute is present with `#ifdef VERTEX_<name>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MeshVertexAttribute {
+    pub name: &'static str,
+    pub shader_location: u32,
+    pub format: wgpu::VertexFormat,
+}
+
+impl MeshVertexAttribute {
+    pub const fn new(name: &'static str, shader_location: u32, format: wgpu::VertexFormat) -> Self {
+        Self {
+            name,
+            shader_location,
+            format,
+        }
+    }
+}
+
+/// The values of an attribute for every vertex of a mesh
+#[derive(Debug, Clone)]
+pub enum VertexAttributeValues {
+    Float32x2(Vec<[f32; 2]>),
+    Float32x3(Vec<[f32; 3]>),
+    Float32x4(Vec<[f32; 4]>),
+    Uint16x4(Vec<[u16; 4]>),
+}
+
+impl VertexAttributeValues {
+    pub fn len(&self) -> usize {
+        match self {
+            VertexAttributeValues::Float32x2(values) => values.len(),
+            VertexAttributeValues::Float32x3(values) => values.len(),
+            VertexAttributeValues::Float32x4(values) => values.len(),
+            VertexAttributeValues::Uint16x4(values) => values.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn format(&self) -> wgpu::VertexFormat {
+        match self {
+            VertexAttributeValues::Float32x2(_) => wgpu::VertexFormat::Float32x2,
+            VertexAttributeValues::Float32x3(_) => wgpu::VertexFormat::Float32x3,
+            VertexAttributeValues::Float32x4(_) => wgpu::VertexFormat::Float32x4,
+            VertexAttributeValues::Uint16x4(_) => wgpu::VertexFormat::Uint16x4,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            VertexAttributeValues::Float32x2(values) => bytemuck::cast_slice(values),
+            VertexAttributeValues::Float32x3(values) => bytemuck::cast_slice(values),
+            VertexAttributeValues::Float32x4(values) => bytemuck::cast_slice(values),
+            VertexAttributeValues::Uint16x4(values) => bytemuck::cast_slice(values),
+        }
+    }
+
+    /// The values of the vertices at the given indices, in the same order as the indices
+    pub fn select(&self, indices: &[u32]) -> Self {
+        fn select<T: Copy>(values: &[T], indices: &[u32]) -> Vec<T> {
+            indices.iter().map(|i| values[*i as usize]).collect()
+        }
+
+        match self {
+            VertexAttributeValues::Float32x2(values) => select(values, indices).into(),
+            VertexAttributeValues::Float32x3(values) => select(values, indices).into(),
+            VertexAttributeValues::Float32x4(values) => select(values, indices).into(),
+            VertexAttributeValues::Uint16x4(values) => select(values, indices).into(),
+        }
+    }
+
+    /// Appends the values of another attribute with the same format
+    pub fn extend(&mut self, other: &VertexAttributeValues) {
+        match (self, other) {
+            (VertexAttributeValues::Float32x2(values), VertexAttributeValues::Float32x2(other)) => {
+                values.extend_from_slice(other);
+            }
+            (VertexAttributeValues::Float32x3(values), VertexAttributeValues::Float32x3(other)) => {
+                values.extend_from_slice(other);
+            }
+            (VertexAttributeValues::Float32x4(values), VertexAttributeValues::Float32x4(other)) => {
+                values.extend_from_slice(other);
+            }
+            (VertexAttributeValues::Uint16x4(values), VertexAttributeValues::Uint16x4(other)) => {
+                values.extend_from_slice(other);
+            }
+            (values, other) => panic!(
+                "Can't extend {:?} values with {:?} values",
+                values.format(),
+                other.format()
+            ),
         }
     }
 
-    pub fn layout<'a>() -> wgpu::VertexBufferLayout<'a> {
+    pub fn as_float2(&self) -> Option<&[[f32; 2]]> {
+        match self {
+            VertexAttributeValues::Float32x2(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_float3(&self) -> Option<&[[f32; 3]]> {
+        match self {
+            VertexAttributeValues::Float32x3(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_float4(&self) -> Option<&[[f32; 4]]> {
+        match self {
+            VertexAttributeValues::Float32x4(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+impl From<Vec<[f32; 2]>> for VertexAttributeValues {
+    fn from(values: Vec<[f32; 2]>) -> Self {
+        VertexAttributeValues::Float32x2(values)
+    }
+}
+
+impl From<Vec<[f32; 3]>> for VertexAttributeValues {
+    fn from(values: Vec<[f32; 3]>) -> Self {
+        VertexAttributeValues::Float32x3(values)
+    }
+}
+
+impl From<Vec<[f32; 4]>> for VertexAttributeValues {
+    fn from(values: Vec<[f32; 4]>) -> Self {
+        VertexAttributeValues::Float32x4(values)
+    }
+}
+
+impl From<Vec<[u16; 4]>> for VertexAttributeValues {
+    fn from(values: Vec<[u16; 4]>) -> Self {
+        VertexAttributeValues::Uint16x4(values)
+    }
+}
+
+/// The interleaved layout of the vertex buffer of a mesh.
+/// Pipelines are created for each layout since they need to know which attributes are present
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MeshVertexLayout {
+    /// Sorted by shader location
+    pub attributes: Vec<MeshVertexAttribute>,
+    pub array_stride: wgpu::BufferAddress,
+    vertex_attributes: Vec<wgpu::VertexAttribute>,
+}
+
+impl MeshVertexLayout {
+    pub fn new(attributes: Vec<MeshVertexAttribute>) -> Self {
+        let mut offset = 0;
+        let vertex_attributes = attributes
+            .iter()
+            .map(|attribute| {
+                let vertex_attribute = wgpu::VertexAttribute {
+                    format: attribute.format,
+                    offset,
+                    shader_location: attribute.shader_location,
+                };
+                offset += attribute.format.size();
+                vertex_attribute
+            })
+            .collect();
+        Self {
+            attributes,
+            array_stride: offset,
+            vertex_attributes,
+        }
+    }
+
+    pub fn buffer_layout(&self) -> wgpu::VertexBufferLayout<'_> {
         wgpu::VertexBufferLayout {
-            array_stride: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
+            array_stride: self.array_stride,
             step_mode: wgpu::VertexStepMode::Vertex,
-            attributes: &[
-                wgpu::VertexAttribute {
-                    offset: 0,
-                    shader_location: 0,
-                    format: wgpu::VertexFormat::Float32x3,
-                },
-                wgpu::VertexAttribute {
-                    offset: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
-                    shader_location: 1,
-                    format: wgpu::VertexFormat::Float32x3,
-                },
-                wgpu::VertexAttribute {
-                    offset: std::mem::size_of::<[f32; 6]>() as wgpu::BufferAddress,
-                    shader_location: 2,
-                    format: wgpu::VertexFormat::Float32x2,
-                },
-                wgpu::VertexAttribute {
-                    offset: std::mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
-                    shader_location: 3,
-                    format: wgpu::VertexFormat::Float32x3,
-                },
-                wgpu::VertexAttribute {
-                    offset: std::mem::size_of::<[f32; 11]>() as wgpu::BufferAddress,
-                    shader_location: 4,
-                    format: wgpu::VertexFormat::Float32x3,
-                },
-            ],
-        }
-    }
-}
-
-// TODO use Map for attributes
-#[derive(Debug)]
+            attributes: &self.vertex_attributes,
+        }
+    }
+
+    #[allow(unused)]
+    pub fn contains(&self, attribute: MeshVertexAttribute) -> bool {
+        self.attributes.contains(&attribute)
+    }
+
+    /// A `VERTEX_<name>` def for every attribute of the layout
+    pub fn shader_defs(&self) -> Vec<String> {
+        self.attributes
+            .iter()
+            .map(|attribute| format!("VERTEX_{}", attribute.name))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct Mesh {
-    pub vertices: Vec<Vertex>,
+    /// Sorted by shader location so the layout of meshes with the same attributes is identical
+    attributes: BTreeMap<u32, (MeshVertexAttribute, VertexAttributeValues)>,
     pub indices: Option<Vec<u32>>,
+    /// The indices of simplified versions of the mesh, from the most to the least detailed.
+    /// They use the same vertices as the full mesh, see `Mesh::generate_lods`
+    pub lods: Vec<Vec<u32>>,
     pub material_id: Option<usize>,
 }
 
 impl Mesh {
-    pub fn compute_normals(&mut self) {
-        fn face_normal(a: [f32; 3], b: [f32; 3], c: [f32; 3]) -> [f32; 3] {
-            let (a, b, c) = (Vec3::from(a), Vec3::from(b), Vec3::from(c));
-            (b - a).cross(c - a).normalize().into()
+    // Location 4 is free, locations 5 to 11 are used by the TransformRaw
+    pub const ATTRIBUTE_POSITION: MeshVertexAttribute =
+        MeshVertexAttribute::new("POSITION", 0, wgpu::VertexFormat::Float32x3);
+    pub const ATTRIBUTE_NORMAL: MeshVertexAttribute =
+        MeshVertexAttribute::new("NORMAL", 1, wgpu::VertexFormat::Float32x3);
+    pub const ATTRIBUTE_UV_0: MeshVertexAttribute =
+        MeshVertexAttribute::new("UV_0", 2, wgpu::VertexFormat::Float32x2);
+    pub const ATTRIBUTE_TANGENT: MeshVertexAttribute =
+        MeshVertexAttribute::new("TANGENT", 3, wgpu::VertexFormat::Float32x4);
+    pub const ATTRIBUTE_COLOR: MeshVertexAttribute =
+        MeshVertexAttribute::new("COLOR", 12, wgpu::VertexFormat::Float32x4);
+    pub const ATTRIBUTE_UV_1: MeshVertexAttribute =
+        MeshVertexAttribute::new("UV_1", 13, wgpu::VertexFormat::Float32x2);
+    pub const ATTRIBUTE_JOINT_INDEX: MeshVertexAttribute =
+        MeshVertexAttribute::new("JOINT_INDEX", 14, wgpu::VertexFormat::Uint16x4);
+    pub const ATTRIBUTE_JOINT_WEIGHT: MeshVertexAttribute =
+        MeshVertexAttribute::new("JOINT_WEIGHT", 15, wgpu::VertexFormat::Float32x4);
+
+    /// Builds a mesh from a list of vertices.
+    /// Attributes that only contain default values aren't added to the mesh,
+    /// so the tangents are only present if they were computed
+    pub fn from_vertices(vertices: &[Vertex], indices: Option<Vec<u32>>) -> Self {
+        let mut mesh = Mesh {
+            indices,
+            ..Default::default()
+        };
+        mesh.insert_attribute(
+            Self::ATTRIBUTE_POSITION,
+            vertices
+                .iter()
+                .map(|v| v.position.to_array())
+                .collect::<Vec<_>>(),
+        );
+        mesh.insert_attribute(
+            Self::ATTRIBUTE_NORMAL,
+            vertices
+                .iter()
+                .map(|v| v.normal.to_array())
+                .collect::<Vec<_>>(),
+        );
+        mesh.insert_attribute(
+            Self::ATTRIBUTE_UV_0,
+            vertices.iter().map(|v| v.uv.to_array()).collect::<Vec<_>>(),
+        );
+        if vertices.iter().any(|v| v.tangent != Vec4::ZERO) {
+            mesh.insert_attribute(
+                Self::ATTRIBUTE_TANGENT,
+                vertices
+                    .iter()
+                    .map(|v| v.tangent.to_array())
+                    .collect::<Vec<_>>(),
+            );
+        }
+        if vertices.iter().any(|v| v.color != Vec4::ONE) {
+            mesh.insert_attribute(
+                Self::ATTRIBUTE_COLOR,
+                vertices
+                    .iter()
+                    .map(|v| v.color.to_array())
+                    .collect::<Vec<_>>(),
+            );
+        }
+        if vertices.iter().any(|v| v.uv1 != v.uv) {
+            mesh.insert_attribute(
+                Self::ATTRIBUTE_UV_1,
+                vertices
+                    .iter()
+                    .map(|v| v.uv1.to_array())
+                    .collect::<Vec<_>>(),
+            );
         }
+        mesh
+    }
+
+    /// Replaces the values of the attribute if it was already present.
+    /// Panics if the format of the values doesn't match the format of the attribute
+    pub fn insert_attribute(
+        &mut self,
+        attribute: MeshVertexAttribute,
+        values: impl Into<VertexAttributeValues>,
+    ) {
+        let values = values.into();
+        assert_eq!(
+            attribute.format,
+            values.format(),
+            "Invalid format for the {} attribute",
+            attribute.name
+        );
+        self.attributes
+            .insert(attribute.shader_location, (attribute, values));
+    }
+
+    pub fn attribute(&self, attribute: MeshVertexAttribute) -> Option<&VertexAttributeValues> {
+        self.attributes
+            .get(&attribute.shader_location)
+            .filter(|(a, _)| *a == attribute)
+            .map(|(_, values)| values)
+    }
+
+    #[allow(unused)]
+    pub fn remove_attribute(
+        &mut self,
+        attribute: MeshVertexAttribute,
+    ) -> Option<VertexAttributeValues> {
+        self.attributes
+            .remove(&attribute.shader_location)
+            .map(|(_, values)| values)
+    }
+
+    pub fn contains_attribute(&self, attribute: MeshVertexAttribute) -> bool {
+        self.attribute(attribute).is_some()
+    }
+
+    /// Every attribute of the mesh sorted by shader location
+    pub fn attributes(
+        &self,
+    ) -> impl Iterator<Item = (&MeshVertexAttribute, &VertexAttributeValues)> {
+        self.attributes
+            .values()
+            .map(|(attribute, values)| (attribute, values))
+    }
+
+    /// Panics if the mesh doesn't have positions
+    pub fn positions(&self) -> &[[f32; 3]] {
+        self.attribute(Self::ATTRIBUTE_POSITION)
+            .and_then(VertexAttributeValues::as_float3)
+            .expect("Mesh doesn't have positions")
+    }
 
-        if let Some(indices) = self.indices.as_ref() {
-            for v in self.vertices.iter_mut() {
-                v.normal = Vec3::ZERO;
+    pub fn count_vertices(&self) -> usize {
+        self.attributes
+            .values()
+            .map(|(_, values)| values.len())
+            .next()
+            .unwrap_or(0)
+    }
+
+    pub fn vertex_buffer_layout(&self) -> MeshVertexLayout {
+        MeshVertexLayout::new(
+            self.attributes
+                .values()
+                .map(|(attribute, _)| *attribute)
+                .collect(),
+        )
+    }
+
+    /// Interleaves the attributes following the `vertex_buffer_layout()`
+    pub fn vertex_buffer_data(&self) -> Vec<u8> {
+        let layout = self.vertex_buffer_layout();
+        let stride = layout.array_stride as usize;
+        let count = self.count_vertices();
+
+        let mut data = vec![0; stride * count];
+        let mut offset = 0;
+        for (attribute, values) in self.attributes.values() {
+            assert_eq!(
+                values.len(),
+                count,
+                "The {} attribute doesn't have a value for every vertex",
+                attribute.name
+            );
+            let size = attribute.format.size() as usize;
+            for (i, bytes) in values.as_bytes().chunks_exact(size).enumerate() {
+                let start = i * stride + offset;
+                data[start..start + size].copy_from_slice(bytes);
             }
+            offset += size;
+        }
+        data
+    }
+
+    /// The axis aligned bounds of the vertices
+    pub fn compute_aabb(&self) -> Aabb {
+        Aabb::from_points(self.positions().iter().map(|p| Vec3::from(*p)))
+    }
 
-            for i in indices.chunks_exact(3) {
-                if let [i1, i2, i3] = i {
-                    let v_a = self.vertices[*i1 as usize];
-                    let v_b = self.vertices[*i2 as usize];
-                    let v_c = self.vertices[*i3 as usize];
+    /// A sphere around the center of the aabb that contains every vertex
+    pub fn compute_bounding_sphere(&self) -> Sphere {
+        Sphere::from_points(
+            self.compute_aabb().center,
+            self.positions().iter().map(|p| Vec3::from(*p)),
+        )
+    }
+
+    /// The vertex indices of every triangle.
+    /// Non-indexed meshes use every 3 consecutive vertices
+    pub fn triangles(&self) -> Vec<[usize; 3]> {
+        match self.indices.as_ref() {
+            Some(indices) => indices
+                .chunks_exact(3)
+                .map(|t| [t[0] as usize, t[1] as usize, t[2] as usize])
+                .collect(),
+            None => (0..self.count_vertices() / 3)
+                .map(|i| [i * 3, i * 3 + 1, i * 3 + 2])
+                .collect(),
+        }
+    }
+
+    /// Appends the vertices and triangles of a mesh with the same vertex layout.
+    /// Both meshes must be indexed or non-indexed. The lods are removed since they can't be merged
+    pub fn append(&mut self, other: &Mesh) {
+        assert_eq!(
+            self.vertex_buffer_layout(),
+            other.vertex_buffer_layout(),
+            "Only meshes with the same attributes can be appended"
+        );
+        let offset = self.count_vertices() as u32;
+        match (self.indices.as_mut(), other.indices.as_ref()) {
+            (Some(indices), Some(other_indices)) => {
+                indices.extend(other_indices.iter().map(|i| i + offset));
+            }
+            (None, None) => {}
+            _ => panic!("Can't append an indexed mesh and a non-indexed mesh"),
+        }
+        for ((_, values), (_, other_values)) in
+            self.attributes.values_mut().zip(other.attributes.values())
+        {
+            values.extend(other_values);
+        }
+        self.lods.clear();
+    }
 
-                    let edge_ab = v_b.position - v_a.position;
-                    let edge_ac = v_c.position - v_a.position;
+    /// Moves the vertices to the space of the matrix.
+    /// The winding of the triangles is reversed if the matrix mirrors the mesh so the front faces stay in front
+    pub fn transform(&mut self, matrix: &Mat4) {
+        let positions: Vec<_> = self
+            .positions()
+            .iter()
+            .map(|p| matrix.transform_point3(Vec3::from(*p)).to_array())
+            .collect();
+        self.insert_attribute(Self::ATTRIBUTE_POSITION, positions);
 
-                    let normal = edge_ab.cross(edge_ac);
+        // The inverse transpose keeps the normals perpendicular to the surface with a non uniform scale
+        let normal_matrix = Mat3::from_mat4(*matrix).inverse().transpose();
+        if let Some(normals) = self
+            .attribute(Self::ATTRIBUTE_NORMAL)
+            .and_then(VertexAttributeValues::as_float3)
+        {
+            let normals: Vec<_> = normals
+                .iter()
+                .map(|n| {
+                    (normal_matrix * Vec3::from(*n))
+                        .normalize_or_zero()
+                        .to_array()
+                })
+                .collect();
+            self.insert_attribute(Self::ATTRIBUTE_NORMAL, normals);
+        }
+        // The bitangent is rebuilt from the normal and tangent so its sign flips with the handedness
+        let handedness = matrix.determinant().signum();
+        if let Some(tangents) = self
+            .attribute(Self::ATTRIBUTE_TANGENT)
+            .and_then(VertexAttributeValues::as_float4)
+        {
+            let tangents: Vec<_> = tangents
+                .iter()
+                .map(|t| {
+                    let t = Vec4::from(*t);
+                    matrix
+                        .transform_vector3(t.xyz())
+                        .normalize_or_zero()
+                        .extend(t.w * handedness)
+                        .to_array()
+                })
+                .collect();
+            self.insert_attribute(Self::ATTRIBUTE_TANGENT, tangents);
+        }
 
-                    self.vertices[*i1 as usize].normal += normal;
-                    self.vertices[*i2 as usize].normal += normal;
-                    self.vertices[*i3 as usize].normal += normal;
+        if handedness < 0.0 {
+            let mut indices = self
+                .indices
+                .take()
+                .unwrap_or_else(|| (0..self.count_vertices() as u32).collect());
+            for triangles in std::iter::once(&mut indices).chain(self.lods.iter_mut()) {
+                for triangle in triangles.chunks_exact_mut(3) {
+                    triangle.swap(1, 2);
                 }
             }
+            self.indices = Some(indices);
+        }
+    }
 
-            for v in self.vertices.iter_mut() {
-                v.normal = v.normal.normalize();
+    /// Duplicates the vertices shared by multiple triangles and removes the indices and the lods.
+    /// Does nothing if the mesh isn't indexed
+    pub fn duplicate_vertices(&mut self) {
+        if let Some(indices) = self.indices.take() {
+            self.lods.clear();
+            for (_, values) in self.attributes.values_mut() {
+                *values = values.select(&indices);
             }
-        } else {
-            let mut normals = vec![];
-            for v in self.vertices.chunks_exact_mut(3) {
-                if let [v1, v2, v3] = v {
-                    let normal = face_normal(
-                        v1.position.to_array(),
-                        v2.position.to_array(),
-                        v3.position.to_array(),
-                    );
-                    normals.push(normal);
-                }
+        }
+    }
+
+    /// Smooth normals, every vertex uses the average normal of the triangles that share it.
+    /// Vertices of non-indexed meshes aren't shared so they use the normal of their triangle
+    pub fn compute_normals(&mut self) {
+        let positions = self.positions();
+        let mut normals = vec![Vec3::ZERO; positions.len()];
+        for triangle in self.triangles() {
+            // Not normalized so bigger triangles have more weight
+            let [a, b, c] = triangle.map(|i| Vec3::from(positions[i]));
+            let normal = (b - a).cross(c - a);
+            for i in triangle {
+                normals[i] += normal;
             }
         }
+
+        let normals: Vec<_> = normals
+            .into_iter()
+            .map(|n| n.normalize_or_zero().to_array())
+            .collect();
+        self.insert_attribute(Self::ATTRIBUTE_NORMAL, normals);
+    }
+
+    /// Faceted normals, every vertex uses the normal of its triangle.
+    /// Panics if the mesh is indexed, call `duplicate_vertices` first
+    pub fn compute_flat_normals(&mut self) {
+        assert!(
+            self.indices.is_none(),
+            "flat normals can't be computed for an indexed mesh, call duplicate_vertices first"
+        );
+
+        let normals: Vec<_> = self
+            .positions()
+            .chunks_exact(3)
+            .flat_map(|triangle| {
+                let [a, b, c] = [triangle[0], triangle[1], triangle[2]].map(Vec3::from);
+                let normal = (b - a).cross(c - a).normalize_or_zero().to_array();
+                [normal; 3]
+            })
+            .collect();
+        self.insert_attribute(Self::ATTRIBUTE_NORMAL, normals);
     }
 
+    /// Computes MikkTSpace tangents from the positions, normals and the first uv set.
+    /// This is the same algorithm used by most tools to bake normal maps.
+    /// Vertices shared by triangles with different tangents are split, the lods keep using
+    /// the original vertices.
+    /// The tangents aren't added if they can't be generated, e.g. if the normals or uvs are missing
+    /// or if every triangle is degenerate
     pub fn compute_tangents(&mut self) {
-        if let Some(indices) = self.indices.as_ref() {
-            let mut triangles_included = (0..self.vertices.len()).collect::<Vec<_>>();
-            for c in indices.chunks(3) {
-                let v0 = self.vertices[c[0] as usize];
-                let v1 = self.vertices[c[1] as usize];
-                let v2 = self.vertices[c[2] as usize];
-
-                let pos0 = v0.position;
-                let pos1 = v1.position;
-                let pos2 = v2.position;
-
-                let uv0 = v0.uv;
-                let uv1 = v1.uv;
-                let uv2 = v2.uv;
-
-                // Calculate the edges of the triangle
-                let delta_pos1 = pos1 - pos0;
-                let delta_pos2 = pos2 - pos0;
-
-                // This will give us a direction to calculate the
-                // tangent and bitangent
-                let delta_uv1 = uv1 - uv0;
-                let delta_uv2 = uv2 - uv0;
-
-                // Solving the following system of equations will
-                // give us the tangent and bitangent.
-                //     delta_pos1 = delta_uv1.x * T + delta_u.y * B
-                //     delta_pos2 = delta_uv2.x * T + delta_uv2.y * B
-                // Luckily, the place I found this equation provided
-                // the solution!
-                let r = 1.0 / (delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x);
-                let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
-                // We flip the bitangent to enable right-handed normal
-                // maps with wgpu texture coordinate system
-                let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * -r;
-
-                // We'll use the same tangent/bitangent for each vertex in the triangle
-                self.vertices[c[0] as usize].tangent += tangent;
-                self.vertices[c[1] as usize].tangent += tangent;
-                self.vertices[c[2] as usize].tangent += tangent;
-
-                self.vertices[c[0] as usize].bitangent += bitangent;
-                self.vertices[c[1] as usize].bitangent += bitangent;
-                self.vertices[c[2] as usize].bitangent += bitangent;
-
-                // Used to average the tangents/bitangents
-                triangles_included[c[0] as usize] += 1;
-                triangles_included[c[1] as usize] += 1;
-                triangles_included[c[2] as usize] += 1;
+        let positions = self.positions();
+        let normals = self
+            .attribute(Self::ATTRIBUTE_NORMAL)
+            .and_then(VertexAttributeValues::as_float3);
+        let uvs = self
+            .attribute(Self::ATTRIBUTE_UV_0)
+            .and_then(VertexAttributeValues::as_float2);
+        let (normals, uvs) = match (normals, uvs) {
+            (Some(normals), Some(uvs)) => (normals, uvs),
+            _ => {
+                log::warn!("Tangents can't be computed without normals and uvs");
+                return;
             }
+        };
 
-            // Average the tangents/bitangents
-            for (i, n) in triangles_included.into_iter().enumerate() {
-                let denom = 1.0 / n as f32;
-                let mut v = &mut self.vertices[i];
-                v.tangent = (v.tangent * denom).normalize();
-                v.bitangent = (v.bitangent * denom).normalize();
+        let triangles = self.triangles();
+        let mut geometry = MikkTSpaceGeometry {
+            tangents: vec![[0.0; 4]; triangles.len() * 3],
+            triangles,
+            positions,
+            normals,
+            uvs,
+        };
+        if !bevy_mikktspace::generate_tangents(&mut geometry) {
+            log::warn!("Failed to generate the tangents of a mesh");
+            return;
+        }
+        let MikkTSpaceGeometry {
+            triangles,
+            tangents: corner_tangents,
+            ..
+        } = geometry;
+
+        let mut tangents: Vec<Option<[f32; 4]>> = vec![None; self.count_vertices()];
+        // The vertex copied by each new vertex
+        let mut split_vertices = vec![];
+        let mut splits = HashMap::new();
+        let mut indices = self.indices.take();
+        for (corner, tangent) in corner_tangents.into_iter().enumerate() {
+            let vertex = triangles[corner / 3][corner % 3];
+            match tangents[vertex] {
+                None => tangents[vertex] = Some(tangent),
+                Some(existing) if existing == tangent => {}
+                // Only indexed meshes share vertices between triangles
+                Some(_) => {
+                    let new_vertex = *splits
+                        .entry((vertex, tangent.map(f32::to_bits)))
+                        .or_insert_with(|| {
+                            split_vertices.push(vertex as u32);
+                            tangents.push(Some(tangent));
+                            tangents.len() as u32 - 1
+                        });
+                    if let Some(indices) = indices.as_mut() {
+                        indices[corner] = new_vertex;
+                    }
+                }
             }
-        } else {
-            todo!("tangents only computed for indexed meshes");
         }
+        self.indices = indices;
+
+        if !split_vertices.is_empty() {
+            for (_, values) in self.attributes.values_mut() {
+                let copies = values.select(&split_vertices);
+                values.extend(&copies);
+            }
+        }
+        let tangents: Vec<_> = tangents
+            .into_iter()
+            .map(|tangent| tangent.unwrap_or_default())
+            .collect();
+        self.insert_attribute(Self::ATTRIBUTE_TANGENT, tangents);
+    }
+}
+
+struct MikkTSpaceGeometry<'a> {
+    triangles: Vec<[usize; 3]>,
+    positions: &'a [[f32; 3]],
+    normals: &'a [[f32; 3]],
+    uvs: &'a [[f32; 2]],
+    /// The tangent of every corner of every triangle
+    tangents: Vec<[f32; 4]>,
+}
+
+impl<'a> bevy_mikktspace::Geometry for MikkTSpaceGeometry<'a> {
+    fn num_faces(&self) -> usize {
+        self.triangles.len()
+    }
+
+    fn num_vertices_of_face(&self, _face: usize) -> usize {
+        3
+    }
+
+    fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.positions[self.triangles[face][vert]]
+    }
+
+    fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.normals[self.triangles[face][vert]]
+    }
+
+    fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+        self.uvs[self.triangles[face][vert]]
+    }
+
+    fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+        self.tangents[face * 3 + vert] = tangent;
     }
 }
@@ -0,0 +1,489 @@
+use std::{collections::VecDeque, ops::Range};
+
+use bevy::{
+    math::Vec3,
+    utils::{HashMap, HashSet},
+};
+
+use crate::mesh::{Mesh, VertexAttributeValues};
+
+// References:
+// <https://tomforsyth1000.github.io/papers/fast_vert_cache_opt.html>
+// <https://gfx.cs.princeton.edu/pubs/Sander_2007_%3ETR/tipsy.pdf>
+// <https://github.com/zeux/meshoptimizer>
+
+/// Controls the optimization of the meshes at import time.
+/// Insert it before adding the loader plugins to change the settings
+#[derive(Debug, Clone)]
+pub struct MeshOptimizationSettings {
+    pub enabled: bool,
+    /// Vertices are merged if every attribute is within this distance
+    pub weld_epsilon: f32,
+    /// How much the vertex cache efficiency can be degraded to reduce overdraw.
+    /// Higher values split the mesh in more clusters that can be reordered
+    pub overdraw_threshold: f32,
+    /// The maximum number of simplified meshes generated for each mesh, 0 disables the lods
+    pub lod_levels: usize,
+    /// The maximum error of the lods relative to the size of the mesh, see `Mesh::simplify`
+    pub lod_target_error: f32,
+    /// Merges the meshes of a model that use the same material before optimizing them,
+    /// see `static_batching::merge_meshes`.
+    /// This reduces the number of draw calls but the merged meshes are culled and picked as a whole
+    pub static_batching: bool,
+}
+
+impl MeshOptimizationSettings {
+    /// Optimizes the mesh and generates its lods
+    pub fn apply(&self, mesh: &mut Mesh) {
+        if self.enabled {
+            mesh.optimize(self);
+        }
+        if self.lod_levels > 0 {
+            mesh.generate_lods(self.lod_levels, self.lod_target_error);
+        }
+    }
+}
+
+impl Default for MeshOptimizationSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            weld_epsilon: 0.00001,
+            overdraw_threshold: 1.05,
+            lod_levels: 3,
+            lod_target_error: 0.05,
+            static_batching: false,
+        }
+    }
+}
+
+const CACHE_SIZE: usize = 32;
+const CACHE_DECAY_POWER: f32 = 1.5;
+const LAST_TRIANGLE_SCORE: f32 = 0.75;
+const VALENCE_BOOST_SCALE: f32 = 2.0;
+const VALENCE_BOOST_POWER: f32 = 0.5;
+
+/// The size of the FIFO cache used to estimate the efficiency of the index order.
+/// This is a reasonable approximation for most gpus
+const SIMULATED_CACHE_SIZE: usize = 16;
+
+impl Mesh {
+    /// Runs every optimization pass.
+    /// The result is always an indexed mesh that looks the same but is faster to render
+    pub fn optimize(&mut self, settings: &MeshOptimizationSettings) {
+        self.weld_vertices(settings.weld_epsilon);
+        self.optimize_vertex_cache();
+        self.optimize_overdraw(settings.overdraw_threshold);
+        self.optimize_vertex_fetch();
+    }
+
+    /// Merges the vertices that have the same attributes, within epsilon.
+    /// Non-indexed meshes become indexed
+    pub fn weld_vertices(&mut self, epsilon: f32) {
+        let count = self.count_vertices();
+        let inv_epsilon = 1.0 / epsilon.max(f32::EPSILON);
+
+        let mut unique_vertices = vec![];
+        let mut remap = Vec::with_capacity(count);
+        let mut vertex_map = HashMap::default();
+        let mut key = vec![];
+        for vertex in 0..count {
+            key.clear();
+            for (_, values) in self.attributes() {
+                quantize_vertex(values, vertex, inv_epsilon, &mut key);
+            }
+            let index = *vertex_map.entry(key.clone()).or_insert_with(|| {
+                unique_vertices.push(vertex as u32);
+                unique_vertices.len() as u32 - 1
+            });
+            remap.push(index);
+        }
+
+        for lod in &mut self.lods {
+            for index in lod.iter_mut() {
+                *index = remap[*index as usize];
+            }
+        }
+        let indices = match self.indices.take() {
+            Some(indices) => indices.iter().map(|i| remap[*i as usize]).collect(),
+            None => remap,
+        };
+        self.remap_vertices(&unique_vertices);
+        self.indices = Some(indices);
+    }
+
+    /// Reorders the triangles so vertices are reused while they are still in the post-transform cache.
+    /// Uses Tom Forsyth's linear-speed vertex cache optimisation
+    pub fn optimize_vertex_cache(&mut self) {
+        let triangles = self.triangles();
+        if triangles.is_empty() {
+            return;
+        }
+        self.indices = Some(vertex_cache_order(&triangles, self.count_vertices()));
+    }
+
+    /// Reorders clusters of triangles so the triangles facing outward are drawn first.
+    /// Triangles are only reordered at the boundaries where it doesn't increase
+    /// the vertex cache misses by more than the threshold, run it after `optimize_vertex_cache`
+    pub fn optimize_overdraw(&mut self, threshold: f32) {
+        let triangles = self.triangles();
+        if triangles.is_empty() {
+            return;
+        }
+        let positions: Vec<_> = self.positions().iter().map(|p| Vec3::from(*p)).collect();
+
+        let clusters = cluster_triangles(&triangles, threshold);
+
+        let (mesh_area, mesh_centroid) =
+            triangles
+                .iter()
+                .fold((0.0, Vec3::ZERO), |(total_area, centroid), triangle| {
+                    let (area, center, _) = triangle_properties(&positions, triangle);
+                    (total_area + area, centroid + center * area)
+                });
+        let mesh_centroid = mesh_centroid / mesh_area.max(f32::EPSILON);
+
+        // Clusters far away from the center and facing outward are likely to occlude the other clusters
+        let mut sorted_clusters: Vec<_> = clusters
+            .into_iter()
+            .map(|cluster| {
+                let (area, centroid, normal) = cluster.clone().fold(
+                    (0.0, Vec3::ZERO, Vec3::ZERO),
+                    |(total_area, centroid, normal), t| {
+                        let (area, center, triangle_normal) =
+                            triangle_properties(&positions, &triangles[t]);
+                        (
+                            total_area + area,
+                            centroid + center * area,
+                            normal + triangle_normal * area,
+                        )
+                    },
+                );
+                let centroid = centroid / area.max(f32::EPSILON);
+                let sort_key = (centroid - mesh_centroid).dot(normal.normalize_or_zero());
+                (sort_key, cluster)
+            })
+            .collect();
+        sorted_clusters
+            .sort_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+
+        self.indices = Some(
+            sorted_clusters
+                .into_iter()
+                .flat_map(|(_, cluster)| cluster)
+                .flat_map(|t| triangles[t])
+                .map(|v| v as u32)
+                .collect(),
+        );
+    }
+
+    /// Reorders the vertices in the order they are first used by the indices
+    /// so the vertex buffer is read sequentially. Unused vertices are removed
+    pub fn optimize_vertex_fetch(&mut self) {
+        let mut indices = match self.indices.take() {
+            Some(indices) => indices,
+            None => return,
+        };
+
+        let mut remap = vec![u32::MAX; self.count_vertices()];
+        let mut vertex_order = vec![];
+        for index in indices.iter_mut() {
+            let new_index = &mut remap[*index as usize];
+            if *new_index == u32::MAX {
+                *new_index = vertex_order.len() as u32;
+                vertex_order.push(*index);
+            }
+            *index = *new_index;
+        }
+        // The lods only use vertices of the full mesh
+        for lod in &mut self.lods {
+            for index in lod.iter_mut() {
+                *index = remap[*index as usize];
+            }
+        }
+        self.remap_vertices(&vertex_order);
+        self.indices = Some(indices);
+    }
+
+    /// Replaces the vertices by the vertices at the given indices
+    fn remap_vertices(&mut self, vertices: &[u32]) {
+        let attributes: Vec<_> = self
+            .attributes()
+            .map(|(attribute, values)| (*attribute, values.select(vertices)))
+            .collect();
+        for (attribute, values) in attributes {
+            self.insert_attribute(attribute, values);
+        }
+    }
+}
+
+/// The indices of the triangles sorted with Tom Forsyth's algorithm, see `Mesh::optimize_vertex_cache`
+pub fn vertex_cache_order(triangles: &[[usize; 3]], vertex_count: usize) -> Vec<u32> {
+    if triangles.is_empty() {
+        return vec![];
+    }
+
+    let mut vertex_triangles = vec![vec![]; vertex_count];
+    for (t, triangle) in triangles.iter().enumerate() {
+        for v in triangle {
+            vertex_triangles[*v].push(t);
+        }
+    }
+
+    let mut remaining_valence: Vec<_> = vertex_triangles.iter().map(Vec::len).collect();
+    let mut cache_position: Vec<Option<usize>> = vec![None; vertex_count];
+    let mut vertex_scores: Vec<_> = remaining_valence
+        .iter()
+        .map(|valence| vertex_score(None, *valence))
+        .collect();
+    let mut triangle_scores: Vec<_> = triangles
+        .iter()
+        .map(|t| t.iter().map(|v| vertex_scores[*v]).sum::<f32>())
+        .collect();
+    let mut emitted = vec![false; triangles.len()];
+
+    let mut cache: Vec<usize> = Vec::with_capacity(CACHE_SIZE + 3);
+    let mut indices = Vec::with_capacity(triangles.len() * 3);
+    // Used to find the next triangle when none of the cached vertices have triangles left
+    let mut next_unemitted = 0;
+    let mut best_triangle = Some(best_score(&triangle_scores, 0..triangles.len(), &emitted));
+
+    while let Some(triangle) = best_triangle {
+        emitted[triangle] = true;
+        for v in triangles[triangle] {
+            indices.push(v as u32);
+            remaining_valence[v] -= 1;
+            vertex_triangles[v].retain(|t| *t != triangle);
+        }
+
+        // Move the vertices of the triangle to the front of the LRU cache
+        let previous_cache = cache.clone();
+        cache.retain(|v| !triangles[triangle].contains(v));
+        for v in triangles[triangle].iter().rev() {
+            cache.insert(0, *v);
+        }
+
+        // Vertices pushed out of the cache and the ones in the cache need new scores
+        for v in previous_cache.iter().chain(cache.iter()) {
+            cache_position[*v] = cache
+                .iter()
+                .position(|c| c == v)
+                .filter(|p| *p < CACHE_SIZE);
+        }
+        cache.truncate(CACHE_SIZE);
+
+        let mut candidates = HashSet::default();
+        for v in previous_cache.iter().chain(cache.iter()) {
+            let score = vertex_score(cache_position[*v], remaining_valence[*v]);
+            let delta = score - vertex_scores[*v];
+            vertex_scores[*v] = score;
+            for t in &vertex_triangles[*v] {
+                triangle_scores[*t] += delta;
+                candidates.insert(*t);
+            }
+        }
+
+        best_triangle = candidates.into_iter().max_by(|a, b| {
+            triangle_scores[*a]
+                .partial_cmp(&triangle_scores[*b])
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        if best_triangle.is_none() {
+            while next_unemitted < triangles.len() && emitted[next_unemitted] {
+                next_unemitted += 1;
+            }
+            if next_unemitted < triangles.len() {
+                best_triangle = Some(best_score(
+                    &triangle_scores,
+                    next_unemitted..triangles.len(),
+                    &emitted,
+                ));
+            }
+        }
+    }
+
+    indices
+}
+
+fn quantize_vertex(
+    values: &VertexAttributeValues,
+    vertex: usize,
+    inv_epsilon: f32,
+    key: &mut Vec<i64>,
+) {
+    let mut quantize = |components: &[f32]| {
+        key.extend(components.iter().map(|c| (c * inv_epsilon).round() as i64));
+    };
+    match values {
+        VertexAttributeValues::Float32x2(values) => quantize(&values[vertex]),
+        VertexAttributeValues::Float32x3(values) => quantize(&values[vertex]),
+        VertexAttributeValues::Float32x4(values) => quantize(&values[vertex]),
+        VertexAttributeValues::Uint16x4(values) => {
+            key.extend(values[vertex].iter().map(|c| *c as i64));
+        }
+    }
+}
+
+fn vertex_score(cache_position: Option<usize>, remaining_valence: usize) -> f32 {
+    if remaining_valence == 0 {
+        // No triangle needs this vertex
+        return -1.0;
+    }
+
+    let cache_score = match cache_position {
+        None => 0.0,
+        // The vertices of the last triangle get a fixed score to avoid
+        // picking a triangle that shares an edge with it, which is usually worse for the cache
+        Some(position) if position < 3 => LAST_TRIANGLE_SCORE,
+        Some(position) => {
+            let scale = 1.0 / (CACHE_SIZE - 3) as f32;
+            (1.0 - (position - 3) as f32 * scale).powf(CACHE_DECAY_POWER)
+        }
+    };
+    // Vertices with few triangles left are prioritized so they can leave the cache
+    let valence_boost = VALENCE_BOOST_SCALE * (remaining_valence as f32).powf(-VALENCE_BOOST_POWER);
+    cache_score + valence_boost
+}
+
+fn best_score(scores: &[f32], range: Range<usize>, emitted: &[bool]) -> usize {
+    range
+        .filter(|t| !emitted[*t])
+        .max_by(|a, b| {
+            scores[*a]
+                .partial_cmp(&scores[*b])
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })
+        .expect("there should be at least one triangle left")
+}
+
+/// Splits the triangles in clusters that can be reordered without hurting the vertex cache too much
+fn cluster_triangles(triangles: &[[usize; 3]], threshold: f32) -> Vec<Range<usize>> {
+    // Hard boundaries are where every vertex of the triangle misses the cache,
+    // moving these clusters doesn't change the number of misses
+    let mut cache = VecDeque::with_capacity(SIMULATED_CACHE_SIZE);
+    let mut hard_boundaries: Vec<_> = triangles
+        .iter()
+        .enumerate()
+        .filter(|(t, triangle)| cache_misses(&mut cache, triangle) == 3 || *t == 0)
+        .map(|(t, _)| t)
+        .collect();
+    hard_boundaries.push(triangles.len());
+
+    // Soft boundaries split the hard clusters where the ACMR (average cache miss ratio)
+    // of the cluster so far is close enough to the ACMR of the whole hard cluster
+    let mut clusters = vec![];
+    for hard_cluster in hard_boundaries.windows(2) {
+        let (start, end) = (hard_cluster[0], hard_cluster[1]);
+
+        cache.clear();
+        let hard_cluster_misses: usize = triangles[start..end]
+            .iter()
+            .map(|triangle| cache_misses(&mut cache, triangle))
+            .sum();
+        let hard_cluster_acmr = hard_cluster_misses as f32 / (end - start) as f32;
+
+        cache.clear();
+        let mut cluster_start = start;
+        let mut cluster_misses = 0;
+        for (t, triangle) in triangles.iter().enumerate().take(end).skip(start) {
+            cluster_misses += cache_misses(&mut cache, triangle);
+            let acmr = cluster_misses as f32 / (t + 1 - cluster_start) as f32;
+            if t + 1 < end && acmr <= hard_cluster_acmr * threshold {
+                clusters.push(cluster_start..t + 1);
+                cluster_start = t + 1;
+                cluster_misses = 0;
+                cache.clear();
+            }
+        }
+        clusters.push(cluster_start..end);
+    }
+    clusters
+}
+
+/// Adds the vertices of the triangle to a FIFO cache and returns how many weren't already in it
+fn cache_misses(cache: &mut VecDeque<usize>, triangle: &[usize; 3]) -> usize {
+    let mut misses = 0;
+    for v in triangle {
+        if !cache.contains(v) {
+            misses += 1;
+            if cache.len() == SIMULATED_CACHE_SIZE {
+                cache.pop_back();
+            }
+            cache.push_front(*v);
+        }
+    }
+    misses
+}
+
+/// The area, center and normal of a triangle
+fn triangle_properties(positions: &[Vec3], triangle: &[usize; 3]) -> (f32, Vec3, Vec3) {
+    let (a, b, c) = (
+        positions[triangle[0]],
+        positions[triangle[1]],
+        positions[triangle[2]],
+    );
+    let cross = (b - a).cross(c - a);
+    let area = cross.length() * 0.5;
+    (area, (a + b + c) / 3.0, cross.normalize_or_zero())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shapes::{plane::Plane, sphere::UVSphere};
+
+    /// The triangles described by their positions, sorted so meshes can be compared
+    /// regardless of the order of the vertices and triangles.
+    /// The corners are rotated to start with the smallest one so the winding is kept
+    fn triangle_set(mesh: &Mesh) -> Vec<[[u32; 3]; 3]> {
+        let positions = mesh.positions();
+        let mut triangles: Vec<_> = mesh
+            .triangles()
+            .into_iter()
+            .map(|triangle| {
+                let corners = triangle.map(|i| positions[i].map(f32::to_bits));
+                let first = (0..3).min_by_key(|i| corners[*i]).unwrap();
+                [0, 1, 2].map(|i| corners[(first + i) % 3])
+            })
+            .collect();
+        triangles.sort_unstable();
+        triangles
+    }
+
+    #[test]
+    fn optimize_keeps_the_same_triangles() {
+        let mut mesh = UVSphere::default().to_mesh();
+        let triangles = triangle_set(&mesh);
+
+        mesh.optimize(&MeshOptimizationSettings::default());
+        assert_eq!(triangle_set(&mesh), triangles);
+    }
+
+    #[test]
+    fn weld_vertices_merges_duplicated_vertices() {
+        let resolution = 4;
+        let mut mesh = Plane {
+            resolution,
+            size: 1.0,
+        }
+        .to_mesh();
+        mesh.duplicate_vertices();
+        let triangles = triangle_set(&mesh);
+        assert_eq!(mesh.count_vertices(), triangles.len() * 3);
+
+        mesh.weld_vertices(MeshOptimizationSettings::default().weld_epsilon);
+        let vertex_count = (resolution + 1) * (resolution + 1);
+        assert_eq!(mesh.count_vertices(), vertex_count);
+        for (_, values) in mesh.attributes() {
+            assert_eq!(values.len(), vertex_count);
+        }
+        assert_eq!(triangle_set(&mesh), triangles);
+
+        // Every vertex is used so the remap keeps all of them
+        mesh.optimize_vertex_fetch();
+        assert_eq!(mesh.count_vertices(), vertex_count);
+        let indices = mesh.indices.as_ref().unwrap();
+        assert!(indices.iter().all(|i| (*i as usize) < vertex_count));
+        assert_eq!(triangle_set(&mesh), triangles);
+    }
+}
@@ -0,0 +1,364 @@
+use std::ops::Add;
+
+use bevy::{
+    math::Vec3,
+    utils::{HashMap, HashSet},
+};
+
+use crate::{mesh::Mesh, mesh_optimizer::vertex_cache_order};
+
+// References:
+// <https://www.cs.cmu.edu/~./garland/Papers/quadrics.pdf>
+// <https://github.com/zeux/meshoptimizer/blob/master/src/simplifier.cpp>
+
+/// Each lod targets half the triangles of the previous one
+const LOD_RATIO: f32 = 0.5;
+
+/// A lod is discarded if it doesn't remove at least this fraction of the triangles of the previous lod
+const MIN_LOD_REDUCTION: f32 = 0.1;
+
+impl Mesh {
+    /// Generates up to `levels` simplified versions of the mesh in `Mesh::lods`.
+    /// Stops early when the mesh can't be simplified further within the target error
+    pub fn generate_lods(&mut self, levels: usize, target_error: f32) {
+        self.lods.clear();
+        let mut previous_len = match self.indices.as_ref() {
+            Some(indices) => indices.len(),
+            None => return,
+        };
+
+        let mut ratio = 1.0;
+        for _ in 0..levels {
+            ratio *= LOD_RATIO;
+            let lod = self.simplify(ratio, target_error);
+            if lod.len() as f32 > previous_len as f32 * (1.0 - MIN_LOD_REDUCTION) {
+                break;
+            }
+            previous_len = lod.len();
+
+            let triangles: Vec<_> = lod
+                .chunks_exact(3)
+                .map(|t| [t[0] as usize, t[1] as usize, t[2] as usize])
+                .collect();
+            self.lods
+                .push(vertex_cache_order(&triangles, self.count_vertices()));
+        }
+    }
+
+    /// Collapses edges, starting with the ones that change the surface the least,
+    /// until only `target_ratio` of the triangles are left or the error would exceed `target_error`.
+    /// The error is relative to the size of the mesh, 0.01 is 1% of its biggest dimension.
+    ///
+    /// Returns the new indices. The vertices aren't modified so the result can share the vertex buffer of the mesh.
+    /// Vertices on the borders and uv or normal seams are never removed to avoid cracks
+    pub fn simplify(&self, target_ratio: f32, target_error: f32) -> Vec<u32> {
+        assert!(
+            self.indices.is_some(),
+            "only indexed meshes can be simplified, call weld_vertices first"
+        );
+
+        let positions: Vec<_> = self.positions().iter().map(|p| Vec3::from(*p)).collect();
+        let mut triangles = self.triangles();
+        let target_triangles = (triangles.len() as f32 * target_ratio) as usize;
+
+        let (min, max) = positions.iter().fold(
+            (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+            |(min, max), p| (min.min(*p), max.max(*p)),
+        );
+        // The quadric error is a squared distance
+        let max_error = ((max - min).max_element() * target_error).powi(2) as f64;
+
+        let locked = locked_vertices(&positions, &triangles);
+
+        let mut quadrics = vec![Quadric::default(); positions.len()];
+        for triangle in &triangles {
+            let quadric = Quadric::from_triangle(&positions, triangle);
+            for v in triangle {
+                quadrics[*v] = quadrics[*v] + quadric;
+            }
+        }
+
+        while triangles.len() > target_triangles {
+            let mut vertex_triangles = vec![vec![]; positions.len()];
+            for (t, triangle) in triangles.iter().enumerate() {
+                for v in triangle {
+                    vertex_triangles[*v].push(t);
+                }
+            }
+
+            let collapses = sorted_collapses(&triangles, &positions, &quadrics, &locked);
+
+            // Each vertex is only collapsed once per pass so the costs and flip checks stay valid
+            let mut touched = vec![false; positions.len()];
+            let mut remap: Vec<_> = (0..positions.len()).collect();
+            let mut removed_triangles = 0;
+            for Collapse { from, to, error } in collapses {
+                if error > max_error || triangles.len() - removed_triangles <= target_triangles {
+                    break;
+                }
+                if touched[from]
+                    || touched[to]
+                    || flips_triangle(&triangles, &vertex_triangles[from], from, to, &positions)
+                {
+                    continue;
+                }
+
+                remap[from] = to;
+                quadrics[to] = quadrics[to] + quadrics[from];
+                for t in &vertex_triangles[from] {
+                    let triangle = triangles[*t];
+                    if triangle.contains(&to) {
+                        removed_triangles += 1;
+                    }
+                    for v in triangle {
+                        touched[v] = true;
+                    }
+                }
+            }
+
+            if removed_triangles == 0 {
+                break;
+            }
+            triangles = triangles
+                .into_iter()
+                .map(|[a, b, c]| [remap[a], remap[b], remap[c]])
+                .filter(|[a, b, c]| a != b && b != c && a != c)
+                .collect();
+        }
+
+        triangles.into_iter().flatten().map(|v| v as u32).collect()
+    }
+}
+
+/// A symmetric 4x4 matrix that gives the sum of the squared distances to a set of planes
+#[derive(Debug, Clone, Copy, Default)]
+struct Quadric {
+    /// The total area of the planes, used to get the average squared distance
+    weight: f64,
+    a2: f64,
+    ab: f64,
+    ac: f64,
+    ad: f64,
+    b2: f64,
+    bc: f64,
+    bd: f64,
+    c2: f64,
+    cd: f64,
+    d2: f64,
+}
+
+impl Quadric {
+    /// The quadric of the plane of the triangle, weighted by its area
+    fn from_triangle(positions: &[Vec3], triangle: &[usize; 3]) -> Self {
+        let (p0, p1, p2) = (
+            positions[triangle[0]],
+            positions[triangle[1]],
+            positions[triangle[2]],
+        );
+        let cross = (p1 - p0).cross(p2 - p0);
+        let area = cross.length() as f64 * 0.5;
+        let normal = cross.normalize_or_zero();
+        let (a, b, c) = (normal.x as f64, normal.y as f64, normal.z as f64);
+        let d = -(normal.dot(p0) as f64);
+
+        Self {
+            weight: area,
+            a2: a * a * area,
+            ab: a * b * area,
+            ac: a * c * area,
+            ad: a * d * area,
+            b2: b * b * area,
+            bc: b * c * area,
+            bd: b * d * area,
+            c2: c * c * area,
+            cd: c * d * area,
+            d2: d * d * area,
+        }
+    }
+
+    /// The average squared distance to the planes
+    fn error(&self, position: Vec3) -> f64 {
+        let (x, y, z) = (position.x as f64, position.y as f64, position.z as f64);
+        let error = self.a2 * x * x
+            + 2.0 * self.ab * x * y
+            + 2.0 * self.ac * x * z
+            + 2.0 * self.ad * x
+            + self.b2 * y * y
+            + 2.0 * self.bc * y * z
+            + 2.0 * self.bd * y
+            + self.c2 * z * z
+            + 2.0 * self.cd * z
+            + self.d2;
+        // Floating point errors can make it slightly negative
+        error.max(0.0) / self.weight.max(f64::EPSILON)
+    }
+}
+
+impl Add for Quadric {
+    type Output = Quadric;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Self {
+            weight: self.weight + rhs.weight,
+            a2: self.a2 + rhs.a2,
+            ab: self.ab + rhs.ab,
+            ac: self.ac + rhs.ac,
+            ad: self.ad + rhs.ad,
+            b2: self.b2 + rhs.b2,
+            bc: self.bc + rhs.bc,
+            bd: self.bd + rhs.bd,
+            c2: self.c2 + rhs.c2,
+            cd: self.cd + rhs.cd,
+            d2: self.d2 + rhs.d2,
+        }
+    }
+}
+
+/// Moves the `from` vertex on the `to` vertex
+struct Collapse {
+    from: usize,
+    to: usize,
+    error: f64,
+}
+
+/// The cheapest collapse of every edge, sorted by error
+fn sorted_collapses(
+    triangles: &[[usize; 3]],
+    positions: &[Vec3],
+    quadrics: &[Quadric],
+    locked: &[bool],
+) -> Vec<Collapse> {
+    let mut edges = HashSet::default();
+    for [a, b, c] in triangles {
+        for (v0, v1) in [(*a, *b), (*b, *c), (*c, *a)] {
+            edges.insert((v0.min(v1), v0.max(v1)));
+        }
+    }
+
+    let mut collapses: Vec<_> = edges
+        .into_iter()
+        .filter_map(|(v0, v1)| {
+            let quadric = quadrics[v0] + quadrics[v1];
+            let collapse = |from: usize, to: usize| {
+                (!locked[from]).then(|| Collapse {
+                    from,
+                    to,
+                    error: quadric.error(positions[to]),
+                })
+            };
+            match (collapse(v0, v1), collapse(v1, v0)) {
+                (Some(a), Some(b)) => Some(if a.error <= b.error { a } else { b }),
+                (a, b) => a.or(b),
+            }
+        })
+        .collect();
+    collapses.sort_by(|a, b| {
+        a.error
+            .partial_cmp(&b.error)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    collapses
+}
+
+/// Vertices on the border of the mesh or shared with another vertex at the same position
+/// can't be moved without opening a hole in the mesh
+fn locked_vertices(positions: &[Vec3], triangles: &[[usize; 3]]) -> Vec<bool> {
+    let mut locked = vec![false; positions.len()];
+
+    let mut edge_count: HashMap<(usize, usize), u32> = HashMap::default();
+    for [a, b, c] in triangles {
+        for (v0, v1) in [(*a, *b), (*b, *c), (*c, *a)] {
+            *edge_count.entry((v0.min(v1), v0.max(v1))).or_default() += 1;
+        }
+    }
+    for ((v0, v1), count) in edge_count {
+        if count == 1 {
+            locked[v0] = true;
+            locked[v1] = true;
+        }
+    }
+
+    let mut vertices_at_position: HashMap<[u32; 3], Vec<usize>> = HashMap::default();
+    for (v, position) in positions.iter().enumerate() {
+        let key = position.to_array().map(f32::to_bits);
+        vertices_at_position.entry(key).or_default().push(v);
+    }
+    for vertices in vertices_at_position.values() {
+        if vertices.len() > 1 {
+            for v in vertices {
+                locked[*v] = true;
+            }
+        }
+    }
+
+    locked
+}
+
+/// Checks if moving `from` on `to` would flip one of the triangles that stay after the collapse
+fn flips_triangle(
+    triangles: &[[usize; 3]],
+    from_triangles: &[usize],
+    from: usize,
+    to: usize,
+    positions: &[Vec3],
+) -> bool {
+    let normal = |[a, b, c]: [Vec3; 3]| (b - a).cross(c - a);
+    from_triangles.iter().any(|t| {
+        let triangle = triangles[*t];
+        if triangle.contains(&to) {
+            // This triangle is removed by the collapse
+            return false;
+        }
+        let before = triangle.map(|v| positions[v]);
+        let after = triangle.map(|v| positions[if v == from { to } else { v }]);
+        normal(before).dot(normal(after)) <= 0.0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shapes::plane::Plane;
+
+    #[test]
+    fn simplify_flat_plane_keeps_border() {
+        let plane = Plane {
+            resolution: 8,
+            size: 1.0,
+        };
+        let mesh = plane.to_mesh();
+        let indices = mesh.simplify(0.25, 0.01);
+
+        let original_len = mesh.indices.as_ref().unwrap().len();
+        assert!(indices.len() < original_len);
+        assert_eq!(indices.len() % 3, 0);
+        assert!(indices
+            .iter()
+            .all(|i| (*i as usize) < mesh.count_vertices()));
+
+        let positions: Vec<_> = mesh.positions().iter().map(|p| Vec3::from(*p)).collect();
+        let locked = locked_vertices(&positions, &mesh.triangles());
+        let is_border = |p: Vec3| [p.x, p.z].iter().any(|c| *c == 0.0 || *c == plane.size);
+
+        // Every vertex on the border of the plane must be locked and still used, otherwise the outline changed
+        for (v, position) in positions.iter().enumerate() {
+            assert_eq!(locked[v], is_border(*position));
+            if locked[v] {
+                assert!(
+                    indices.contains(&(v as u32)),
+                    "border vertex {v} was removed"
+                );
+            }
+        }
+
+        // The simplified plane still covers the whole square
+        let area: f32 = indices
+            .chunks_exact(3)
+            .map(|t| {
+                let [a, b, c] = [t[0], t[1], t[2]].map(|i| positions[i as usize]);
+                (b - a).cross(c - a).length() / 2.0
+            })
+            .sum();
+        assert!((area - plane.size * plane.size).abs() < 1e-4);
+    }
+}
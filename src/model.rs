@@ -1,116 +1,316 @@
-use crate::{mesh::Mesh, renderer::bind_groups::material::GpuModelMaterials};
+use crate::{
+    bounds::{Aabb, Sphere},
+    image_utils::image_from_color,
+    mesh::{Mesh, MeshVertexLayout},
+    raycast::Bvh,
+    renderer::{
+        bind_groups::material::GpuMaterials,
+        culling::VisibleInstances,
+        render_phase_3d::{CullVariants, LayoutPipelines},
+    },
+    texture::{ColorSpace, SamplerDescriptor, TextureData},
+};
 use bevy::{
-    math::{Vec3, Vec4},
-    prelude::Component,
+    math::{Mat3, Vec2, Vec3, Vec4},
+    prelude::{Color, Component, Handle},
+    reflect::TypeUuid,
+    utils::HashMap,
 };
 use image::RgbaImage;
-use std::ops::Range;
+use std::{ops::Range, sync::Arc};
 use wgpu::util::DeviceExt;
 
 #[derive(Component)]
 pub struct Model {
     pub meshes: Vec<ModelMesh>,
-    pub materials: Vec<Material>,
 }
 
 impl Model {
+    /// A sphere in local space that contains the bounding sphere of every mesh
+    pub fn bounding_sphere(&self) -> Sphere {
+        let aabb = Aabb::from_points(self.meshes.iter().flat_map(|mesh| {
+            let sphere = mesh.bounding_sphere;
+            [sphere.center - sphere.radius, sphere.center + sphere.radius]
+        }));
+        Sphere {
+            center: aabb.center,
+            radius: self
+                .meshes
+                .iter()
+                .map(|mesh| {
+                    mesh.bounding_sphere.center.distance(aabb.center) + mesh.bounding_sphere.radius
+                })
+                .fold(0.0, f32::max),
+        }
+    }
+
     pub fn draw<'a>(
         &'a self,
         render_pass: &mut wgpu::RenderPass<'a>,
-        gpu_materials: &'a GpuModelMaterials,
+        visible: Option<&'a VisibleInstances>,
+        gpu_materials: &'a GpuMaterials,
         mesh_view_bind_group: &'a wgpu::BindGroup,
         transparent: bool,
+        pipelines: &'a LayoutPipelines<CullVariants>,
     ) {
         self.draw_instanced(
             render_pass,
             0..1,
+            visible,
             gpu_materials,
             mesh_view_bind_group,
             transparent,
+            pipelines,
         );
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn draw_instanced<'a>(
         &'a self,
         render_pass: &mut wgpu::RenderPass<'a>,
         instances: Range<u32>,
-        gpu_materials: &'a GpuModelMaterials,
+        visible: Option<&'a VisibleInstances>,
+        gpu_materials: &'a GpuMaterials,
         mesh_view_bind_group: &'a wgpu::BindGroup,
         transparent: bool,
+        pipelines: &'a LayoutPipelines<CullVariants>,
     ) {
-        for mesh in &self.meshes {
-            // TODO get data from Handle
-            // TODO handle material_id == None
-            let material = &gpu_materials.data[mesh.material_id.unwrap_or(0)];
-
-            if transparent && material.0.alpha < 1.0 {
-                mesh.draw_instanced(
-                    render_pass,
-                    instances.clone(),
-                    &material.2,
-                    mesh_view_bind_group,
-                );
+        let mut bound_mesh = None;
+        for (i, mesh) in self.meshes.iter().enumerate() {
+            // Culled instances are skipped
+            let ranges = VisibleInstances::ranges(visible, i, &instances);
+            if ranges.is_empty() {
+                continue;
             }
+            // Meshes without a material, or with a material that isn't prepared yet, are skipped
+            let material = match mesh.material.as_ref().and_then(|h| gpu_materials.get(h)) {
+                Some(material) => material,
+                None => continue,
+            };
+            if transparent != (material.alpha_mode == AlphaMode::Blend) {
+                continue;
+            }
+            // The pipeline depends on the vertex layout of the mesh and double sided materials don't cull
+            let pipeline = match pipelines.get(&mesh.layout) {
+                Some(pipelines) => pipelines.get(material.double_sided),
+                None => continue,
+            };
 
-            if !transparent && material.0.alpha == 1.0 {
-                mesh.draw_instanced(
-                    render_pass,
-                    instances.clone(),
-                    &material.2,
-                    mesh_view_bind_group,
-                );
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(0, mesh_view_bind_group, &[]);
+            render_pass.set_bind_group(1, &material.bind_group, &[]);
+            // Meshes uploaded together share their buffers
+            if !bound_mesh.map_or(false, |bound: &ModelMesh| bound.shares_buffers(mesh)) {
+                mesh.set_buffers(render_pass);
+                bound_mesh = Some(mesh);
+            }
+            for range in ranges {
+                mesh.draw_range(render_pass, range.clone());
             }
         }
     }
 }
 
-#[derive(Debug, Clone)]
+/// How the alpha of the base color is used
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+pub enum AlphaMode {
+    /// The alpha is ignored
+    Opaque,
+    /// Fragments with an alpha lower than the cutoff are discarded, everything else is opaque
+    Mask(f32),
+    /// The mesh is drawn in the transparent pass and blended with what's behind it
+    Blend,
+}
+
+impl Default for AlphaMode {
+    fn default() -> Self {
+        AlphaMode::Opaque
+    }
+}
+
+#[derive(Debug, Clone, TypeUuid)]
+#[uuid = "d6b80f7c-31c0-4fc1-a67f-f37c1d2a4a49"]
 pub struct Material {
     pub name: String,
     pub base_color: Vec4,
     pub alpha: f32,
+    pub alpha_mode: AlphaMode,
+    /// Disables back face culling, back faces are shaded with a flipped normal
+    pub double_sided: bool,
     pub gloss: f32,
     pub specular: Vec3,
-    pub diffuse_texture: RgbaImage,
-    pub normal_texture: Option<RgbaImage>,
-    pub specular_texture: Option<RgbaImage>,
+    pub emissive: Vec3,
+    pub emissive_intensity: f32,
+    pub diffuse_texture: MaterialTexture,
+    pub normal_texture: Option<MaterialTexture>,
+    pub specular_texture: Option<MaterialTexture>,
+    pub emissive_texture: Option<MaterialTexture>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MaterialTexture {
+    pub image: TextureData,
+    pub sampler: SamplerDescriptor,
+    /// Depends on the role of the texture in the material, the loaders set it for each texture
+    pub color_space: ColorSpace,
+    /// The uv set of the mesh used to sample this texture, either 0 or 1
+    pub uv_channel: u32,
+    /// Applied to the uvs before sampling, used for tiling and texture atlases
+    pub uv_transform: Mat3,
+}
+
+impl MaterialTexture {
+    /// Builds a `uv_transform` like KHR_texture_transform.
+    /// The uvs are scaled, then rotated counter-clockwise by `rotation` radians and finally offset
+    pub fn uv_transform(offset: Vec2, rotation: f32, scale: Vec2) -> Mat3 {
+        // Rotating the uvs counter-clockwise is a clockwise rotation in the uv space where v goes down
+        Mat3::from_scale_angle_translation(scale, -rotation, offset)
+    }
+
+    /// A 1x1 texture of a single color
+    pub fn from_color(color: Color, color_space: ColorSpace) -> Self {
+        Self {
+            image: TextureData::Rgba(image_from_color(color, color_space)),
+            sampler: SamplerDescriptor::default(),
+            color_space,
+            uv_channel: 0,
+            uv_transform: Mat3::IDENTITY,
+        }
+    }
+}
+
+/// The image is assumed to be in sRGB
+impl From<RgbaImage> for MaterialTexture {
+    fn from(image: RgbaImage) -> Self {
+        Self {
+            image: TextureData::Rgba(image),
+            sampler: SamplerDescriptor::default(),
+            color_space: ColorSpace::Srgb,
+            uv_channel: 0,
+            uv_transform: Mat3::IDENTITY,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct ModelMesh {
     pub name: String,
     // TODO don't store buffer on mesh
-    pub vertex_buffer: wgpu::Buffer,
-    pub index_buffer: wgpu::Buffer,
+    /// May be shared with the other meshes of the model that have the same layout
+    pub vertex_buffer: Arc<wgpu::Buffer>,
+    /// The attributes present in the vertex buffer
+    pub layout: MeshVertexLayout,
+    /// The index of the first vertex of the mesh in the vertex buffer
+    pub base_vertex: u32,
+    /// May be shared with the other meshes of the model.
+    /// Non-indexed meshes are drawn with consecutive vertices
+    pub index_buffer: Option<Arc<wgpu::Buffer>>,
+    /// The first index of the mesh in the index buffer
+    pub first_index: u32,
+    /// The number of indices, or the number of vertices if the mesh isn't indexed
+    pub num_elements: u32,
+    pub material: Option<Handle<Material>>,
+    /// The bounds of the mesh in local space. The center is used to sort transparent meshes
+    pub aabb: Aabb,
+    pub bounding_sphere: Sphere,
+    /// Used to raycast the mesh on the cpu
+    pub bvh: Bvh,
+    /// Simplified versions of the mesh that use the same vertex and index buffers
+    pub lods: Vec<MeshLod>,
+    /// The lod that is drawn, 0 is the full mesh and 1 is the first of `lods`
+    pub lod: usize,
+}
+
+#[derive(Debug)]
+pub struct MeshLod {
+    pub first_index: u32,
     pub num_elements: u32,
-    pub material_id: Option<usize>,
 }
 
 impl ModelMesh {
     pub fn from_mesh(label: &str, device: &wgpu::Device, mesh: &Mesh) -> Self {
-        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some(&format!("{label} vertex buffer")),
-            contents: bytemuck::cast_slice(&mesh.vertices),
-            usage: wgpu::BufferUsages::VERTEX,
-        });
+        Self::from_meshes(label, device, std::slice::from_ref(mesh))
+            .pop()
+            .unwrap()
+    }
 
-        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some(&format!("{label} index buffer")),
-            contents: bytemuck::cast_slice(
-                mesh.indices
-                    .as_ref()
-                    .expect("tried to get index buffer without indices"),
-            ),
-            usage: wgpu::BufferUsages::INDEX,
-        });
+    /// Uploads the meshes in shared buffers, one vertex buffer for each vertex layout and one index buffer.
+    /// Each mesh is drawn from its own range of the buffers,
+    /// so meshes drawn one after the other don't need to bind new buffers
+    pub fn from_meshes(label: &str, device: &wgpu::Device, meshes: &[Mesh]) -> Vec<Self> {
+        let mut vertex_data: HashMap<MeshVertexLayout, Vec<u8>> = HashMap::default();
+        let mut indices = vec![];
+        let mut ranges = vec![];
+        for mesh in meshes {
+            let layout = mesh.vertex_buffer_layout();
+            let data = vertex_data.entry(layout.clone()).or_default();
+            let base_vertex = (data.len() as u64 / layout.array_stride) as u32;
+            data.extend(mesh.vertex_buffer_data());
 
-        ModelMesh {
-            name: label.to_string(),
-            vertex_buffer,
-            index_buffer,
-            num_elements: mesh.indices.clone().map(|i| i.len() as u32).unwrap_or(1),
-            material_id: mesh.material_id,
+            let first_index = indices.len() as u32;
+            indices.extend_from_slice(mesh.indices.as_deref().unwrap_or_default());
+            let lods: Vec<_> = mesh
+                .lods
+                .iter()
+                .map(|lod| {
+                    let first_index = indices.len() as u32;
+                    indices.extend_from_slice(lod);
+                    MeshLod {
+                        first_index,
+                        num_elements: lod.len() as u32,
+                    }
+                })
+                .collect();
+            ranges.push((layout, base_vertex, first_index, lods));
         }
+
+        let vertex_buffers: HashMap<_, _> = vertex_data
+            .into_iter()
+            .map(|(layout, data)| {
+                let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some(&format!("{label} vertex buffer")),
+                    contents: &data,
+                    usage: wgpu::BufferUsages::VERTEX,
+                });
+                (layout, Arc::new(buffer))
+            })
+            .collect();
+        let index_buffer = (!indices.is_empty()).then(|| {
+            Arc::new(
+                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some(&format!("{label} index buffer")),
+                    contents: bytemuck::cast_slice(&indices),
+                    usage: wgpu::BufferUsages::INDEX,
+                }),
+            )
+        });
+
+        meshes
+            .iter()
+            .zip(ranges)
+            .map(
+                |(mesh, (layout, base_vertex, first_index, lods))| ModelMesh {
+                    name: label.to_string(),
+                    vertex_buffer: vertex_buffers[&layout].clone(),
+                    layout,
+                    base_vertex,
+                    index_buffer: mesh.indices.as_ref().and(index_buffer.clone()),
+                    first_index,
+                    num_elements: mesh
+                        .indices
+                        .as_ref()
+                        .map(|indices| indices.len())
+                        .unwrap_or_else(|| mesh.count_vertices())
+                        as u32,
+                    material: None,
+                    aabb: mesh.compute_aabb(),
+                    bounding_sphere: mesh.compute_bounding_sphere(),
+                    bvh: Bvh::new(mesh),
+                    lods,
+                    lod: 0,
+                },
+            )
+            .collect()
     }
 
     #[allow(unused)]
@@ -130,10 +330,58 @@ impl ModelMesh {
         material_bind_group: &'a wgpu::BindGroup,
         mesh_view_bind_group: &'a wgpu::BindGroup,
     ) {
-        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
-        render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
         render_pass.set_bind_group(0, mesh_view_bind_group, &[]);
         render_pass.set_bind_group(1, material_bind_group, &[]);
-        render_pass.draw_indexed(0..self.num_elements, 0, instances);
+        self.draw_vertices(render_pass, instances);
+    }
+
+    /// Binds the vertex and index buffers and draws the mesh.
+    /// The bind groups and the instance buffer must already be set
+    pub fn draw_vertices<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        instances: Range<u32>,
+    ) {
+        self.set_buffers(render_pass);
+        self.draw_range(render_pass, instances);
+    }
+
+    pub fn set_buffers<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        if let Some(index_buffer) = &self.index_buffer {
+            render_pass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        }
+    }
+
+    /// Draws the range of the buffers used by the mesh, or by its current lod.
+    /// The buffers must already be set
+    pub fn draw_range(&self, render_pass: &mut wgpu::RenderPass, instances: Range<u32>) {
+        if self.index_buffer.is_none() {
+            let vertices = self.base_vertex..self.base_vertex + self.num_elements;
+            render_pass.draw(vertices, instances);
+            return;
+        }
+        let (first_index, num_elements) =
+            match self.lod.checked_sub(1).and_then(|lod| self.lods.get(lod)) {
+                Some(lod) => (lod.first_index, lod.num_elements),
+                None => (self.first_index, self.num_elements),
+            };
+        render_pass.draw_indexed(
+            first_index..first_index + num_elements,
+            self.base_vertex as i32,
+            instances,
+        );
+    }
+
+    /// Whether the buffers set for this mesh can be used to draw the other mesh
+    pub fn shares_buffers(&self, other: &ModelMesh) -> bool {
+        Arc::ptr_eq(&self.vertex_buffer, &other.vertex_buffer)
+            && match (&self.index_buffer, &other.index_buffer) {
+                (Some(index_buffer), Some(other_index_buffer)) => {
+                    Arc::ptr_eq(index_buffer, other_index_buffer)
+                }
+                (_, None) => true,
+                (None, Some(_)) => false,
+            }
     }
 }
@@ -1,16 +1,71 @@
 use anyhow::Context;
-use bevy::{asset::LoadContext, prelude::*, tasks::IoTaskPool};
-use image::RgbaImage;
+use bevy::{
+    asset::{LoadContext, LoadedAsset},
+    prelude::*,
+    tasks::IoTaskPool,
+    utils::Instant,
+};
 use std::io::{BufReader, Cursor};
 
-use crate::{image_utils::image_from_color, mesh::Mesh, mesh::Vertex, model::Material};
+use crate::{
+    image_utils::{apply_alpha_mask, is_binary_mask},
+    mesh::Mesh,
+    mesh::Vertex,
+    mesh_optimizer::MeshOptimizationSettings,
+    model::{AlphaMode, Material, MaterialTexture},
+    static_batching::merge_meshes,
+    texture::{ColorSpace, DeviceFeatures, SamplerDescriptor, TextureData},
+};
 
 use super::LoadedObj;
 
+/// Alpha cutoff used when map_d is a black and white cutout mask
+const ALPHA_MASK_CUTOFF: f32 = 0.5;
+
 pub async fn load_obj<'a, 'b>(
     bytes: &'a [u8],
-    load_context: &'a LoadContext<'b>,
+    load_context: &'a mut LoadContext<'b>,
+    mesh_optimization: &MeshOptimizationSettings,
+    device_features: &DeviceFeatures,
 ) -> anyhow::Result<LoadedObj> {
+    let (obj_models, materials) = {
+        let load_context: &LoadContext = load_context;
+        load_obj_data(bytes, load_context, device_features).await?
+    };
+
+    let mut meshes = generate_mesh(&obj_models, &materials);
+    if mesh_optimization.static_batching {
+        let mesh_count = meshes.len();
+        meshes = merge_meshes(meshes);
+        log::info!("Merged {mesh_count} meshes in {} batches", meshes.len());
+    }
+
+    // single_index duplicates every vertex used with a different combination of indices
+    let start = Instant::now();
+    for mesh in &mut meshes {
+        mesh_optimization.apply(mesh);
+    }
+    log::info!(
+        "Optimized all meshes in {}ms",
+        (Instant::now() - start).as_millis()
+    );
+
+    let materials = materials
+        .into_iter()
+        .enumerate()
+        .map(|(i, material)| {
+            load_context.set_labeled_asset(&format!("Material{i}"), LoadedAsset::new(material))
+        })
+        .collect();
+
+    Ok(LoadedObj { materials, meshes })
+}
+
+async fn load_obj_data<'a, 'b>(
+    bytes: &'a [u8],
+    load_context: &'a LoadContext<'b>,
+    device_features: &'a DeviceFeatures,
+) -> anyhow::Result<(Vec<tobj::Model>, Vec<Material>)> {
     let (obj_models, obj_materials) = tobj::load_obj_buf_async(
         &mut BufReader::new(Cursor::new(bytes)),
         &tobj::LoadOptions {
@@ -34,66 +89,219 @@ pub async fn load_obj<'a, 'b>(
         .scope(|scope| {
             obj_materials.iter().for_each(|obj_material| {
                 log::info!("Loading {}", obj_material.name);
-                scope.spawn(async move { load_material(load_context, obj_material).await });
+                scope.spawn(async move {
+                    load_material(load_context, obj_material, device_features).await
+                });
             });
         })
         .into_iter()
         .filter_map(|res| {
-            if let Err(err) = res.as_ref() {
-                log::error!("Error while loading obj materials: {err}");
+            match res.as_ref() {
+                Ok(material) => log::info!("Finished loading material: {}", material.name),
+                Err(err) => log::error!("Error while loading obj materials: {err}"),
             }
-            log::info!("Finished loading material: {}", res.as_ref().unwrap().name);
             res.ok()
         })
         .collect();
 
-    let meshes = generate_mesh(&obj_models, &materials);
-
-    Ok(LoadedObj { materials, meshes })
+    Ok((obj_models, materials))
 }
 
 async fn load_material<'a>(
     load_context: &LoadContext<'a>,
     obj_material: &tobj::Material,
+    device_features: &DeviceFeatures,
 ) -> anyhow::Result<Material> {
-    let diffuse_texture = load_texture(load_context, &obj_material.diffuse_texture)
-        .await?
-        .unwrap_or_else(|| image_from_color(Color::WHITE));
-    let normal_texture = load_texture(load_context, &obj_material.normal_texture).await?;
-    let specular_texture = load_texture(load_context, &obj_material.specular_texture).await?;
+    let mut diffuse_texture = load_texture(
+        load_context,
+        &obj_material.diffuse_texture,
+        ColorSpace::Srgb,
+        device_features,
+    )
+    .await?
+    .unwrap_or_else(|| MaterialTexture::from_color(Color::WHITE, ColorSpace::Srgb));
+    // map_d is merged in the diffuse alpha. A black and white mask is a cutout,
+    // anything else needs blending
+    let dissolve_texture = load_texture(
+        load_context,
+        &obj_material.dissolve_texture,
+        ColorSpace::Linear,
+        device_features,
+    )
+    .await?;
+    let alpha_mode = if let Some(dissolve_texture) = dissolve_texture {
+        let binary_mask = match (&diffuse_texture.image, &dissolve_texture.image) {
+            (TextureData::Rgba(diffuse), TextureData::Rgba(mask)) => {
+                diffuse_texture.image = TextureData::Rgba(apply_alpha_mask(diffuse, mask));
+                is_binary_mask(mask)
+            }
+            _ => {
+                log::warn!(
+                    "map_d can't be merged with compressed textures, the diffuse alpha is used instead"
+                );
+                false
+            }
+        };
+        if obj_material.dissolve < 1.0 || !binary_mask {
+            AlphaMode::Blend
+        } else {
+            AlphaMode::Mask(ALPHA_MASK_CUTOFF)
+        }
+    } else if obj_material.dissolve < 1.0 {
+        AlphaMode::Blend
+    } else {
+        AlphaMode::Opaque
+    };
+    let normal_texture = load_texture(
+        load_context,
+        &obj_material.normal_texture,
+        ColorSpace::Normal,
+        device_features,
+    )
+    .await?;
+    // map_Ks is a color in the mtl spec
+    let specular_texture = load_texture(
+        load_context,
+        &obj_material.specular_texture,
+        ColorSpace::Srgb,
+        device_features,
+    )
+    .await?;
+
+    // tobj doesn't know about the emissive parameters so we need to read them from the unknown params
+    let emissive_texture = match obj_material.unknown_param.get("map_Ke") {
+        Some(path) => load_texture(load_context, path, ColorSpace::Srgb, device_features).await?,
+        None => None,
+    };
+    let emissive = match obj_material.unknown_param.get("Ke") {
+        Some(ke) => parse_vec3(ke)
+            .with_context(|| format!("Failed to parse Ke for {}", obj_material.name))?,
+        // If there's only an emissive map, the map is used as is
+        None if emissive_texture.is_some() => Vec3::ONE,
+        None => Vec3::ZERO,
+    };
 
     Ok(Material {
         name: obj_material.name.clone(),
         base_color: Vec3::from(obj_material.diffuse).extend(obj_material.dissolve),
         diffuse_texture,
         alpha: obj_material.dissolve,
+        alpha_mode,
+        // mtl doesn't have a way to specify this
+        double_sided: false,
         gloss: obj_material.shininess,
         specular: Vec3::from(obj_material.specular),
+        emissive,
+        emissive_intensity: 1.0,
         normal_texture,
         specular_texture,
+        emissive_texture,
     })
 }
 
+/// Parses a mtl color parameter like `Ke 1.0 0.5 0.0`
+fn parse_vec3(value: &str) -> anyhow::Result<Vec3> {
+    let values = value
+        .split_whitespace()
+        .map(|v| v.parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()?;
+    match values[..] {
+        [r, g, b] => Ok(Vec3::new(r, g, b)),
+        // A single value is applied to every channel
+        [v] => Ok(Vec3::splat(v)),
+        _ => anyhow::bail!("Expected 3 values but found {}", values.len()),
+    }
+}
+
+/// Loads a texture map like `map_Kd -clamp on texture.png`
 async fn load_texture<'a>(
     load_context: &LoadContext<'a>,
-    texture_path: &str,
-) -> anyhow::Result<Option<RgbaImage>> {
+    texture_map: &str,
+    color_space: ColorSpace,
+    device_features: &DeviceFeatures,
+) -> anyhow::Result<Option<MaterialTexture>> {
+    let (texture_path, sampler, uv_transform) = parse_texture_options(texture_map);
     Ok(if !texture_path.is_empty() {
         let bytes = load_context
             .read_asset_bytes(load_context.path().parent().unwrap().join(&texture_path))
             .await?;
         log::info!("Finished loading texture: {texture_path:?}");
-        let rgba = image::load_from_memory(&bytes)?.to_rgba8();
-        Some(rgba)
+        let image = TextureData::from_file_bytes(&texture_path, &bytes)?;
+        device_features.check(&image)?;
+        Some(MaterialTexture {
+            image,
+            sampler,
+            color_space,
+            // obj only has a single uv set
+            uv_channel: 0,
+            uv_transform,
+        })
     } else {
         None
     })
 }
 
+/// Splits the options of a texture map from its path.
+/// `-clamp` affects the sampler and `-o` and `-s` the uv transform, the other options are ignored.
+fn parse_texture_options(texture_map: &str) -> (String, SamplerDescriptor, Mat3) {
+    let mut sampler = SamplerDescriptor::default();
+    let mut offset = Vec2::ZERO;
+    let mut scale = Vec2::ONE;
+    let mut tokens = texture_map.split_whitespace().peekable();
+    while let Some(option) = tokens.next_if(|token| token.starts_with('-')) {
+        match option {
+            "-clamp" => {
+                if tokens.next() == Some("on") {
+                    sampler = SamplerDescriptor::clamp_to_edge();
+                }
+            }
+            // These options take between 1 and 3 numbers, only u and v are used
+            "-o" | "-s" | "-t" => {
+                let mut values = vec![];
+                for _ in 0..3 {
+                    match tokens.next_if(|v| v.parse::<f32>().is_ok()) {
+                        Some(v) => values.push(v.parse::<f32>().unwrap()),
+                        None => break,
+                    }
+                }
+                let value = |i: usize, default: f32| values.get(i).copied().unwrap_or(default);
+                match option {
+                    "-o" => offset = Vec2::new(value(0, 0.0), value(1, 0.0)),
+                    "-s" => scale = Vec2::new(value(0, 1.0), value(1, 1.0)),
+                    _ => {}
+                }
+            }
+            "-mm" => {
+                tokens.next();
+                tokens.next();
+            }
+            _ => {
+                tokens.next();
+            }
+        }
+    }
+    // The v coordinate is flipped when loading the mesh so the transform needs to be flipped too
+    let uv_transform = Mat3::from_scale_angle_translation(
+        scale,
+        0.0,
+        Vec2::new(offset.x, 1.0 - scale.y - offset.y),
+    );
+    // The path can contain spaces
+    (tokens.collect::<Vec<_>>().join(" "), sampler, uv_transform)
+}
+
 fn generate_mesh(obj_models: &[tobj::Model], materials: &[Material]) -> Vec<Mesh> {
     obj_models
         .iter()
         .map(|m| {
+            let uv = |i: usize| {
+                if m.mesh.texcoords.is_empty() {
+                    Vec2::ZERO
+                } else {
+                    // UVs are flipped
+                    Vec2::new(m.mesh.texcoords[i * 2], 1.0 - m.mesh.texcoords[i * 2 + 1])
+                }
+            };
             let vertices: Vec<_> = (0..m.mesh.positions.len() / 3)
                 .map(|i| Vertex {
                     position: Vec3::new(
@@ -101,12 +309,7 @@ fn generate_mesh(obj_models: &[tobj::Model], materials: &[Material]) -> Vec<Mesh
                         m.mesh.positions[i * 3 + 1],
                         m.mesh.positions[i * 3 + 2],
                     ),
-                    uv: if m.mesh.texcoords.is_empty() {
-                        Vec2::ZERO
-                    } else {
-                        // UVs are flipped
-                        Vec2::new(m.mesh.texcoords[i * 2], 1.0 - m.mesh.texcoords[i * 2 + 1])
-                    },
+                    uv: uv(i),
                     normal: if m.mesh.normals.is_empty() {
                         Vec3::ZERO
                     } else {
@@ -116,16 +319,24 @@ fn generate_mesh(obj_models: &[tobj::Model], materials: &[Material]) -> Vec<Mesh
                             m.mesh.normals[i * 3 + 2],
                         )
                     },
-                    tangent: Vec3::ZERO,
-                    bitangent: Vec3::ZERO,
+                    tangent: Vec4::ZERO,
+                    color: if m.mesh.vertex_color.is_empty() {
+                        Vec4::ONE
+                    } else {
+                        Vec4::new(
+                            m.mesh.vertex_color[i * 3],
+                            m.mesh.vertex_color[i * 3 + 1],
+                            m.mesh.vertex_color[i * 3 + 2],
+                            1.0,
+                        )
+                    },
+                    // obj only has a single uv set
+                    uv1: uv(i),
                 })
                 .collect();
 
-            let mut mesh = crate::mesh::Mesh {
-                vertices,
-                indices: Some(m.mesh.indices.clone()),
-                material_id: m.mesh.material_id,
-            };
+            let mut mesh = Mesh::from_vertices(&vertices, Some(m.mesh.indices.clone()));
+            mesh.material_id = m.mesh.material_id;
 
             if m.mesh.normals.is_empty() {
                 mesh.compute_normals();
@@ -133,7 +344,7 @@ fn generate_mesh(obj_models: &[tobj::Model], materials: &[Material]) -> Vec<Mesh
             if !m.mesh.normals.is_empty()
                 && m.mesh
                     .material_id
-                    .and_then(|m_id| materials[m_id].normal_texture.clone())
+                    .and_then(|m_id| materials[m_id].normal_texture.as_ref())
                     .is_some()
             {
                 mesh.compute_tangents();
@@ -7,9 +7,11 @@ use bevy::{
 
 use crate::{
     mesh::Mesh,
+    mesh_optimizer::MeshOptimizationSettings,
     model::{Material, Model, ModelMesh},
     obj_loader::loader::load_obj,
     renderer::WgpuRenderer,
+    texture::DeviceFeatures,
 };
 
 mod loader;
@@ -31,11 +33,28 @@ impl Plugin for ObjLoaderPlugin {
 #[derive(Debug, TypeUuid)]
 #[uuid = "39cadc56-aa9c-4543-8640-a018b74b5052"]
 pub struct LoadedObj {
-    pub materials: Vec<Material>,
+    pub materials: Vec<Handle<Material>>,
     pub meshes: Vec<Mesh>,
 }
-#[derive(Default)]
-pub struct ObjLoader;
+pub struct ObjLoader {
+    mesh_optimization: MeshOptimizationSettings,
+    device_features: DeviceFeatures,
+}
+
+impl FromWorld for ObjLoader {
+    fn from_world(world: &mut World) -> Self {
+        Self {
+            mesh_optimization: world
+                .get_resource::<MeshOptimizationSettings>()
+                .cloned()
+                .unwrap_or_default(),
+            device_features: world
+                .get_resource::<DeviceFeatures>()
+                .cloned()
+                .unwrap_or_default(),
+        }
+    }
+}
 
 impl AssetLoader for ObjLoader {
     fn extensions(&self) -> &[&str] {
@@ -52,7 +71,13 @@ impl AssetLoader for ObjLoader {
 
             log::info!("Loading {:?}", load_context.path());
 
-            let obj = load_obj(bytes, load_context).await?;
+            let obj = load_obj(
+                bytes,
+                load_context,
+                &self.mesh_optimization,
+                &self.device_features,
+            )
+            .await?;
             load_context.set_default_asset(LoadedAsset::new(obj));
 
             log::info!(
@@ -85,10 +110,10 @@ fn obj_spawner(
             let LoadedObj { materials, meshes } = obj;
 
             // TODO mesh label for obj
-            let model_meshes = meshes
-                .iter()
-                .map(|mesh| ModelMesh::from_mesh("", &renderer.device, mesh))
-                .collect();
+            let mut model_meshes = ModelMesh::from_meshes("", &renderer.device, meshes);
+            for (model_mesh, mesh) in model_meshes.iter_mut().zip(meshes) {
+                model_mesh.material = mesh.material_id.map(|id| materials[id].clone());
+            }
 
             log::info!(
                 "Finished creating mesh buffers {}ms",
@@ -96,7 +121,6 @@ fn obj_spawner(
             );
 
             commands.entity(entity).insert(Model {
-                materials: materials.clone(),
                 meshes: model_meshes,
             });
 
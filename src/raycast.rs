@@ -0,0 +1,443 @@
+use bevy::{
+    math::{Mat4, Vec2, Vec3},
+    prelude::Entity,
+};
+
+use crate::{
+    bounds::Aabb,
+    instances::Instances,
+    mesh::{Mesh, VertexAttributeValues},
+    model::Model,
+    transform::Transform,
+};
+
+// References:
+// <https://jacco.ompf2.com/2022/04/13/how-to-build-a-bvh-part-1-basics/>
+// <https://www.scratchapixel.com/lessons/3d-basic-rendering/ray-tracing-rendering-a-triangle/moller-trumbore-ray-triangle-intersection>
+
+/// Leaves with this many triangles or less aren't split
+const MAX_LEAF_TRIANGLES: usize = 4;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Ray {
+    pub origin: Vec3,
+    /// Not normalized once transformed so distances stay comparable between spaces
+    pub direction: Vec3,
+}
+
+impl Ray {
+    pub fn new(origin: Vec3, direction: Vec3) -> Self {
+        Self {
+            origin,
+            direction: direction.normalize(),
+        }
+    }
+
+    pub fn at(&self, distance: f32) -> Vec3 {
+        self.origin + self.direction * distance
+    }
+
+    pub fn transform(&self, matrix: &Mat4) -> Self {
+        Self {
+            origin: matrix.transform_point3(self.origin),
+            direction: matrix.transform_vector3(self.direction),
+        }
+    }
+
+    /// The distance to the entry point of the box, 0 if the origin is inside it
+    pub fn intersect_aabb(&self, aabb: &Aabb) -> Option<f32> {
+        let inv_direction = self.direction.recip();
+        let t0 = (aabb.min() - self.origin) * inv_direction;
+        let t1 = (aabb.max() - self.origin) * inv_direction;
+        let mut near = 0.0f32;
+        let mut far = f32::INFINITY;
+        for axis in 0..3 {
+            // 0 * inf is NaN when the ray is parallel to a face and starts on it, it stays inside that slab
+            if t0[axis].is_nan() || t1[axis].is_nan() {
+                continue;
+            }
+            near = near.max(t0[axis].min(t1[axis]));
+            far = far.min(t0[axis].max(t1[axis]));
+        }
+        if near <= far {
+            Some(near)
+        } else {
+            None
+        }
+    }
+
+    /// Möller–Trumbore intersection, both faces of the triangle are hit.
+    /// Returns the distance and the barycentric coordinates of the second and third vertices
+    pub fn intersect_triangle(&self, [p0, p1, p2]: [Vec3; 3]) -> Option<(f32, Vec2)> {
+        let edge1 = p1 - p0;
+        let edge2 = p2 - p0;
+        let p = self.direction.cross(edge2);
+        let determinant = edge1.dot(p);
+        // The determinant scales with the length of the edges and of the direction,
+        // the threshold must scale with them to work with tiny and huge triangles
+        let scale = edge1.length() * edge2.length() * self.direction.length();
+        if determinant.abs() <= f32::EPSILON * scale {
+            // The ray is parallel to the triangle, or the triangle is degenerate
+            return None;
+        }
+        let inv_determinant = 1.0 / determinant;
+
+        let s = self.origin - p0;
+        let u = s.dot(p) * inv_determinant;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let q = s.cross(edge1);
+        let v = self.direction.dot(q) * inv_determinant;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        let distance = edge2.dot(q) * inv_determinant;
+        (distance > 0.0).then(|| (distance, Vec2::new(u, v)))
+    }
+}
+
+#[derive(Debug)]
+struct BvhNode {
+    aabb: Aabb,
+    /// The first child for inner nodes or the first triangle for leaves.
+    /// The second child is always right after the first one
+    first: usize,
+    /// The number of triangles, 0 for inner nodes
+    count: usize,
+}
+
+/// A bounding volume hierarchy over the triangles of a `Mesh`.
+/// Keeps a copy of the attributes needed to describe a hit
+#[derive(Debug)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    triangles: Vec<[usize; 3]>,
+    positions: Vec<Vec3>,
+    normals: Option<Vec<Vec3>>,
+    uvs: Option<Vec<Vec2>>,
+}
+
+/// The closest intersection of a ray with a mesh in the space of the mesh
+#[derive(Debug, Clone, Copy)]
+pub struct MeshHit {
+    pub distance: f32,
+    pub point: Vec3,
+    pub normal: Vec3,
+    pub uv: Option<Vec2>,
+}
+
+impl Bvh {
+    pub fn new(mesh: &Mesh) -> Self {
+        let float3 = |values: &VertexAttributeValues| {
+            values
+                .as_float3()
+                .map(|values| values.iter().map(|v| Vec3::from(*v)).collect())
+        };
+        let positions: Vec<_> = mesh.positions().iter().map(|p| Vec3::from(*p)).collect();
+        let mut bvh = Self {
+            nodes: vec![],
+            triangles: mesh.triangles(),
+            normals: mesh.attribute(Mesh::ATTRIBUTE_NORMAL).and_then(float3),
+            uvs: mesh
+                .attribute(Mesh::ATTRIBUTE_UV_0)
+                .and_then(VertexAttributeValues::as_float2)
+                .map(|uvs| uvs.iter().map(|uv| Vec2::from(*uv)).collect()),
+            positions,
+        };
+        if !bvh.triangles.is_empty() {
+            bvh.nodes.push(BvhNode {
+                aabb: bvh.triangles_aabb(0, bvh.triangles.len()),
+                first: 0,
+                count: bvh.triangles.len(),
+            });
+            bvh.subdivide(0);
+        }
+        bvh
+    }
+
+    fn triangle_positions(&self, triangle: usize) -> [Vec3; 3] {
+        self.triangles[triangle].map(|v| self.positions[v])
+    }
+
+    fn centroid(&self, triangle: usize) -> Vec3 {
+        let [p0, p1, p2] = self.triangle_positions(triangle);
+        (p0 + p1 + p2) / 3.0
+    }
+
+    fn triangles_aabb(&self, first: usize, count: usize) -> Aabb {
+        Aabb::from_points((first..first + count).flat_map(|t| self.triangle_positions(t)))
+    }
+
+    /// Splits the node at the middle of the longest axis of the centroids of its triangles
+    fn subdivide(&mut self, node: usize) {
+        let BvhNode { first, count, .. } = self.nodes[node];
+        if count <= MAX_LEAF_TRIANGLES {
+            return;
+        }
+
+        let centroids = Aabb::from_points((first..first + count).map(|t| self.centroid(t)));
+        let extents = centroids.half_extents;
+        let axis = if extents.x > extents.y && extents.x > extents.z {
+            0
+        } else if extents.y > extents.z {
+            1
+        } else {
+            2
+        };
+        let split = centroids.center[axis];
+
+        // Partition the triangles on each side of the split
+        let mut left = first;
+        let mut right = first + count;
+        while left < right {
+            if self.centroid(left)[axis] < split {
+                left += 1;
+            } else {
+                right -= 1;
+                self.triangles.swap(left, right);
+            }
+        }
+        let left_count = left - first;
+        if left_count == 0 || left_count == count {
+            // Every centroid is at the same position
+            return;
+        }
+
+        let child = self.nodes.len();
+        for (first, count) in [(first, left_count), (left, count - left_count)] {
+            self.nodes.push(BvhNode {
+                aabb: self.triangles_aabb(first, count),
+                first,
+                count,
+            });
+        }
+        self.nodes[node].first = child;
+        self.nodes[node].count = 0;
+
+        self.subdivide(child);
+        self.subdivide(child + 1);
+    }
+
+    /// The closest hit along the ray, the ray must be in the space of the mesh
+    pub fn raycast(&self, ray: &Ray) -> Option<MeshHit> {
+        let mut closest: Option<(f32, usize, Vec2)> = None;
+        let mut stack = vec![0];
+        while let Some(node) = stack.pop() {
+            let node = match self.nodes.get(node) {
+                Some(node) => node,
+                None => continue,
+            };
+            match ray.intersect_aabb(&node.aabb) {
+                Some(distance) if closest.map_or(true, |(closest, ..)| distance < closest) => {}
+                _ => continue,
+            }
+
+            if node.count == 0 {
+                stack.extend([node.first, node.first + 1]);
+                continue;
+            }
+            for triangle in node.first..node.first + node.count {
+                if let Some((distance, barycentric)) =
+                    ray.intersect_triangle(self.triangle_positions(triangle))
+                {
+                    if closest.map_or(true, |(closest, ..)| distance < closest) {
+                        closest = Some((distance, triangle, barycentric));
+                    }
+                }
+            }
+        }
+
+        closest.map(|(distance, triangle, barycentric)| {
+            let (u, v) = (barycentric.x, barycentric.y);
+            let [v0, v1, v2] = self.triangles[triangle];
+            let [p0, p1, p2] = self.triangle_positions(triangle);
+            let normal = match self.normals.as_ref() {
+                Some(normals) => normals[v0] * (1.0 - u - v) + normals[v1] * u + normals[v2] * v,
+                None => (p1 - p0).cross(p2 - p0),
+            };
+            MeshHit {
+                distance,
+                point: ray.at(distance),
+                normal: normal.normalize_or_zero(),
+                uv: self
+                    .uvs
+                    .as_ref()
+                    .map(|uvs| uvs[v0] * (1.0 - u - v) + uvs[v1] * u + uvs[v2] * v),
+            }
+        })
+    }
+}
+
+/// The closest intersection of a ray with a `Model`, in world space
+#[derive(Debug, Clone, Copy)]
+pub struct RayHit {
+    pub entity: Entity,
+    /// The index in `Instances`, 0 for models with a `Transform`
+    pub instance: usize,
+    /// The index in `Model::meshes`
+    pub mesh: usize,
+    pub distance: f32,
+    pub point: Vec3,
+    pub normal: Vec3,
+    pub uv: Option<Vec2>,
+}
+
+/// Finds the closest mesh hit by a ray in world space.
+/// Every instance is tested, including the ones that were culled
+pub fn raycast<'a>(
+    ray: &Ray,
+    models: impl Iterator<
+        Item = (
+            Entity,
+            &'a Model,
+            Option<&'a Transform>,
+            Option<&'a Instances>,
+        ),
+    >,
+) -> Option<RayHit> {
+    let mut closest: Option<RayHit> = None;
+    for (entity, model, transform, instances) in models {
+        let transforms = if let Some(transform) = transform {
+            vec![transform.compute_matrix()]
+        } else if let Some(instances) = instances {
+            instances.0.iter().map(Transform::compute_matrix).collect()
+        } else {
+            continue;
+        };
+
+        let model_sphere = model.bounding_sphere();
+        for (instance, matrix) in transforms.iter().enumerate() {
+            let sphere = model_sphere.transform(matrix);
+            let to_center = sphere.center - ray.origin;
+            let closest_approach = to_center - ray.direction * to_center.dot(ray.direction);
+            if closest_approach.length() > sphere.radius {
+                continue;
+            }
+
+            // The direction isn't normalized so the distances are the same in both spaces
+            let local_ray = ray.transform(&matrix.inverse());
+            let normal_matrix = matrix.inverse().transpose();
+            for (mesh_index, mesh) in model.meshes.iter().enumerate() {
+                let hit = match mesh.bvh.raycast(&local_ray) {
+                    Some(hit) => hit,
+                    None => continue,
+                };
+                if closest.map_or(false, |closest| closest.distance <= hit.distance) {
+                    continue;
+                }
+                closest = Some(RayHit {
+                    entity,
+                    instance,
+                    mesh: mesh_index,
+                    distance: hit.distance,
+                    point: matrix.transform_point3(hit.point),
+                    normal: normal_matrix
+                        .transform_vector3(hit.normal)
+                        .normalize_or_zero(),
+                    uv: hit.uv,
+                });
+            }
+        }
+    }
+    closest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shapes::plane::Plane;
+
+    fn triangle() -> [Vec3; 3] {
+        [Vec3::ZERO, Vec3::X, Vec3::Y]
+    }
+
+    #[test]
+    fn triangle_hit() {
+        let ray = Ray::new(Vec3::new(0.25, 0.5, 2.0), -Vec3::Z);
+        let (distance, barycentric) = ray.intersect_triangle(triangle()).unwrap();
+        assert!((distance - 2.0).abs() < 1e-6);
+        assert!(barycentric.abs_diff_eq(Vec2::new(0.25, 0.5), 1e-6));
+
+        // Both faces are hit
+        let ray = Ray::new(Vec3::new(0.25, 0.5, -2.0), Vec3::Z);
+        assert!(ray.intersect_triangle(triangle()).is_some());
+    }
+
+    #[test]
+    fn triangle_miss() {
+        // Next to the triangle
+        let ray = Ray::new(Vec3::new(0.75, 0.75, 2.0), -Vec3::Z);
+        assert!(ray.intersect_triangle(triangle()).is_none());
+        // Pointing away from the triangle
+        let ray = Ray::new(Vec3::new(0.25, 0.25, 2.0), Vec3::Z);
+        assert!(ray.intersect_triangle(triangle()).is_none());
+    }
+
+    #[test]
+    fn triangle_parallel_ray() {
+        // In the plane of the triangle, crossing it
+        let ray = Ray::new(Vec3::new(-1.0, 0.25, 0.0), Vec3::X);
+        assert!(ray.intersect_triangle(triangle()).is_none());
+        // Above the triangle
+        let ray = Ray::new(Vec3::new(-1.0, 0.25, 1.0), Vec3::X);
+        assert!(ray.intersect_triangle(triangle()).is_none());
+    }
+
+    #[test]
+    fn scaled_triangle_hit() {
+        for scale in [1e-4, 1e4] {
+            let triangle = triangle().map(|p| p * scale);
+            let ray = Ray::new(Vec3::new(0.25, 0.5, 2.0) * scale, -Vec3::Z);
+            let (distance, barycentric) = ray.intersect_triangle(triangle).unwrap();
+            assert!((distance / scale - 2.0).abs() < 1e-4, "scale {scale}");
+            assert!(barycentric.abs_diff_eq(Vec2::new(0.25, 0.5), 1e-4));
+
+            // A transformed ray isn't normalized
+            let ray = Ray::new(Vec3::new(0.25, 0.5, 2.0), -Vec3::Z)
+                .transform(&Mat4::from_scale(Vec3::splat(scale)));
+            let (distance, _) = ray.intersect_triangle(triangle).unwrap();
+            assert!((distance - 2.0).abs() < 1e-4, "scale {scale}");
+
+            let ray = Ray::new(Vec3::new(-1.0, 0.25, 0.0) * scale, Vec3::X);
+            assert!(ray.intersect_triangle(triangle).is_none());
+        }
+    }
+
+    #[test]
+    fn bvh_hit_and_miss() {
+        let mesh = Plane {
+            resolution: 16,
+            size: 2.0,
+        }
+        .to_mesh();
+        let bvh = Bvh::new(&mesh);
+
+        // Straight down on a vertex, on the faces of the boxes of the nodes around it
+        let hit = bvh
+            .raycast(&Ray::new(Vec3::new(0.5, 3.0, 1.5), -Vec3::Y))
+            .unwrap();
+        assert!((hit.distance - 3.0).abs() < 1e-5);
+        assert!(hit.point.abs_diff_eq(Vec3::new(0.5, 0.0, 1.5), 1e-5));
+        assert!(hit.normal.abs_diff_eq(Vec3::Y, 1e-5));
+        assert!(hit.uv.unwrap().abs_diff_eq(Vec2::new(0.25, 0.75), 1e-5));
+
+        let hit = bvh
+            .raycast(&Ray::new(
+                Vec3::new(1.3, 2.0, 0.6),
+                Vec3::new(0.0, -1.0, 0.5),
+            ))
+            .unwrap();
+        assert!(hit.point.abs_diff_eq(Vec3::new(1.3, 0.0, 1.6), 1e-5));
+        assert!((hit.distance - 5.0f32.sqrt()).abs() < 1e-5);
+
+        // Outside of the plane, parallel to it and pointing away from it
+        for ray in [
+            Ray::new(Vec3::new(3.0, 1.0, 1.0), -Vec3::Y),
+            Ray::new(Vec3::new(-1.0, 0.0, 1.0), Vec3::X),
+            Ray::new(Vec3::new(1.0, 1.0, 1.0), Vec3::Y),
+        ] {
+            assert!(bvh.raycast(&ray).is_none(), "{ray:?}");
+        }
+    }
+}
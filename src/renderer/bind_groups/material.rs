@@ -5,24 +5,33 @@ use bevy::{
         encase::{self, UniformBuffer},
         ShaderType,
     },
+    utils::HashMap,
 };
 use wgpu::util::DeviceExt;
 
 use crate::{
-    image_utils::image_from_color, model::Model, renderer::WgpuRenderer, texture::Texture,
+    model::{AlphaMode, Material, MaterialTexture},
+    renderer::WgpuRenderer,
+    texture::{ColorSpace, SamplerCache, Texture},
 };
 
-// TODO
-// this is temporary until Meshes have handles to their material and
-// Models are just a list of Mesh handles
-#[derive(Component)]
-pub struct GpuModelMaterials {
-    pub data: Vec<(
-        MaterialUniform,
-        wgpu::Buffer,
-        wgpu::BindGroup,
-        UniformBuffer<Vec<u8>>,
-    )>,
+/// The gpu data of every loaded `Material`.
+/// Every mesh using the same material handle shares the same textures and bind group.
+#[derive(Default)]
+pub struct GpuMaterials(pub HashMap<Handle<Material>, GpuMaterial>);
+
+impl GpuMaterials {
+    pub fn get(&self, handle: &Handle<Material>) -> Option<&GpuMaterial> {
+        self.0.get(handle)
+    }
+}
+
+pub struct GpuMaterial {
+    pub alpha_mode: AlphaMode,
+    pub double_sided: bool,
+    pub uniform: MaterialUniform,
+    pub buffer: wgpu::Buffer,
+    pub bind_group: wgpu::BindGroup,
 }
 
 #[derive(ShaderType)]
@@ -32,6 +41,52 @@ pub struct MaterialUniform {
     pub gloss: f32,
     pub specular: Vec3,
     pub flags: u32,
+    pub emissive: Vec3,
+    pub emissive_intensity: f32,
+    pub alpha_cutoff: f32,
+    pub diffuse_uv_transform: Mat3,
+    pub normal_uv_transform: Mat3,
+    pub specular_uv_transform: Mat3,
+    pub emissive_uv_transform: Mat3,
+    /// The uv set of the diffuse, normal, specular and emissive textures
+    pub uv_channels: UVec4,
+}
+
+impl From<&Material> for MaterialUniform {
+    fn from(material: &Material) -> Self {
+        let uv = |texture: Option<&MaterialTexture>| {
+            texture
+                .map(|texture| (texture.uv_transform, texture.uv_channel))
+                .unwrap_or((Mat3::IDENTITY, 0))
+        };
+        let (diffuse_uv_transform, diffuse_uv_channel) = uv(Some(&material.diffuse_texture));
+        let (normal_uv_transform, normal_uv_channel) = uv(material.normal_texture.as_ref());
+        let (specular_uv_transform, specular_uv_channel) = uv(material.specular_texture.as_ref());
+        let (emissive_uv_transform, emissive_uv_channel) = uv(material.emissive_texture.as_ref());
+        Self {
+            base_color: material.base_color,
+            alpha: material.alpha,
+            gloss: material.gloss,
+            specular: material.specular,
+            flags: MaterialFlags::from(material).bits(),
+            emissive: material.emissive,
+            emissive_intensity: material.emissive_intensity,
+            alpha_cutoff: match material.alpha_mode {
+                AlphaMode::Mask(cutoff) => cutoff,
+                _ => 0.0,
+            },
+            diffuse_uv_transform,
+            normal_uv_transform,
+            specular_uv_transform,
+            emissive_uv_transform,
+            uv_channels: UVec4::new(
+                diffuse_uv_channel,
+                normal_uv_channel,
+                specular_uv_channel,
+                emissive_uv_channel,
+            ),
+        }
+    }
 }
 
 // WARN these must match the flags in shader.wgsl
@@ -39,7 +94,7 @@ bitflags::bitflags! {
     #[repr(transparent)]
     pub struct MaterialFlags: u32 {
         const USE_NORMAL_MAP = (1 << 0);
-        const _1 = (1 << 1);
+        const ALPHA_MASK = (1 << 1);
         const _2 = (1 << 2);
         const _3 = (1 << 3);
         const _4 = (1 << 4);
@@ -54,6 +109,19 @@ bitflags::bitflags! {
     }
 }
 
+impl From<&Material> for MaterialFlags {
+    fn from(material: &Material) -> Self {
+        let mut flags = MaterialFlags::NONE;
+        if material.normal_texture.is_some() {
+            flags |= MaterialFlags::USE_NORMAL_MAP;
+        }
+        if let AlphaMode::Mask(_) = material.alpha_mode {
+            flags |= MaterialFlags::ALPHA_MASK;
+        }
+        flags
+    }
+}
+
 pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
     device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
         label: Some("material_bind_group_layout"),
@@ -120,149 +188,192 @@ pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
                 ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                 count: None,
             },
+            // emissive_texture
+            wgpu::BindGroupLayoutEntry {
+                binding: 7,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 8,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
         ],
     })
 }
 
-#[allow(clippy::type_complexity)]
-pub fn create_material_uniform(
-    mut commands: Commands,
-    renderer: Res<WgpuRenderer>,
-    query: Query<(Entity, &Model), (Added<Model>, Without<GpuModelMaterials>)>,
-) {
-    for (entity, model) in query.iter() {
-        log::info!("New model detected");
+impl GpuMaterial {
+    pub fn new(
+        renderer: &WgpuRenderer,
+        sampler_cache: &mut SamplerCache,
+        material: &Material,
+    ) -> Self {
+        let uniform = MaterialUniform::from(material);
 
-        let mut gpu_materials = vec![];
-        for material in &model.materials {
-            let uniform = MaterialUniform {
-                base_color: material.base_color,
-                alpha: material.alpha,
-                gloss: material.gloss,
-                specular: material.specular,
-                flags: if material.normal_texture.is_some() {
-                    MaterialFlags::USE_NORMAL_MAP.bits()
-                } else {
-                    MaterialFlags::NONE.bits()
-                },
-            };
+        let mut uniform_buffer = encase::UniformBuffer::new(Vec::new());
+        uniform_buffer.write(&uniform).unwrap();
+
+        let buffer = renderer
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                contents: uniform_buffer.as_ref(),
+                label: None,
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            });
 
-            let byte_buffer = Vec::new();
-            let mut uniform_buffer = encase::UniformBuffer::new(byte_buffer);
-            uniform_buffer.write(&uniform).unwrap();
+        // White is the same in both color spaces so it works for every role
+        let default_white = MaterialTexture::from_color(Color::WHITE, ColorSpace::Linear);
+        let diffuse = &material.diffuse_texture;
+        let normal = material.normal_texture.as_ref().unwrap_or(&default_white);
+        let specular = material.specular_texture.as_ref().unwrap_or(&default_white);
+        let emissive = material.emissive_texture.as_ref().unwrap_or(&default_white);
+
+        for texture in [diffuse, normal, specular, emissive] {
+            sampler_cache.prepare(renderer, texture.sampler);
+        }
 
-            let buffer = renderer
-                .device
-                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    contents: uniform_buffer.as_ref(),
-                    label: None,
-                    usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-                });
+        let diffuse_texture = upload_texture(
+            renderer,
+            diffuse,
+            &format!("{}_diffuse_texture", material.name),
+        );
 
-            let diffuse_texture = Texture::from_image(
-                &renderer.device,
-                &renderer.queue,
-                &material.diffuse_texture,
-                Some(&format!("{}_diffuse_texture", material.name)),
-                None,
-            )
-            .unwrap();
+        let normal_texture = upload_texture(
+            renderer,
+            normal,
+            &format!("{}_normal_texture", material.name),
+        );
 
-            let default_white = image_from_color(Color::WHITE);
+        let specular_texture = upload_texture(
+            renderer,
+            specular,
+            &format!("{}_specular_texture", material.name),
+        );
 
-            let normal_texture = Texture::from_image(
-                &renderer.device,
-                &renderer.queue,
-                material.normal_texture.as_ref().unwrap_or(&default_white),
-                Some(&format!("{}_normal_texture", material.name)),
-                Some(wgpu::TextureFormat::Rgba8Unorm),
-            )
-            .unwrap();
+        let emissive_texture = upload_texture(
+            renderer,
+            emissive,
+            &format!("{}_emissive_texture", material.name),
+        );
 
-            let specular_texture = Texture::from_image(
-                &renderer.device,
-                &renderer.queue,
-                material.specular_texture.as_ref().unwrap_or(&default_white),
-                Some(&format!("{}_specular_texture", material.name)),
-                None,
-            )
-            .unwrap();
+        let bind_group = renderer
+            .device
+            .create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some(&format!("{}_material_bind_group", material.name)),
+                layout: &bind_group_layout(&renderer.device),
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: buffer.as_entire_binding(),
+                    },
+                    // diffuse
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::Sampler(
+                            sampler_cache.get(&diffuse.sampler),
+                        ),
+                    },
+                    // normal
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: wgpu::BindingResource::TextureView(&normal_texture.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 4,
+                        resource: wgpu::BindingResource::Sampler(
+                            sampler_cache.get(&normal.sampler),
+                        ),
+                    },
+                    // specular
+                    wgpu::BindGroupEntry {
+                        binding: 5,
+                        resource: wgpu::BindingResource::TextureView(&specular_texture.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 6,
+                        resource: wgpu::BindingResource::Sampler(
+                            sampler_cache.get(&specular.sampler),
+                        ),
+                    },
+                    // emissive
+                    wgpu::BindGroupEntry {
+                        binding: 7,
+                        resource: wgpu::BindingResource::TextureView(&emissive_texture.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 8,
+                        resource: wgpu::BindingResource::Sampler(
+                            sampler_cache.get(&emissive.sampler),
+                        ),
+                    },
+                ],
+            });
 
-            let bind_group = renderer
-                .device
-                .create_bind_group(&wgpu::BindGroupDescriptor {
-                    label: Some(&format!("{}_material_bind_group", material.name)),
-                    layout: &bind_group_layout(&renderer.device),
-                    entries: &[
-                        wgpu::BindGroupEntry {
-                            binding: 0,
-                            resource: buffer.as_entire_binding(),
-                        },
-                        // diffuse
-                        wgpu::BindGroupEntry {
-                            binding: 1,
-                            resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
-                        },
-                        wgpu::BindGroupEntry {
-                            binding: 2,
-                            resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
-                        },
-                        // normal
-                        wgpu::BindGroupEntry {
-                            binding: 3,
-                            resource: wgpu::BindingResource::TextureView(&normal_texture.view),
-                        },
-                        wgpu::BindGroupEntry {
-                            binding: 4,
-                            resource: wgpu::BindingResource::Sampler(&normal_texture.sampler),
-                        },
-                        // specular
-                        wgpu::BindGroupEntry {
-                            binding: 5,
-                            resource: wgpu::BindingResource::TextureView(&specular_texture.view),
-                        },
-                        wgpu::BindGroupEntry {
-                            binding: 6,
-                            resource: wgpu::BindingResource::Sampler(&specular_texture.sampler),
-                        },
-                    ],
-                });
-            gpu_materials.push((uniform, buffer, bind_group, uniform_buffer));
+        Self {
+            alpha_mode: material.alpha_mode,
+            double_sided: material.double_sided,
+            uniform,
+            buffer,
+            bind_group,
         }
-        commands.entity(entity).insert(GpuModelMaterials {
-            data: gpu_materials,
-        });
     }
 }
 
-pub fn update_material_buffer(
+/// Textures that can't be uploaded, like a compressed format the device doesn't support
+/// and that can't be decompressed on the cpu, are replaced by a white texture
+fn upload_texture(renderer: &WgpuRenderer, texture: &MaterialTexture, label: &str) -> Texture {
+    Texture::from_texture_data(
+        &renderer.device,
+        &renderer.queue,
+        &texture.image,
+        Some(label),
+        texture.color_space,
+    )
+    .unwrap_or_else(|err| {
+        log::error!("Failed to upload {label}, using a white texture instead: {err:?}");
+        let white = MaterialTexture::from_color(Color::WHITE, texture.color_space);
+        Texture::from_texture_data(
+            &renderer.device,
+            &renderer.queue,
+            &white.image,
+            Some(label),
+            white.color_space,
+        )
+        .expect("Failed to upload the fallback texture")
+    })
+}
+
+/// Creates the gpu data of new materials and recreates it when a material is modified
+pub fn prepare_materials(
     renderer: Res<WgpuRenderer>,
-    mut query: Query<(&Model, &mut GpuModelMaterials), Changed<Model>>,
+    mut events: EventReader<AssetEvent<Material>>,
+    materials: Res<Assets<Material>>,
+    mut gpu_materials: ResMut<GpuMaterials>,
+    mut sampler_cache: ResMut<SamplerCache>,
 ) {
-    for (model, mut gpu_materials) in query.iter_mut() {
-        for (i, mat) in model.materials.iter().enumerate() {
-            let u = MaterialUniform {
-                base_color: mat.base_color,
-                alpha: mat.alpha,
-                gloss: mat.gloss,
-                specular: mat.specular,
-                flags: if mat.normal_texture.is_some() {
-                    MaterialFlags::USE_NORMAL_MAP.bits()
-                } else {
-                    MaterialFlags::NONE.bits()
-                },
-            };
-            gpu_materials.data[i]
-                .3
-                .write(&u)
-                .expect("failed to write to material buffer");
-            // TODO I have no idea if this actually works since I don't change any material at runtime
-            renderer.queue.write_buffer(
-                &gpu_materials.data[i].1,
-                0,
-                gpu_materials.data[i].3.as_ref(),
-            );
-            gpu_materials.data[i].0 = u;
+    for event in events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                if let Some(material) = materials.get(handle) {
+                    log::info!("Preparing material {}", material.name);
+                    let gpu_material = GpuMaterial::new(&renderer, &mut sampler_cache, material);
+                    gpu_materials.0.insert(handle.clone_weak(), gpu_material);
+                }
+            }
+            AssetEvent::Removed { handle } => {
+                gpu_materials.0.remove(handle);
+            }
         }
     }
 }
@@ -1,12 +1,19 @@
 use bevy::prelude::*;
 use wgpu::util::DeviceExt;
 
-use crate::{camera::Camera, light::Light, renderer::WgpuRenderer};
+use crate::{
+    camera::Camera,
+    fog::{Fog, FogUniform},
+    light::Light,
+    renderer::WgpuRenderer,
+};
 
 pub struct CameraBuffer(pub wgpu::Buffer);
 
 pub struct LightBuffer(pub wgpu::Buffer);
 
+pub struct FogBuffer(pub wgpu::Buffer);
+
 pub struct MeshViewBindGroup(pub wgpu::BindGroup);
 
 pub struct MeshViewBindGroupLayout(pub wgpu::BindGroupLayout);
@@ -71,6 +78,7 @@ pub fn setup_mesh_view_bind_group(
     renderer: Res<WgpuRenderer>,
     camera_uniform: Res<CameraUniform>,
     light: Query<&Light>,
+    fog: Res<Fog>,
 ) {
     let device = &renderer.device;
 
@@ -99,6 +107,17 @@ pub fn setup_mesh_view_bind_group(
                 },
                 count: None,
             },
+            // Fog
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
         ],
     });
 
@@ -115,6 +134,12 @@ pub fn setup_mesh_view_bind_group(
         usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
     });
 
+    let fog_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Fog Buffer"),
+        contents: &FogUniform::from(&*fog).to_bytes(),
+        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+    });
+
     let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
         label: Some("camera_bind_group"),
         layout: &mesh_view_layout,
@@ -127,11 +152,16 @@ pub fn setup_mesh_view_bind_group(
                 binding: 1,
                 resource: light_buffer.as_entire_binding(),
             },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: fog_buffer.as_entire_binding(),
+            },
         ],
     });
 
     commands.insert_resource(CameraBuffer(camera_buffer));
     commands.insert_resource(LightBuffer(light_buffer));
+    commands.insert_resource(FogBuffer(fog_buffer));
     commands.insert_resource(MeshViewBindGroupLayout(mesh_view_layout));
     commands.insert_resource(MeshViewBindGroup(bind_group));
 }
@@ -0,0 +1,163 @@
+use std::ops::Range;
+
+use bevy::prelude::{Commands, Component, Entity, Query, Res, ResMut, Without};
+
+use crate::{
+    bounds::Frustum, camera::Camera, instances::Instances, light::Light, model::Model,
+    transform::Transform,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct CullingSettings {
+    pub enabled: bool,
+}
+
+impl Default for CullingSettings {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+/// The number of mesh instances drawn in the last frame.
+/// Lights aren't culled so they aren't counted
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CullingStats {
+    pub visible_draws: u32,
+    pub total_draws: u32,
+}
+
+/// The instances of each mesh of a `Model` that are inside the view frustum.
+/// Consecutive visible instances are merged in a single range so they can be drawn together
+#[derive(Component, Default)]
+pub struct VisibleInstances {
+    pub meshes: Vec<Vec<Range<u32>>>,
+}
+
+impl VisibleInstances {
+    /// The instance ranges to draw for a mesh.
+    /// Every instance is drawn if the visibility wasn't computed yet
+    pub fn ranges<'a>(
+        visible: Option<&'a Self>,
+        mesh: usize,
+        instances: &'a Range<u32>,
+    ) -> &'a [Range<u32>] {
+        match visible.and_then(|visible| visible.meshes.get(mesh)) {
+            Some(ranges) => ranges,
+            None => std::slice::from_ref(instances),
+        }
+    }
+}
+
+/// Tests the bounding sphere of every instance of a `Model` against the view frustum,
+/// then the aabb of each of its meshes
+pub fn update_visibility(
+    mut commands: Commands,
+    camera: Res<Camera>,
+    settings: Res<CullingSettings>,
+    mut stats: ResMut<CullingStats>,
+    mut query: Query<
+        (
+            Entity,
+            &Model,
+            Option<&Transform>,
+            Option<&Instances>,
+            Option<&mut VisibleInstances>,
+        ),
+        Without<Light>,
+    >,
+) {
+    let frustum = Frustum::from_view_projection(&camera.build_view_projection_matrix());
+
+    *stats = CullingStats::default();
+    for (entity, model, transform, instances, visible) in query.iter_mut() {
+        let transforms = if let Some(transform) = transform {
+            vec![transform.compute_matrix()]
+        } else if let Some(instances) = instances {
+            instances.0.iter().map(Transform::compute_matrix).collect()
+        } else {
+            continue;
+        };
+
+        let model_sphere = model.bounding_sphere();
+        let mut meshes = vec![vec![]; model.meshes.len()];
+        for (instance, matrix) in transforms.iter().enumerate() {
+            if settings.enabled && !frustum.intersects_sphere(&model_sphere.transform(matrix)) {
+                continue;
+            }
+            let instance = instance as u32;
+            for (mesh, ranges) in model.meshes.iter().zip(&mut meshes) {
+                if settings.enabled && !frustum.intersects_aabb(&mesh.aabb.transform(matrix)) {
+                    continue;
+                }
+                push_instance(ranges, instance);
+            }
+        }
+
+        stats.total_draws += (transforms.len() * model.meshes.len()) as u32;
+        stats.visible_draws += meshes
+            .iter()
+            .flatten()
+            .map(|range| range.len() as u32)
+            .sum::<u32>();
+
+        match visible {
+            Some(mut visible) => visible.meshes = meshes,
+            None => {
+                commands.entity(entity).insert(VisibleInstances { meshes });
+            }
+        }
+    }
+}
+
+/// Adds a visible instance, extending the last range if the instance follows it
+fn push_instance(ranges: &mut Vec<Range<u32>>, instance: u32) {
+    match ranges.last_mut() {
+        Some(Range { end, .. }) if *end == instance => *end += 1,
+        _ => ranges.push(instance..instance + 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge(instances: &[u32]) -> Vec<Range<u32>> {
+        let mut ranges = vec![];
+        for instance in instances {
+            push_instance(&mut ranges, *instance);
+        }
+        ranges
+    }
+
+    #[test]
+    fn consecutive_instances_are_merged() {
+        assert_eq!(merge(&[]), vec![]);
+        assert_eq!(merge(&[3]), vec![3..4]);
+        assert_eq!(merge(&[0, 1, 2, 3]), vec![0..4]);
+        assert_eq!(merge(&[0, 1, 3, 4, 5, 8]), vec![0..2, 3..6, 8..9]);
+        assert_eq!(merge(&[1, 3, 5]), vec![1..2, 3..4, 5..6]);
+    }
+
+    #[test]
+    fn ranges_default_to_every_instance() {
+        let instances = 0..10;
+        assert_eq!(
+            VisibleInstances::ranges(None, 0, &instances),
+            std::slice::from_ref(&instances)
+        );
+
+        let visible = VisibleInstances {
+            meshes: vec![vec![1..3, 5..6], vec![]],
+        };
+        assert_eq!(
+            VisibleInstances::ranges(Some(&visible), 0, &instances),
+            &[1..3, 5..6]
+        );
+        assert!(VisibleInstances::ranges(Some(&visible), 1, &instances).is_empty());
+        // Meshes added since the visibility was computed are drawn
+        assert_eq!(
+            VisibleInstances::ranges(Some(&visible), 2, &instances),
+            std::slice::from_ref(&instances)
+        );
+    }
+}
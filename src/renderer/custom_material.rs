@@ -0,0 +1,520 @@
+use std::marker::PhantomData;
+
+use bevy::{
+    asset::Asset,
+    math::Mat4,
+    prelude::*,
+    render::render_resource::{encase, encase::internal::WriteInto, ShaderType},
+    utils::HashMap,
+};
+use image::RgbaImage;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    instances::InstanceBuffer,
+    light::Light,
+    mesh::MeshVertexLayout,
+    model::Model,
+    texture::{SamplerCache, SamplerDescriptor, Texture},
+    transform::{Transform, TransformRaw},
+    Instances,
+};
+
+use super::{
+    bind_groups::mesh_view::MeshViewBindGroupLayout,
+    culling::VisibleInstances,
+    render_phase_3d::{instance_matrices, mesh_layouts, LayoutPipelines, TransparentDraw},
+    shader_preprocessor::preprocess_shader,
+    WgpuRenderer,
+};
+
+/// A material with its own uniform, textures and shader.
+///
+/// The shader source is prepended with `custom_material_prelude.wgsl` which declares the
+/// mesh_view bindings in group 0, the `Vertex` and `InstanceInput` structs and a few helpers.
+/// The shader is preprocessed with the `VERTEX_<name>` defs of the attributes of each mesh.
+/// The shader must declare a `vertex` and a `fragment` entry point and its bindings in group 1:
+/// binding 0 is the uniform and every texture uses the next 2 bindings for the texture and its sampler.
+pub trait CustomMaterial: Asset {
+    type Uniform: ShaderType + WriteInto;
+
+    /// The number of textures returned by `textures()`. It's used to generate the bind group layout.
+    const TEXTURE_COUNT: usize = 0;
+
+    fn shader() -> &'static str;
+
+    fn uniform(&self) -> Self::Uniform;
+
+    fn textures(&self) -> Vec<&RgbaImage> {
+        vec![]
+    }
+
+    /// Transparent materials are alpha blended and don't write to the depth buffer.
+    /// They are sorted with the other transparent meshes, opaque materials are also drawn
+    /// to the depth prepass using only their vertex shader
+    fn transparent() -> bool {
+        false
+    }
+}
+
+/// Registers the asset and the systems required to draw entities with a `Model` and a `Handle<M>`
+pub struct CustomMaterialPlugin<M: CustomMaterial>(PhantomData<M>);
+
+impl<M: CustomMaterial> Default for CustomMaterialPlugin<M> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<M: CustomMaterial> Plugin for CustomMaterialPlugin<M> {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<M>()
+            .init_resource::<GpuCustomMaterials<M>>()
+            .add_system(prepare_custom_materials::<M>);
+
+        // The pipelines are created with the RenderPhase3d
+        app.world
+            .get_resource_or_insert_with(CustomMaterialPipelines::default)
+            .0
+            .push(|world| Box::new(CustomMaterialPipeline::<M>::from_world(world)));
+    }
+}
+
+/// The constructors of the pipeline of every registered `CustomMaterial`
+#[derive(Default)]
+pub struct CustomMaterialPipelines(pub Vec<fn(&mut World) -> Box<dyn DrawCustomMaterial>>);
+
+pub trait DrawCustomMaterial: Send + Sync {
+    fn update(&mut self, world: &mut World);
+    /// Draws the depth of the opaque meshes
+    fn draw_depth_prepass<'w>(
+        &'w self,
+        world: &'w World,
+        render_pass: &mut wgpu::RenderPass<'w>,
+        mesh_view_bind_group: &'w wgpu::BindGroup,
+    );
+    /// Draws the opaque meshes
+    fn draw<'w>(
+        &'w self,
+        world: &'w World,
+        render_pass: &mut wgpu::RenderPass<'w>,
+        mesh_view_bind_group: &'w wgpu::BindGroup,
+    );
+    /// Collects every visible instance of the transparent meshes so they can be sorted
+    fn transparent_draws<'w>(&'w self, world: &'w World, view: &Mat4) -> Vec<TransparentDraw<'w>>;
+}
+
+pub struct GpuCustomMaterial {
+    pub buffer: wgpu::Buffer,
+    pub bind_group: wgpu::BindGroup,
+}
+
+pub struct GpuCustomMaterials<M: CustomMaterial>(pub HashMap<Handle<M>, GpuCustomMaterial>);
+
+impl<M: CustomMaterial> Default for GpuCustomMaterials<M> {
+    fn default() -> Self {
+        Self(HashMap::default())
+    }
+}
+
+pub fn bind_group_layout<M: CustomMaterial>(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    let mut entries = vec![wgpu::BindGroupLayoutEntry {
+        binding: 0,
+        visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }];
+    for i in 0..M::TEXTURE_COUNT as u32 {
+        entries.push(wgpu::BindGroupLayoutEntry {
+            binding: 1 + i * 2,
+            visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        });
+        entries.push(wgpu::BindGroupLayoutEntry {
+            binding: 2 + i * 2,
+            visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+            ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+            count: None,
+        });
+    }
+
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some("custom_material_bind_group_layout"),
+        entries: &entries,
+    })
+}
+
+impl GpuCustomMaterial {
+    /// Every texture is sampled with the default `SamplerDescriptor`
+    pub fn new<M: CustomMaterial>(
+        renderer: &WgpuRenderer,
+        sampler_cache: &mut SamplerCache,
+        material: &M,
+    ) -> anyhow::Result<Self> {
+        let images = material.textures();
+        if images.len() != M::TEXTURE_COUNT {
+            anyhow::bail!(
+                "CustomMaterial::textures() returned {} textures instead of TEXTURE_COUNT {}",
+                images.len(),
+                M::TEXTURE_COUNT
+            );
+        }
+
+        let mut uniform_buffer = encase::UniformBuffer::new(Vec::new());
+        uniform_buffer.write(&material.uniform()).unwrap();
+
+        let buffer = renderer
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                contents: uniform_buffer.as_ref(),
+                label: Some("custom_material_buffer"),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            });
+
+        let textures = images
+            .into_iter()
+            .map(|image| {
+                Texture::from_image(
+                    &renderer.device,
+                    &renderer.queue,
+                    image,
+                    Some("custom_material_texture"),
+                    None,
+                )
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        sampler_cache.prepare(renderer, SamplerDescriptor::default());
+        let sampler = sampler_cache.get(&SamplerDescriptor::default());
+
+        let mut entries = vec![wgpu::BindGroupEntry {
+            binding: 0,
+            resource: buffer.as_entire_binding(),
+        }];
+        for (i, texture) in textures.iter().enumerate() {
+            let i = i as u32;
+            entries.push(wgpu::BindGroupEntry {
+                binding: 1 + i * 2,
+                resource: wgpu::BindingResource::TextureView(&texture.view),
+            });
+            entries.push(wgpu::BindGroupEntry {
+                binding: 2 + i * 2,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            });
+        }
+
+        let bind_group = renderer
+            .device
+            .create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("custom_material_bind_group"),
+                layout: &bind_group_layout::<M>(&renderer.device),
+                entries: &entries,
+            });
+
+        Ok(Self { buffer, bind_group })
+    }
+}
+
+pub fn prepare_custom_materials<M: CustomMaterial>(
+    renderer: Res<WgpuRenderer>,
+    mut events: EventReader<AssetEvent<M>>,
+    materials: Res<Assets<M>>,
+    mut gpu_materials: ResMut<GpuCustomMaterials<M>>,
+    mut sampler_cache: ResMut<SamplerCache>,
+) {
+    for event in events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                let material = match materials.get(handle) {
+                    Some(material) => material,
+                    None => continue,
+                };
+                match GpuCustomMaterial::new(&renderer, &mut sampler_cache, material) {
+                    Ok(gpu_material) => {
+                        gpu_materials.0.insert(handle.clone_weak(), gpu_material);
+                    }
+                    Err(err) => {
+                        log::error!("Failed to prepare {}: {err:?}", std::any::type_name::<M>())
+                    }
+                }
+            }
+            AssetEvent::Removed { handle } => {
+                gpu_materials.0.remove(handle);
+            }
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub struct CustomMaterialPipeline<M: CustomMaterial> {
+    pipeline_layout: wgpu::PipelineLayout,
+    pub render_pipeline: LayoutPipelines<wgpu::RenderPipeline>,
+    /// Only used by opaque materials
+    pub depth_prepass_pipeline: LayoutPipelines<wgpu::RenderPipeline>,
+    pub query: QueryState<
+        (
+            &'static Model,
+            &'static Handle<M>,
+            &'static InstanceBuffer,
+            Option<&'static Transform>,
+            Option<&'static Instances>,
+            Option<&'static VisibleInstances>,
+        ),
+        Without<Light>,
+    >,
+}
+
+impl<M: CustomMaterial> CustomMaterialPipeline<M> {
+    pub fn from_world(world: &mut World) -> Self {
+        let renderer = world.resource::<WgpuRenderer>();
+        let mesh_view_layout = world.resource::<MeshViewBindGroupLayout>();
+
+        let material_layout = bind_group_layout::<M>(&renderer.device);
+        let pipeline_layout =
+            renderer
+                .device
+                .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: Some("Custom Material Pipeline Layout"),
+                    bind_group_layouts: &[&mesh_view_layout.0, &material_layout],
+                    push_constant_ranges: &[],
+                });
+
+        // The pipelines are created in update() once the layout of the meshes is known
+        Self {
+            pipeline_layout,
+            render_pipeline: LayoutPipelines::default(),
+            depth_prepass_pipeline: LayoutPipelines::default(),
+            query: world.query_filtered(),
+        }
+    }
+
+    fn draw_meshes<'w>(
+        &'w self,
+        world: &'w World,
+        render_pass: &mut wgpu::RenderPass<'w>,
+        mesh_view_bind_group: &'w wgpu::BindGroup,
+        pipelines: &'w LayoutPipelines<wgpu::RenderPipeline>,
+    ) {
+        let gpu_materials = world.resource::<GpuCustomMaterials<M>>();
+        for (model, material, instance_buffer, _, instances, visible) in
+            self.query.iter_manual(world)
+        {
+            let gpu_material = match gpu_materials.0.get(material) {
+                Some(gpu_material) => gpu_material,
+                None => continue,
+            };
+            let instances = 0..instances.map(|i| i.0.len() as u32).unwrap_or(1);
+            render_pass.set_vertex_buffer(1, instance_buffer.0.slice(..));
+            for (i, mesh) in model.meshes.iter().enumerate() {
+                let ranges = VisibleInstances::ranges(visible, i, &instances);
+                if ranges.is_empty() {
+                    continue;
+                }
+                let pipeline = match pipelines.get(&mesh.layout) {
+                    Some(pipeline) => pipeline,
+                    None => continue,
+                };
+                render_pass.set_pipeline(pipeline);
+                for range in ranges {
+                    mesh.draw_instanced(
+                        render_pass,
+                        range.clone(),
+                        &gpu_material.bind_group,
+                        mesh_view_bind_group,
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl<M: CustomMaterial> DrawCustomMaterial for CustomMaterialPipeline<M> {
+    fn update(&mut self, world: &mut World) {
+        self.query.update_archetypes(world);
+
+        let world: &World = world;
+        let renderer = world.resource::<WgpuRenderer>();
+        let layouts = mesh_layouts(self.query.iter_manual(world).map(|(model, ..)| model));
+        let shader = |layout: &MeshVertexLayout| {
+            let shader = format!(
+                "{}\n{}",
+                include_str!("shaders/custom_material_prelude.wgsl"),
+                M::shader()
+            );
+            preprocess_shader(&shader, &layout.shader_defs())
+        };
+        for layout in layouts {
+            let pipeline_layout = &self.pipeline_layout;
+            self.render_pipeline.prepare(layout, |layout| {
+                renderer.create_render_pipeline(
+                    &format!("{} Pipeline", std::any::type_name::<M>()),
+                    &shader(layout),
+                    pipeline_layout,
+                    &[layout.buffer_layout(), TransformRaw::layout()],
+                    Some(wgpu::DepthStencilState {
+                        format: Texture::DEPTH_FORMAT,
+                        depth_write_enabled: !M::transparent(),
+                        depth_compare: wgpu::CompareFunction::LessEqual,
+                        stencil: wgpu::StencilState::default(),
+                        bias: wgpu::DepthBiasState::default(),
+                    }),
+                    if M::transparent() {
+                        wgpu::BlendState::ALPHA_BLENDING
+                    } else {
+                        wgpu::BlendState::REPLACE
+                    },
+                    Some(wgpu::Face::Back),
+                )
+            });
+            if !M::transparent() {
+                self.depth_prepass_pipeline.prepare(layout, |layout| {
+                    create_depth_prepass_pipeline::<M>(
+                        renderer,
+                        pipeline_layout,
+                        &shader(layout),
+                        layout,
+                    )
+                });
+            }
+        }
+    }
+
+    fn draw_depth_prepass<'w>(
+        &'w self,
+        world: &'w World,
+        render_pass: &mut wgpu::RenderPass<'w>,
+        mesh_view_bind_group: &'w wgpu::BindGroup,
+    ) {
+        if !M::transparent() {
+            self.draw_meshes(
+                world,
+                render_pass,
+                mesh_view_bind_group,
+                &self.depth_prepass_pipeline,
+            );
+        }
+    }
+
+    fn draw<'w>(
+        &'w self,
+        world: &'w World,
+        render_pass: &mut wgpu::RenderPass<'w>,
+        mesh_view_bind_group: &'w wgpu::BindGroup,
+    ) {
+        if !M::transparent() {
+            self.draw_meshes(
+                world,
+                render_pass,
+                mesh_view_bind_group,
+                &self.render_pipeline,
+            );
+        }
+    }
+
+    fn transparent_draws<'w>(&'w self, world: &'w World, view: &Mat4) -> Vec<TransparentDraw<'w>> {
+        if !M::transparent() {
+            return vec![];
+        }
+
+        let gpu_materials = world.resource::<GpuCustomMaterials<M>>();
+        let mut draws = vec![];
+        for (model, material, instance_buffer, transform, instances, visible) in
+            self.query.iter_manual(world)
+        {
+            let gpu_material = match gpu_materials.0.get(material) {
+                Some(gpu_material) => gpu_material,
+                None => continue,
+            };
+            let transforms = match instance_matrices(transform, instances) {
+                Some(transforms) => transforms,
+                None => continue,
+            };
+
+            let all_instances = 0..transforms.len() as u32;
+            for (i, mesh) in model.meshes.iter().enumerate() {
+                let pipeline = match self.render_pipeline.get(&mesh.layout) {
+                    Some(pipeline) => pipeline,
+                    None => continue,
+                };
+                let visible_instances = VisibleInstances::ranges(visible, i, &all_instances)
+                    .iter()
+                    .cloned()
+                    .flatten();
+                for instance in visible_instances {
+                    draws.push(TransparentDraw {
+                        distance: TransparentDraw::view_distance(
+                            view,
+                            &transforms[instance as usize],
+                            mesh,
+                        ),
+                        mesh,
+                        material_bind_group: &gpu_material.bind_group,
+                        double_sided: false,
+                        instance_buffer: &instance_buffer.0,
+                        instance,
+                        pipeline: Some(pipeline),
+                    });
+                }
+            }
+        }
+        draws
+    }
+}
+
+/// Only runs the vertex shader of the material, the fragment shader isn't needed to write the depth
+fn create_depth_prepass_pipeline<M: CustomMaterial>(
+    renderer: &WgpuRenderer,
+    pipeline_layout: &wgpu::PipelineLayout,
+    shader: &str,
+    layout: &MeshVertexLayout,
+) -> wgpu::RenderPipeline {
+    let label = format!("{} Depth Prepass Pipeline", std::any::type_name::<M>());
+    let shader = renderer
+        .device
+        .create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some(&format!("{label} Shader")),
+            source: wgpu::ShaderSource::Wgsl(shader.into()),
+        });
+    renderer
+        .device
+        .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(&label),
+            layout: Some(pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vertex",
+                buffers: &[layout.buffer_layout(), TransformRaw::layout()],
+            },
+            fragment: None,
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: Some(wgpu::Face::Back),
+                polygon_mode: wgpu::PolygonMode::Fill,
+                unclipped_depth: false,
+                conservative: false,
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+        })
+}
@@ -63,6 +63,7 @@ struct DepthPassMaterial {
 
 pub struct DepthPass {
     layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
     bind_group: wgpu::BindGroup,
     vertex_buffer: wgpu::Buffer,
     index_buffer: wgpu::Buffer,
@@ -73,10 +74,15 @@ pub struct DepthPass {
 impl DepthPass {
     pub fn new(renderer: &WgpuRenderer, texture: &Texture) -> Self {
         let layout = DepthPass::bind_group_layout(&renderer.device);
+        let sampler = renderer.device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Depth Pass Sampler"),
+            ..Default::default()
+        });
         let bind_group = DepthPass::bind_group(
             &renderer.device,
             &layout,
             texture,
+            &sampler,
             DepthPassMaterial {
                 near: DEFAULT_NEAR,
                 far: DEFAULT_FAR,
@@ -114,10 +120,12 @@ impl DepthPass {
             &[Vertex::layout()],
             None,
             wgpu::BlendState::REPLACE,
+            Some(wgpu::Face::Back),
         );
 
         Self {
             layout,
+            sampler,
             bind_group,
             vertex_buffer,
             index_buffer,
@@ -131,6 +139,7 @@ impl DepthPass {
             device,
             &self.layout,
             texture,
+            &self.sampler,
             DepthPassMaterial {
                 near: DEFAULT_NEAR,
                 far: DEFAULT_FAR,
@@ -196,6 +205,7 @@ impl DepthPass {
         device: &wgpu::Device,
         layout: &wgpu::BindGroupLayout,
         texture: &Texture,
+        sampler: &wgpu::Sampler,
         material: DepthPassMaterial,
     ) -> wgpu::BindGroup {
         let byte_buffer = [0u8; std::mem::size_of::<f32>() * 2];
@@ -222,7 +232,7 @@ impl DepthPass {
                 },
                 wgpu::BindGroupEntry {
                     binding: 2,
-                    resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                    resource: wgpu::BindingResource::Sampler(sampler),
                 },
             ],
         })
@@ -4,12 +4,17 @@ use winit::window::Window;
 
 use crate::egui_plugin::EguiRenderPhase;
 
-use self::render_phase_3d::RenderPhase3d;
+use self::{render_phase_3d::RenderPhase3d, shader_preprocessor::preprocess_shader};
 
 pub mod bind_groups;
+pub mod culling;
+pub mod custom_material;
 pub mod depth_pass;
+pub mod oit_pass;
 pub mod plugin;
 pub mod render_phase_3d;
+pub mod shader_preprocessor;
+pub mod ssao_pass;
 
 // NOTE: Is this trait necessary?
 pub trait RenderPhase {
@@ -23,6 +28,8 @@ pub struct WgpuRenderer {
     pub queue: wgpu::Queue,
     pub config: wgpu::SurfaceConfiguration,
     pub size: winit::dpi::PhysicalSize<u32>,
+    /// Capabilities missing on some backends, like anisotropic filtering
+    pub downlevel_flags: wgpu::DownlevelFlags,
 }
 
 impl WgpuRenderer {
@@ -43,7 +50,11 @@ impl WgpuRenderer {
         let (device, queue) = adapter
             .request_device(
                 &wgpu::DeviceDescriptor {
-                    features: wgpu::Features::empty(),
+                    // Optional features are only enabled if the adapter supports them
+                    features: adapter.features()
+                        & (wgpu::Features::TEXTURE_COMPRESSION_BC
+                            | wgpu::Features::TEXTURE_COMPRESSION_ETC2
+                            | wgpu::Features::TEXTURE_COMPRESSION_ASTC_LDR),
                     limits: wgpu::Limits::default(),
                     label: None,
                 },
@@ -67,6 +78,7 @@ impl WgpuRenderer {
             queue,
             config,
             size,
+            downlevel_flags: adapter.get_downlevel_properties().flags,
         }
     }
 
@@ -79,6 +91,7 @@ impl WgpuRenderer {
         vertex_layouts: &[wgpu::VertexBufferLayout],
         depth_stencil: Option<wgpu::DepthStencilState>,
         blend: wgpu::BlendState,
+        cull_mode: Option<wgpu::Face>,
     ) -> wgpu::RenderPipeline {
         let shader = self
             .device
@@ -108,7 +121,7 @@ impl WgpuRenderer {
                     topology: wgpu::PrimitiveTopology::TriangleList,
                     strip_index_format: None,
                     front_face: wgpu::FrontFace::Ccw,
-                    cull_mode: Some(wgpu::Face::Back),
+                    cull_mode,
                     polygon_mode: wgpu::PolygonMode::Fill,
                     unclipped_depth: false,
                     conservative: false,
@@ -123,6 +136,47 @@ impl WgpuRenderer {
             })
     }
 
+    /// Creates a pipeline that draws a single triangle covering the screen.
+    /// The shader is preprocessed, it can `#import fullscreen` to generate the vertices from the vertex_index
+    pub fn create_fullscreen_pipeline(
+        &self,
+        label: &str,
+        shader: &str,
+        pipeline_layout: &wgpu::PipelineLayout,
+        format: wgpu::TextureFormat,
+        blend: Option<wgpu::BlendState>,
+    ) -> wgpu::RenderPipeline {
+        let shader = self
+            .device
+            .create_shader_module(&wgpu::ShaderModuleDescriptor {
+                label: Some(&format!("{label} Shader")),
+                source: wgpu::ShaderSource::Wgsl(preprocess_shader(shader, &[]).into()),
+            });
+        self.device
+            .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(label),
+                layout: Some(pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vertex",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fragment",
+                    targets: &[wgpu::ColorTargetState {
+                        format,
+                        blend,
+                        write_mask: wgpu::ColorWrites::ALL,
+                    }],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            })
+    }
+
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
         if new_size.width > 0 && new_size.height > 0 {
             self.size = new_size;
@@ -0,0 +1,253 @@
+use crate::{
+    mesh::MeshVertexLayout, renderer::WgpuRenderer, texture::Texture, transform::TransformRaw,
+};
+
+use super::{
+    render_phase_3d::{CullVariants, LayoutPipelines},
+    shader_preprocessor::preprocess_shader,
+};
+
+const ACCUM_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+const REVEALAGE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R8Unorm;
+
+/// Weighted blended order independent transparency.
+/// Transparent meshes are accumulated in 2 targets with `pipeline`,
+/// then `composite` blends the result on top of the opaque meshes.
+/// <https://jcgt.org/published/0002/02/09/>
+pub struct OitPass {
+    pub pipeline: LayoutPipelines<CullVariants>,
+    composite_layout: wgpu::BindGroupLayout,
+    composite_pipeline: wgpu::RenderPipeline,
+    pub accum_texture: Texture,
+    pub revealage_texture: Texture,
+    composite_bind_group: wgpu::BindGroup,
+}
+
+impl OitPass {
+    pub fn new(renderer: &WgpuRenderer) -> Self {
+        let device = &renderer.device;
+
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let composite_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("oit_composite_bind_group_layout"),
+            entries: &[texture_entry(0), texture_entry(1)],
+        });
+
+        let composite_pipeline = renderer.create_fullscreen_pipeline(
+            "OIT Composite Pipeline",
+            include_str!("shaders/oit_composite.wgsl"),
+            &device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("OIT Composite Pipeline Layout"),
+                bind_group_layouts: &[&composite_layout],
+                push_constant_ranges: &[],
+            }),
+            renderer.config.format,
+            Some(wgpu::BlendState::ALPHA_BLENDING),
+        );
+
+        let (accum_texture, revealage_texture) = create_targets(device, &renderer.config);
+        let composite_bind_group = composite_bind_group(
+            device,
+            &composite_layout,
+            &accum_texture,
+            &revealage_texture,
+        );
+
+        Self {
+            pipeline: LayoutPipelines::default(),
+            composite_layout,
+            composite_pipeline,
+            accum_texture,
+            revealage_texture,
+            composite_bind_group,
+        }
+    }
+
+    /// Creates the accumulation pipeline for the vertex layout if it doesn't exist yet.
+    /// The pipeline_layout must be compatible with the one used for the transparent meshes
+    pub fn prepare_pipeline(
+        &mut self,
+        renderer: &WgpuRenderer,
+        pipeline_layout: &wgpu::PipelineLayout,
+        layout: &MeshVertexLayout,
+    ) {
+        let device = &renderer.device;
+        self.pipeline.prepare(layout, |layout| {
+            let shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                label: Some("OIT Shader"),
+                source: wgpu::ShaderSource::Wgsl(
+                    preprocess_shader(include_str!("shaders/shader.wgsl"), &layout.shader_defs())
+                        .into(),
+                ),
+            });
+            CullVariants::new(|cull_mode| {
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some("OIT Pipeline"),
+                    layout: Some(pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &shader,
+                        entry_point: "vertex",
+                        buffers: &[layout.buffer_layout(), TransformRaw::layout()],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &shader,
+                        entry_point: "fragment_oit",
+                        targets: &[
+                            // Sum of the weighted colors
+                            wgpu::ColorTargetState {
+                                format: ACCUM_FORMAT,
+                                blend: Some(wgpu::BlendState {
+                                    color: wgpu::BlendComponent {
+                                        src_factor: wgpu::BlendFactor::One,
+                                        dst_factor: wgpu::BlendFactor::One,
+                                        operation: wgpu::BlendOperation::Add,
+                                    },
+                                    alpha: wgpu::BlendComponent {
+                                        src_factor: wgpu::BlendFactor::One,
+                                        dst_factor: wgpu::BlendFactor::One,
+                                        operation: wgpu::BlendOperation::Add,
+                                    },
+                                }),
+                                write_mask: wgpu::ColorWrites::ALL,
+                            },
+                            // Product of (1 - alpha)
+                            wgpu::ColorTargetState {
+                                format: REVEALAGE_FORMAT,
+                                blend: Some(wgpu::BlendState {
+                                    color: wgpu::BlendComponent {
+                                        src_factor: wgpu::BlendFactor::Zero,
+                                        dst_factor: wgpu::BlendFactor::OneMinusSrc,
+                                        operation: wgpu::BlendOperation::Add,
+                                    },
+                                    alpha: wgpu::BlendComponent {
+                                        src_factor: wgpu::BlendFactor::Zero,
+                                        dst_factor: wgpu::BlendFactor::OneMinusSrc,
+                                        operation: wgpu::BlendOperation::Add,
+                                    },
+                                }),
+                                write_mask: wgpu::ColorWrites::ALL,
+                            },
+                        ],
+                    }),
+                    primitive: wgpu::PrimitiveState {
+                        topology: wgpu::PrimitiveTopology::TriangleList,
+                        strip_index_format: None,
+                        front_face: wgpu::FrontFace::Ccw,
+                        cull_mode,
+                        polygon_mode: wgpu::PolygonMode::Fill,
+                        unclipped_depth: false,
+                        conservative: false,
+                    },
+                    depth_stencil: Some(wgpu::DepthStencilState {
+                        format: Texture::DEPTH_FORMAT,
+                        depth_write_enabled: false,
+                        depth_compare: wgpu::CompareFunction::Less,
+                        stencil: wgpu::StencilState::default(),
+                        bias: wgpu::DepthBiasState::default(),
+                    }),
+                    multisample: wgpu::MultisampleState {
+                        count: 1,
+                        mask: !0,
+                        alpha_to_coverage_enabled: false,
+                    },
+                    multiview: None,
+                })
+            });
+        });
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) {
+        let (accum_texture, revealage_texture) = create_targets(device, config);
+        self.composite_bind_group = composite_bind_group(
+            device,
+            &self.composite_layout,
+            &accum_texture,
+            &revealage_texture,
+        );
+        self.accum_texture = accum_texture;
+        self.revealage_texture = revealage_texture;
+    }
+
+    /// The color attachments used to accumulate the transparent meshes.
+    /// The accumulation is cleared to 0 and the revealage to 1
+    pub fn color_attachments(&self) -> [wgpu::RenderPassColorAttachment; 2] {
+        [
+            wgpu::RenderPassColorAttachment {
+                view: &self.accum_texture.view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                    store: true,
+                },
+            },
+            wgpu::RenderPassColorAttachment {
+                view: &self.revealage_texture.view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                    store: true,
+                },
+            },
+        ]
+    }
+
+    /// Blends the accumulated transparent meshes on top of the view
+    pub fn composite(&self, view: &wgpu::TextureView, encoder: &mut wgpu::CommandEncoder) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("OIT Composite Render Pass"),
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+        render_pass.set_pipeline(&self.composite_pipeline);
+        render_pass.set_bind_group(0, &self.composite_bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+fn create_targets(
+    device: &wgpu::Device,
+    config: &wgpu::SurfaceConfiguration,
+) -> (Texture, Texture) {
+    (
+        Texture::create_render_target(device, config, ACCUM_FORMAT, "oit_accum_texture"),
+        Texture::create_render_target(device, config, REVEALAGE_FORMAT, "oit_revealage_texture"),
+    )
+}
+
+fn composite_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    accum_texture: &Texture,
+    revealage_texture: &Texture,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("oit_composite_bind_group"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&accum_texture.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(&revealage_texture.view),
+            },
+        ],
+    })
+}
@@ -5,15 +5,19 @@ use winit::dpi::PhysicalSize;
 use crate::{
     camera::{Camera, CameraPlugin},
     egui_plugin::EguiRenderPhase,
+    fog::{self, Fog},
     instances,
+    model::Material,
     renderer::{RenderPhase, WgpuRenderer},
-    texture::Texture,
+    texture::{DeviceFeatures, SamplerCache, Texture},
 };
 
 use super::{
-    bind_groups::{self, mesh_view::CameraUniform},
+    bind_groups::{self, material::GpuMaterials, mesh_view::CameraUniform},
+    culling::{self, CullingSettings, CullingStats},
     depth_pass::DepthPass,
     render_phase_3d::{DepthTexture, RenderPhase3d},
+    ssao_pass::{self, SsaoPass, SsaoSettings},
 };
 
 pub struct WgpuRendererPlugin;
@@ -22,6 +26,16 @@ impl Plugin for WgpuRendererPlugin {
         app
             // Add the camera plugin here because it's required for the renderer to work
             .add_plugin(CameraPlugin)
+            // Requires the AssetPlugin to be added before this plugin
+            .add_asset::<Material>()
+            .init_resource::<GpuMaterials>()
+            .init_resource::<SamplerCache>()
+            // The asset loaders get it when they are created, so it must exist before the renderer
+            .init_resource::<DeviceFeatures>()
+            .init_resource::<Fog>()
+            .init_resource::<SsaoSettings>()
+            .init_resource::<CullingSettings>()
+            .init_resource::<CullingStats>()
             // This startup system needs to be run before any startup that needs the WgpuRenderer
             .add_startup_system_to_stage(StartupStage::PreStartup, init_renderer)
             .add_startup_stage_after(
@@ -37,6 +51,15 @@ impl Plugin for WgpuRendererPlugin {
                 StartupStage::PostStartup,
                 bind_groups::mesh_view::setup_mesh_view_bind_group,
             )
+            // Needs to be in PostStartup because it needs the depth texture
+            .add_startup_system_to_stage(StartupStage::PostStartup, ssao_pass::init_ssao_pass)
+            // Runs after every system that moves the camera or the models
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                culling::update_visibility
+                    .exclusive_system()
+                    .before("render"),
+            )
             .add_system_to_stage(
                 CoreStage::PostUpdate,
                 update_render_phase::<RenderPhase3d>
@@ -55,8 +78,9 @@ impl Plugin for WgpuRendererPlugin {
             )
             .add_system(bind_groups::mesh_view::update_light_buffer)
             .add_system(bind_groups::mesh_view::update_camera_buffer)
-            .add_system(bind_groups::material::update_material_buffer)
-            .add_system(bind_groups::material::create_material_uniform)
+            .add_system(fog::update_fog_buffer)
+            .add_system(ssao_pass::update_ssao_buffer)
+            .add_system(bind_groups::material::prepare_materials)
             .add_system(instances::update_instance_buffer)
             .add_system(instances::create_instance_buffer)
             .add_system(resize);
@@ -67,6 +91,7 @@ fn init_renderer(
     mut commands: Commands,
     windows: Res<Windows>,
     winit_windows: NonSendMut<WinitWindows>,
+    device_features: Res<DeviceFeatures>,
 ) {
     let winit_window = windows
         .get_primary()
@@ -74,6 +99,7 @@ fn init_renderer(
         .expect("Failed to get window");
 
     let renderer = future::block_on(WgpuRenderer::new(winit_window));
+    device_features.set(renderer.device.features());
     commands.insert_resource(renderer);
 }
 
@@ -109,6 +135,8 @@ fn resize(
     windows: Res<Windows>,
     mut depth_pass: ResMut<DepthPass>,
     mut depth_texture: ResMut<DepthTexture>,
+    mut ssao_pass: ResMut<SsaoPass>,
+    mut render_phase_3d: ResMut<RenderPhase3d>,
     mut camera_uniform: ResMut<CameraUniform>,
     mut camera: ResMut<Camera>,
     mut screen_descriptor: ResMut<egui_wgpu::renderer::ScreenDescriptor>,
@@ -126,6 +154,11 @@ fn resize(
 
         depth_texture.0 = Texture::create_depth_texture(&renderer.device, &renderer.config);
         depth_pass.resize(&renderer.device, &depth_texture.0);
+        ssao_pass.resize(&renderer.device, &renderer.config, &depth_texture.0);
+        render_phase_3d
+            .opaque_pass
+            .oit_pass
+            .resize(&renderer.device, &renderer.config);
 
         // Should probably be done in EguiPlugin
         screen_descriptor.size_in_pixels = [width as u32, height as u32];
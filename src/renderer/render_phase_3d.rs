@@ -1,32 +1,60 @@
-use bevy::prelude::{Color, Component, QueryState, With, Without, World};
+use bevy::{
+    math::Mat4,
+    prelude::{Color, Component, QueryState, With, Without, World},
+    utils::{HashMap, HashSet},
+};
 use wgpu::CommandEncoder;
 
 use crate::{
+    camera::Camera,
+    fog::Fog,
     instances::InstanceBuffer,
     light::draw_light_model,
     light::Light,
-    mesh::{self},
-    model::Model,
+    mesh::MeshVertexLayout,
+    model::{AlphaMode, Model, ModelMesh},
     texture::Texture,
-    transform::TransformRaw,
+    transform::{Transform, TransformRaw},
     Instances,
 };
 
 use super::{
     bind_groups::{
-        material::{self, GpuModelMaterials},
+        material::{self, GpuMaterials},
         mesh_view::{MeshViewBindGroup, MeshViewBindGroupLayout},
     },
+    culling::VisibleInstances,
+    custom_material::{CustomMaterialPipelines, DrawCustomMaterial},
     depth_pass::DepthPass,
+    oit_pass::OitPass,
+    shader_preprocessor::preprocess_shader,
+    ssao_pass::{SsaoPass, SsaoSettings},
     RenderPhase, WgpuRenderer,
 };
 
 pub struct DepthTexture(pub Texture);
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransparencyMode {
+    /// Transparent meshes are sorted back to front and alpha blended
+    Sorted,
+    /// Weighted blended order independent transparency.
+    /// Handles intersecting meshes but the result is only an approximation
+    WeightedBlended,
+}
+
+impl Default for TransparencyMode {
+    fn default() -> Self {
+        TransparencyMode::Sorted
+    }
+}
+
 #[derive(Default)]
 pub struct RenderPhase3dDescriptor {
+    /// Converted to the color space of the surface when clearing it
     pub clear_color: Color,
     pub show_depth_buffer: bool,
+    pub transparency_mode: TransparencyMode,
 }
 
 pub struct RenderPhase3d {
@@ -47,6 +75,13 @@ impl RenderPhase for RenderPhase3d {
     }
 
     fn render(&self, world: &World, view: &wgpu::TextureView, encoder: &mut CommandEncoder) {
+        let ssao_enabled = world.resource::<SsaoSettings>().enabled;
+        if ssao_enabled {
+            self.opaque_pass.render_depth_prepass(world, encoder);
+        }
+        // TODO the RenderPhase3d should probably own this
+        world.resource::<SsaoPass>().render(ssao_enabled, encoder);
+
         self.opaque_pass.render(world, view, encoder);
 
         if world
@@ -60,21 +95,122 @@ impl RenderPhase for RenderPhase3d {
     }
 }
 
+/// Forces every mesh of the model to be drawn in the transparent pass,
+/// even if its material isn't alpha blended
 #[derive(Component)]
 pub struct Transparent;
 
+/// A pipeline with back face culling and its variant without culling used by double sided materials
+pub struct CullVariants {
+    pub single_sided: wgpu::RenderPipeline,
+    pub double_sided: wgpu::RenderPipeline,
+}
+
+impl CullVariants {
+    /// Calls create_pipeline with the cull mode of each variant
+    pub fn new(create_pipeline: impl Fn(Option<wgpu::Face>) -> wgpu::RenderPipeline) -> Self {
+        Self {
+            single_sided: create_pipeline(Some(wgpu::Face::Back)),
+            double_sided: create_pipeline(None),
+        }
+    }
+
+    pub fn get(&self, double_sided: bool) -> &wgpu::RenderPipeline {
+        if double_sided {
+            &self.double_sided
+        } else {
+            &self.single_sided
+        }
+    }
+}
+
+/// Pipelines specialized for the vertex layout of the meshes they draw.
+/// The pipeline of a layout is created the first time a mesh with this layout needs to be drawn
+pub struct LayoutPipelines<P>(HashMap<MeshVertexLayout, P>);
+
+impl<P> Default for LayoutPipelines<P> {
+    fn default() -> Self {
+        Self(HashMap::default())
+    }
+}
+
+impl<P> LayoutPipelines<P> {
+    /// Calls create_pipeline if there's no pipeline for this layout yet
+    pub fn prepare(
+        &mut self,
+        layout: &MeshVertexLayout,
+        create_pipeline: impl FnOnce(&MeshVertexLayout) -> P,
+    ) {
+        if !self.0.contains_key(layout) {
+            let pipeline = create_pipeline(layout);
+            self.0.insert(layout.clone(), pipeline);
+        }
+    }
+
+    pub fn get(&self, layout: &MeshVertexLayout) -> Option<&P> {
+        self.0.get(layout)
+    }
+}
+
+/// The vertex layouts of every mesh of the models
+pub fn mesh_layouts<'w>(models: impl Iterator<Item = &'w Model>) -> HashSet<&'w MeshVertexLayout> {
+    models
+        .flat_map(|model| model.meshes.iter().map(|mesh| &mesh.layout))
+        .collect()
+}
+
+/// The model matrix of every instance of a model, `None` if it doesn't have a transform
+pub fn instance_matrices(
+    transform: Option<&Transform>,
+    instances: Option<&Instances>,
+) -> Option<Vec<Mat4>> {
+    if let Some(transform) = transform {
+        Some(vec![transform.compute_matrix()])
+    } else {
+        instances.map(|instances| instances.0.iter().map(Transform::compute_matrix).collect())
+    }
+}
+
+/// A single instance of a transparent mesh
+pub struct TransparentDraw<'w> {
+    /// Distance from the camera in view space
+    pub distance: f32,
+    pub mesh: &'w ModelMesh,
+    pub material_bind_group: &'w wgpu::BindGroup,
+    pub double_sided: bool,
+    pub instance_buffer: &'w wgpu::Buffer,
+    pub instance: u32,
+    /// Custom materials use their own pipeline instead of the pipeline of the pass
+    pub pipeline: Option<&'w wgpu::RenderPipeline>,
+}
+
+impl<'w> TransparentDraw<'w> {
+    /// The distance used to sort the draws, from the camera to the center of the mesh
+    pub fn view_distance(view: &Mat4, model_matrix: &Mat4, mesh: &ModelMesh) -> f32 {
+        let view_position = *view * *model_matrix * mesh.aabb.center.extend(1.0);
+        // The camera looks down -z
+        -view_position.z
+    }
+}
+
 #[allow(clippy::type_complexity)]
 pub struct OpaquePass {
-    pub render_pipeline: wgpu::RenderPipeline,
-    pub light_render_pipeline: wgpu::RenderPipeline,
-    pub transparent_render_pipeline: wgpu::RenderPipeline,
+    depth_prepass_layout: wgpu::PipelineLayout,
+    render_pipeline_layout: wgpu::PipelineLayout,
+    light_pipeline_layout: wgpu::PipelineLayout,
+    pub depth_prepass_pipeline: LayoutPipelines<CullVariants>,
+    pub render_pipeline: LayoutPipelines<CullVariants>,
+    pub light_render_pipeline: LayoutPipelines<wgpu::RenderPipeline>,
+    pub transparent_render_pipeline: LayoutPipelines<CullVariants>,
+    pub custom_material_pipelines: Vec<Box<dyn DrawCustomMaterial>>,
+    pub oit_pass: OitPass,
     pub light_query: QueryState<&'static Model, With<Light>>,
     pub model_query: QueryState<
         (
             &'static Model,
             &'static InstanceBuffer,
             Option<&'static Instances>,
-            &'static GpuModelMaterials,
+            Option<&'static VisibleInstances>,
         ),
         (Without<Light>, Without<Transparent>),
     >,
@@ -82,17 +218,30 @@ pub struct OpaquePass {
         (
             &'static Model,
             &'static InstanceBuffer,
+            Option<&'static Transform>,
             Option<&'static Instances>,
+            Option<&'static Transparent>,
+            Option<&'static VisibleInstances>,
         ),
-        (Without<Light>, With<Transparent>),
+        Without<Light>,
     >,
 }
 
 impl OpaquePass {
     pub fn from_world(world: &mut World) -> Self {
+        let custom_material_pipelines = world
+            .get_resource::<CustomMaterialPipelines>()
+            .map(|pipelines| pipelines.0.clone())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|create_pipeline| create_pipeline(world))
+            .collect();
+
         let renderer = world.resource::<WgpuRenderer>();
         let mesh_view_layout = world.resource::<MeshViewBindGroupLayout>();
+        let ssao_pass = world.resource::<SsaoPass>();
 
+        let material_layout = material::bind_group_layout(&renderer.device);
         let render_pipeline_layout =
             renderer
                 .device
@@ -100,67 +249,40 @@ impl OpaquePass {
                     label: Some("Render Pipeline Layout"),
                     bind_group_layouts: &[
                         &mesh_view_layout.0,
-                        &material::bind_group_layout(&renderer.device),
+                        &material_layout,
+                        &ssao_pass.output_layout,
                     ],
                     push_constant_ranges: &[],
                 });
 
-        // TODO have a better way to attach draw commands to a pipeline
-        let render_pipeline = renderer.create_render_pipeline(
-            "Opaque Render Pipeline",
-            include_str!("shaders/shader.wgsl"),
-            &render_pipeline_layout,
-            &[mesh::Vertex::layout(), TransformRaw::layout()],
-            Some(wgpu::DepthStencilState {
-                format: Texture::DEPTH_FORMAT,
-                depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::Less,
-                stencil: wgpu::StencilState::default(),
-                bias: wgpu::DepthBiasState::default(),
-            }),
-            wgpu::BlendState::REPLACE,
-        );
-
-        let transparent_render_pipeline = renderer.create_render_pipeline(
-            "Transparent Render Pipeline",
-            include_str!("shaders/shader.wgsl"),
-            &render_pipeline_layout,
-            &[mesh::Vertex::layout(), TransformRaw::layout()],
-            Some(wgpu::DepthStencilState {
-                format: Texture::DEPTH_FORMAT,
-                depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::Less,
-                stencil: wgpu::StencilState::default(),
-                bias: wgpu::DepthBiasState::default(),
-            }),
-            wgpu::BlendState::ALPHA_BLENDING,
-        );
-
-        let light_render_pipeline = renderer.create_render_pipeline(
-            "Light Render Pipeline",
-            include_str!("shaders/light.wgsl"),
-            &renderer
+        let depth_prepass_layout =
+            renderer
+                .device
+                .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: Some("Depth Prepass Pipeline Layout"),
+                    bind_group_layouts: &[&mesh_view_layout.0, &material_layout],
+                    push_constant_ranges: &[],
+                });
+        let light_pipeline_layout =
+            renderer
                 .device
                 .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                     label: Some("Light Pipeline Layout"),
                     bind_group_layouts: &[&mesh_view_layout.0],
                     push_constant_ranges: &[],
-                }),
-            &[mesh::Vertex::layout()],
-            Some(wgpu::DepthStencilState {
-                format: Texture::DEPTH_FORMAT,
-                depth_write_enabled: false,
-                depth_compare: wgpu::CompareFunction::Less,
-                stencil: wgpu::StencilState::default(),
-                bias: wgpu::DepthBiasState::default(),
-            }),
-            wgpu::BlendState::REPLACE,
-        );
+                });
 
+        // The pipelines are created in update() once the layout of the meshes is known
         Self {
-            render_pipeline,
-            light_render_pipeline,
-            transparent_render_pipeline,
+            depth_prepass_layout,
+            render_pipeline_layout,
+            light_pipeline_layout,
+            depth_prepass_pipeline: LayoutPipelines::default(),
+            render_pipeline: LayoutPipelines::default(),
+            light_render_pipeline: LayoutPipelines::default(),
+            transparent_render_pipeline: LayoutPipelines::default(),
+            custom_material_pipelines,
+            oit_pass: OitPass::new(renderer),
             light_query: world.query_filtered(),
             model_query: world.query_filtered(),
             transparent_model_query: world.query_filtered(),
@@ -171,28 +293,115 @@ impl OpaquePass {
         self.light_query.update_archetypes(world);
         self.model_query.update_archetypes(world);
         self.transparent_model_query.update_archetypes(world);
+        for pipeline in &mut self.custom_material_pipelines {
+            pipeline.update(world);
+        }
+
+        let world: &World = world;
+        let renderer = world.resource::<WgpuRenderer>();
+
+        // The transparent_model_query contains every model that isn't a light
+        let layouts = mesh_layouts(
+            self.transparent_model_query
+                .iter_manual(world)
+                .map(|(model, ..)| model),
+        );
+        for layout in layouts {
+            self.prepare_pipelines(renderer, layout);
+        }
+
+        let light_layouts = mesh_layouts(self.light_query.iter_manual(world));
+        for layout in light_layouts {
+            self.light_render_pipeline.prepare(layout, |layout| {
+                renderer.create_render_pipeline(
+                    "Light Render Pipeline",
+                    &preprocess_shader(include_str!("shaders/light.wgsl"), &layout.shader_defs()),
+                    &self.light_pipeline_layout,
+                    &[layout.buffer_layout()],
+                    Some(wgpu::DepthStencilState {
+                        format: Texture::DEPTH_FORMAT,
+                        depth_write_enabled: false,
+                        depth_compare: wgpu::CompareFunction::Less,
+                        stencil: wgpu::StencilState::default(),
+                        bias: wgpu::DepthBiasState::default(),
+                    }),
+                    wgpu::BlendState::REPLACE,
+                    Some(wgpu::Face::Back),
+                )
+            });
+        }
     }
 
-    fn render(&self, world: &World, view: &wgpu::TextureView, encoder: &mut wgpu::CommandEncoder) {
+    /// Creates every pipeline used to draw a mesh with this layout
+    fn prepare_pipelines(&mut self, renderer: &WgpuRenderer, layout: &MeshVertexLayout) {
+        let shader = |layout: &MeshVertexLayout| {
+            preprocess_shader(include_str!("shaders/shader.wgsl"), &layout.shader_defs())
+        };
+        let depth_prepass_layout = &self.depth_prepass_layout;
+        self.depth_prepass_pipeline.prepare(layout, |layout| {
+            CullVariants::new(|cull_mode| {
+                create_depth_prepass_pipeline(renderer, depth_prepass_layout, layout, cull_mode)
+            })
+        });
+
+        let render_pipeline_layout = &self.render_pipeline_layout;
+        // TODO have a better way to attach draw commands to a pipeline
+        self.render_pipeline.prepare(layout, |layout| {
+            let shader = shader(layout);
+            CullVariants::new(|cull_mode| {
+                renderer.create_render_pipeline(
+                    "Opaque Render Pipeline",
+                    &shader,
+                    render_pipeline_layout,
+                    &[layout.buffer_layout(), TransformRaw::layout()],
+                    Some(wgpu::DepthStencilState {
+                        format: Texture::DEPTH_FORMAT,
+                        depth_write_enabled: true,
+                        // The depth prepass already wrote the depth of every opaque mesh
+                        depth_compare: wgpu::CompareFunction::LessEqual,
+                        stencil: wgpu::StencilState::default(),
+                        bias: wgpu::DepthBiasState::default(),
+                    }),
+                    wgpu::BlendState::REPLACE,
+                    cull_mode,
+                )
+            })
+        });
+
+        self.transparent_render_pipeline.prepare(layout, |layout| {
+            let shader = shader(layout);
+            CullVariants::new(|cull_mode| {
+                renderer.create_render_pipeline(
+                    "Transparent Render Pipeline",
+                    &shader,
+                    render_pipeline_layout,
+                    &[layout.buffer_layout(), TransformRaw::layout()],
+                    Some(wgpu::DepthStencilState {
+                        format: Texture::DEPTH_FORMAT,
+                        // Transparent meshes are sorted so they shouldn't hide each other
+                        depth_write_enabled: false,
+                        depth_compare: wgpu::CompareFunction::Less,
+                        stencil: wgpu::StencilState::default(),
+                        bias: wgpu::DepthBiasState::default(),
+                    }),
+                    wgpu::BlendState::ALPHA_BLENDING,
+                    cull_mode,
+                )
+            })
+        });
+
+        self.oit_pass
+            .prepare_pipeline(renderer, render_pipeline_layout, layout);
+    }
+
+    /// Draws every opaque mesh to the depth texture
+    fn render_depth_prepass(&self, world: &World, encoder: &mut wgpu::CommandEncoder) {
         let mesh_view_bind_group = world.resource::<MeshViewBindGroup>();
         let depth_texture = world.resource::<DepthTexture>();
-        let clear_color = world.resource::<RenderPhase3dDescriptor>().clear_color;
 
         let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-            label: Some("Opaque Render Pass"),
-            color_attachments: &[wgpu::RenderPassColorAttachment {
-                view,
-                resolve_target: None,
-                ops: wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(wgpu::Color {
-                        r: clear_color.r() as f64,
-                        g: clear_color.g() as f64,
-                        b: clear_color.b() as f64,
-                        a: clear_color.a() as f64,
-                    }),
-                    store: true,
-                },
-            }],
+            label: Some("Depth Prepass"),
+            color_attachments: &[],
             depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
                 view: &depth_texture.0.view,
                 depth_ops: Some(wgpu::Operations {
@@ -203,61 +412,369 @@ impl OpaquePass {
             }),
         });
 
-        // TODO figure out how to sort models
-        render_pass.set_pipeline(&self.render_pipeline);
-        for (model, instance_buffer, instances, gpu_materials) in
-            self.model_query.iter_manual(world)
-        {
+        self.draw_models(
+            world,
+            &mut render_pass,
+            &self.depth_prepass_pipeline,
+            &mesh_view_bind_group.0,
+        );
+
+        for pipeline in &self.custom_material_pipelines {
+            pipeline.draw_depth_prepass(world, &mut render_pass, &mesh_view_bind_group.0);
+        }
+    }
+
+    fn draw_models<'w>(
+        &'w self,
+        world: &'w World,
+        render_pass: &mut wgpu::RenderPass<'w>,
+        pipelines: &'w LayoutPipelines<CullVariants>,
+        mesh_view_bind_group: &'w wgpu::BindGroup,
+    ) {
+        let gpu_materials = world.resource::<GpuMaterials>();
+        for (model, instance_buffer, instances, visible) in self.model_query.iter_manual(world) {
             // The draw function also uses the instance buffer under the hood it simply is of size 1
             render_pass.set_vertex_buffer(1, instance_buffer.0.slice(..));
-            let transparent = false;
             if let Some(instances) = instances {
                 model.draw_instanced(
-                    &mut render_pass,
+                    render_pass,
                     0..instances.0.len() as u32,
+                    visible,
                     gpu_materials,
-                    &mesh_view_bind_group.0,
-                    transparent,
+                    mesh_view_bind_group,
+                    false,
+                    pipelines,
                 );
             } else {
                 model.draw(
-                    &mut render_pass,
+                    render_pass,
+                    visible,
                     gpu_materials,
-                    &mesh_view_bind_group.0,
-                    transparent,
+                    mesh_view_bind_group,
+                    false,
+                    pipelines,
                 );
             }
         }
+    }
 
-        // TODO I need a better way to identify transparent meshes in a model
-        render_pass.set_pipeline(&self.transparent_render_pipeline);
-        for (model, instance_buffer, instances, gpu_materials) in
-            self.model_query.iter_manual(world)
+    /// Collects every instance of every transparent mesh
+    fn transparent_draws<'w>(&'w self, world: &'w World) -> Vec<TransparentDraw<'w>> {
+        let gpu_materials = world.resource::<GpuMaterials>();
+        let view = world.resource::<Camera>().build_view_matrix();
+
+        let mut draws = vec![];
+        for (model, instance_buffer, transform, instances, transparent, visible) in
+            self.transparent_model_query.iter_manual(world)
         {
-            // The draw function also uses the instance buffer under the hood it simply is of size 1
-            render_pass.set_vertex_buffer(1, instance_buffer.0.slice(..));
-            let transparent = true;
-            if let Some(instances) = instances {
-                model.draw_instanced(
-                    &mut render_pass,
-                    0..instances.0.len() as u32,
-                    gpu_materials,
-                    &mesh_view_bind_group.0,
-                    transparent,
-                );
-            } else {
-                model.draw(
+            let transforms = match instance_matrices(transform, instances) {
+                Some(transforms) => transforms,
+                None => continue,
+            };
+
+            let all_instances = 0..transforms.len() as u32;
+            for (i, mesh) in model.meshes.iter().enumerate() {
+                let material = match mesh.material.as_ref().and_then(|h| gpu_materials.get(h)) {
+                    Some(material) => material,
+                    None => continue,
+                };
+                if transparent.is_none() && material.alpha_mode != AlphaMode::Blend {
+                    continue;
+                }
+                let visible_instances = VisibleInstances::ranges(visible, i, &all_instances)
+                    .iter()
+                    .cloned()
+                    .flatten();
+                for instance in visible_instances {
+                    draws.push(TransparentDraw {
+                        distance: TransparentDraw::view_distance(
+                            &view,
+                            &transforms[instance as usize],
+                            mesh,
+                        ),
+                        mesh,
+                        material_bind_group: &material.bind_group,
+                        double_sided: material.double_sided,
+                        instance_buffer: &instance_buffer.0,
+                        instance,
+                        pipeline: None,
+                    });
+                }
+            }
+        }
+
+        draws
+    }
+
+    /// Collects every instance of the meshes of transparent custom materials
+    fn custom_transparent_draws<'w>(&'w self, world: &'w World) -> Vec<TransparentDraw<'w>> {
+        let view = world.resource::<Camera>().build_view_matrix();
+        self.custom_material_pipelines
+            .iter()
+            .flat_map(|pipeline| pipeline.transparent_draws(world, &view))
+            .collect()
+    }
+
+    fn draw_transparent<'w>(
+        draws: Vec<TransparentDraw<'w>>,
+        pipelines: &'w LayoutPipelines<CullVariants>,
+        render_pass: &mut wgpu::RenderPass<'w>,
+        mesh_view_bind_group: &'w wgpu::BindGroup,
+    ) {
+        // Only switch pipeline when the next draw needs a different layout or cull mode
+        let mut current_pipeline = None;
+        for draw in draws {
+            let pipeline = match (draw.pipeline, pipelines.get(&draw.mesh.layout)) {
+                (Some(pipeline), _) => pipeline,
+                (None, Some(pipeline)) => pipeline.get(draw.double_sided),
+                (None, None) => continue,
+            };
+            if current_pipeline.map_or(true, |current| !std::ptr::eq(current, pipeline)) {
+                render_pass.set_pipeline(pipeline);
+                current_pipeline = Some(pipeline);
+            }
+            render_pass.set_vertex_buffer(1, draw.instance_buffer.slice(..));
+            draw.mesh.draw_instanced(
+                render_pass,
+                draw.instance..draw.instance + 1,
+                draw.material_bind_group,
+                mesh_view_bind_group,
+            );
+        }
+    }
+
+    /// Accumulates the transparent meshes in the oit targets and composites them on the view
+    fn render_oit(
+        &self,
+        world: &World,
+        view: &wgpu::TextureView,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        let mesh_view_bind_group = world.resource::<MeshViewBindGroup>();
+        let depth_texture = world.resource::<DepthTexture>();
+
+        {
+            let color_attachments = self.oit_pass.color_attachments();
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("OIT Render Pass"),
+                color_attachments: &color_attachments,
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &depth_texture.0.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+
+            render_pass.set_bind_group(2, &world.resource::<SsaoPass>().output_bind_group, &[]);
+            // The order doesn't matter so there's no need to sort
+            Self::draw_transparent(
+                self.transparent_draws(world),
+                &self.oit_pass.pipeline,
+                &mut render_pass,
+                &mesh_view_bind_group.0,
+            );
+        }
+
+        self.oit_pass.composite(view, encoder);
+    }
+
+    fn render(&self, world: &World, view: &wgpu::TextureView, encoder: &mut wgpu::CommandEncoder) {
+        let mesh_view_bind_group = world.resource::<MeshViewBindGroup>();
+        let depth_texture = world.resource::<DepthTexture>();
+        let ssao_enabled = world.resource::<SsaoSettings>().enabled;
+        let descriptor = world.resource::<RenderPhase3dDescriptor>();
+        let transparency_mode = descriptor.transparency_mode;
+        let clear_color = world
+            .resource::<Fog>()
+            .apply_to_clear_color(descriptor.clear_color);
+        // The clear color isn't converted by the gpu, it must already be in the space of the target
+        let [r, g, b, a] = if world
+            .resource::<WgpuRenderer>()
+            .config
+            .format
+            .describe()
+            .srgb
+        {
+            clear_color.as_linear_rgba_f32()
+        } else {
+            clear_color.as_rgba_f32()
+        };
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Opaque Render Pass"),
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color {
+                        r: r as f64,
+                        g: g as f64,
+                        b: b as f64,
+                        a: a as f64,
+                    }),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &depth_texture.0.view,
+                depth_ops: Some(wgpu::Operations {
+                    // Keep the depth from the prepass
+                    load: if ssao_enabled {
+                        wgpu::LoadOp::Load
+                    } else {
+                        wgpu::LoadOp::Clear(1.0)
+                    },
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+
+        render_pass.set_bind_group(2, &world.resource::<SsaoPass>().output_bind_group, &[]);
+
+        self.draw_models(
+            world,
+            &mut render_pass,
+            &self.render_pipeline,
+            &mesh_view_bind_group.0,
+        );
+
+        for pipeline in &self.custom_material_pipelines {
+            pipeline.draw(world, &mut render_pass, &mesh_view_bind_group.0);
+        }
+
+        for light_model in self.light_query.iter_manual(world) {
+            draw_light_model(
+                &mut render_pass,
+                light_model,
+                &self.light_render_pipeline,
+                &mesh_view_bind_group.0,
+            );
+        }
+
+        match transparency_mode {
+            TransparencyMode::Sorted => {
+                let mut draws = self.transparent_draws(world);
+                draws.extend(self.custom_transparent_draws(world));
+                // Draw the furthest meshes first
+                draws.sort_by(|a, b| b.distance.total_cmp(&a.distance));
+
+                Self::draw_transparent(
+                    draws,
+                    &self.transparent_render_pipeline,
                     &mut render_pass,
-                    gpu_materials,
                     &mesh_view_bind_group.0,
-                    transparent,
                 );
             }
+            TransparencyMode::WeightedBlended => {
+                drop(render_pass);
+                self.render_oit(world, view, encoder);
+                self.render_custom_transparent(world, view, encoder);
+            }
         }
+    }
 
-        render_pass.set_pipeline(&self.light_render_pipeline);
-        for light_model in self.light_query.iter_manual(world) {
-            draw_light_model(&mut render_pass, light_model, &mesh_view_bind_group.0);
+    /// Custom materials can't write to the oit targets,
+    /// they are sorted and blended on top of the composited transparent meshes
+    fn render_custom_transparent(
+        &self,
+        world: &World,
+        view: &wgpu::TextureView,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        let mut draws = self.custom_transparent_draws(world);
+        if draws.is_empty() {
+            return;
         }
+        draws.sort_by(|a, b| b.distance.total_cmp(&a.distance));
+
+        let mesh_view_bind_group = world.resource::<MeshViewBindGroup>();
+        let depth_texture = world.resource::<DepthTexture>();
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Custom Transparent Render Pass"),
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &depth_texture.0.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+        Self::draw_transparent(
+            draws,
+            &self.transparent_render_pipeline,
+            &mut render_pass,
+            &mesh_view_bind_group.0,
+        );
     }
 }
+
+fn create_depth_prepass_pipeline(
+    renderer: &WgpuRenderer,
+    pipeline_layout: &wgpu::PipelineLayout,
+    layout: &MeshVertexLayout,
+    cull_mode: Option<wgpu::Face>,
+) -> wgpu::RenderPipeline {
+    let shader = renderer
+        .device
+        .create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("Depth Prepass Shader"),
+            source: wgpu::ShaderSource::Wgsl(
+                preprocess_shader(
+                    include_str!("shaders/depth_prepass.wgsl"),
+                    &layout.shader_defs(),
+                )
+                .into(),
+            ),
+        });
+    renderer
+        .device
+        .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Depth Prepass Pipeline"),
+            layout: Some(pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vertex",
+                buffers: &[layout.buffer_layout(), TransformRaw::layout()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fragment",
+                targets: &[],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                unclipped_depth: false,
+                conservative: false,
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+        })
+}
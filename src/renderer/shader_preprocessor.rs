@@ -0,0 +1,52 @@
+/// Shaders shared by multiple pipelines. The fog expects the camera uniform to be declared before the import
+const SHADER_IMPORTS: &[(&str, &str)] = &[
+    ("fog", include_str!("shaders/fog.wgsl")),
+    ("fullscreen", include_str!("shaders/fullscreen.wgsl")),
+];
+
+/// Removes the lines of the shader that are disabled by `#ifdef`, `#ifndef` and `#else` blocks.
+/// Blocks can be nested and every block must be closed by an `#endif`.
+///
+/// ```wgsl
+/// #ifdef VERTEX_NORMAL
+///     let normal = vertex.normal;
+/// #else
+///     let normal = vec3<f32>(0.0, 1.0, 0.0);
+/// #endif
+/// ```
+///
+/// `#import <name>` is replaced by the preprocessed source of the shared shader with that name,
+/// see `SHADER_IMPORTS`.
+pub fn preprocess_shader(source: &str, shader_defs: &[String]) -> String {
+    // Each open block is enabled if its condition and every parent block are enabled
+    let mut scopes: Vec<bool> = vec![];
+    let enabled = |scopes: &[bool]| scopes.iter().all(|enabled| *enabled);
+
+    let mut output = String::with_capacity(source.len());
+    for line in source.lines() {
+        let trimmed = line.trim();
+        if let Some(def) = trimmed.strip_prefix("#ifdef ") {
+            scopes.push(shader_defs.iter().any(|d| d == def.trim()));
+        } else if let Some(def) = trimmed.strip_prefix("#ifndef ") {
+            scopes.push(!shader_defs.iter().any(|d| d == def.trim()));
+        } else if trimmed == "#else" {
+            let scope = scopes.last_mut().expect("#else without #ifdef in shader");
+            *scope = !*scope;
+        } else if trimmed == "#endif" {
+            scopes.pop().expect("#endif without #ifdef in shader");
+        } else if let Some(name) = trimmed.strip_prefix("#import ") {
+            if enabled(&scopes) {
+                let (_, import) = SHADER_IMPORTS
+                    .iter()
+                    .find(|(import_name, _)| *import_name == name.trim())
+                    .unwrap_or_else(|| panic!("Unknown shader import {name}"));
+                output.push_str(&preprocess_shader(import, shader_defs));
+            }
+        } else if enabled(&scopes) {
+            output.push_str(line);
+            output.push('\n');
+        }
+    }
+    assert!(scopes.is_empty(), "#ifdef without #endif in shader");
+    output
+}
@@ -0,0 +1,105 @@
+// This is prepended to the shader of every CustomMaterial.
+// The material bindings are expected to be in group 1 and must match the layout
+// generated by the CustomMaterial. Binding 0 is the uniform, then each texture
+// uses 2 bindings, the texture followed by its sampler.
+// The prelude and the shader are preprocessed with the shader defs of the mesh vertex layout,
+// e.g. `#ifdef VERTEX_COLOR`, so optional vertex attributes can be used.
+
+struct CameraUniform {
+    view_pos: vec4<f32>;
+    view_proj: mat4x4<f32>;
+};
+[[group(0), binding(0)]]
+var<uniform> camera: CameraUniform;
+
+struct Light {
+    position: vec3<f32>;
+    color: vec3<f32>;
+};
+[[group(0), binding(1)]]
+var<uniform> light: Light;
+
+#import fog
+
+struct Vertex {
+    [[location(0)]] position: vec3<f32>;
+#ifdef VERTEX_NORMAL
+    [[location(1)]] normal: vec3<f32>;
+#endif
+#ifdef VERTEX_UV_0
+    [[location(2)]] uv: vec2<f32>;
+#endif
+#ifdef VERTEX_TANGENT
+    // w is the sign of the bitangent
+    [[location(3)]] tangent: vec4<f32>;
+#endif
+#ifdef VERTEX_COLOR
+    [[location(12)]] color: vec4<f32>;
+#endif
+#ifdef VERTEX_UV_1
+    [[location(13)]] uv1: vec2<f32>;
+#endif
+};
+
+struct InstanceInput {
+    [[location(5)]] model_matrix_0: vec4<f32>;
+    [[location(6)]] model_matrix_1: vec4<f32>;
+    [[location(7)]] model_matrix_2: vec4<f32>;
+    [[location(8)]] model_matrix_3: vec4<f32>;
+    [[location(9)]] normal_matrix_0: vec3<f32>;
+    [[location(10)]] normal_matrix_1: vec3<f32>;
+    [[location(11)]] normal_matrix_2: vec3<f32>;
+};
+
+struct MeshVertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] world_position: vec4<f32>;
+    [[location(1)]] world_normal: vec3<f32>;
+    [[location(2)]] uv: vec2<f32>;
+    [[location(3)]] color: vec4<f32>;
+};
+
+fn build_model_matrix(instance: InstanceInput) -> mat4x4<f32> {
+    return mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+}
+
+fn build_normal_matrix(instance: InstanceInput) -> mat3x3<f32> {
+    return mat3x3<f32>(
+        instance.normal_matrix_0,
+        instance.normal_matrix_1,
+        instance.normal_matrix_2,
+    );
+}
+
+// Transforms the vertex the same way as the default shader.
+// A material that doesn't need a custom vertex shader can simply return this from its vertex entry point.
+fn mesh_vertex(vertex: Vertex, instance: InstanceInput) -> MeshVertexOutput {
+    let model_matrix = build_model_matrix(instance);
+    let normal_matrix = build_normal_matrix(instance);
+    let world_position = model_matrix * vec4<f32>(vertex.position, 1.0);
+
+    var out: MeshVertexOutput;
+    out.clip_position = camera.view_proj * world_position;
+    out.world_position = world_position;
+#ifdef VERTEX_NORMAL
+    out.world_normal = normal_matrix * vertex.normal;
+#else
+    out.world_normal = normal_matrix * vec3<f32>(0.0, 1.0, 0.0);
+#endif
+#ifdef VERTEX_UV_0
+    out.uv = vertex.uv;
+#else
+    out.uv = vec2<f32>(0.0, 0.0);
+#endif
+#ifdef VERTEX_COLOR
+    out.color = vertex.color;
+#else
+    out.color = vec4<f32>(1.0, 1.0, 1.0, 1.0);
+#endif
+    return out;
+}
@@ -0,0 +1,107 @@
+// Only writes the depth of opaque and alpha masked meshes so it can be used by the ssao pass
+
+struct CameraUniform {
+    view_pos: vec4<f32>;
+    view_proj: mat4x4<f32>;
+};
+[[group(0), binding(0)]]
+var<uniform> camera: CameraUniform;
+
+// WARN this must match the Material in shader.wgsl
+struct Material {
+    base_color: vec4<f32>;
+    alpha: f32;
+    gloss: f32;
+    specular_color: vec3<f32>;
+    flags: u32;
+    emissive: vec3<f32>;
+    emissive_intensity: f32;
+    alpha_cutoff: f32;
+    diffuse_uv_transform: mat3x3<f32>;
+    normal_uv_transform: mat3x3<f32>;
+    specular_uv_transform: mat3x3<f32>;
+    emissive_uv_transform: mat3x3<f32>;
+    // diffuse, normal, specular, emissive
+    uv_channels: vec4<u32>;
+};
+
+let MATERIAL_FLAGS_ALPHA_MASK: u32 = 2u;
+
+[[group(1), binding(0)]]
+var<uniform> material: Material;
+
+[[group(1), binding(1)]]
+var t_diffuse: texture_2d<f32>;
+[[group(1), binding(2)]]
+var s_diffuse: sampler;
+
+struct Vertex {
+    [[location(0)]] position: vec3<f32>;
+#ifdef VERTEX_UV_0
+    [[location(2)]] uv: vec2<f32>;
+#endif
+#ifdef VERTEX_COLOR
+    [[location(12)]] color: vec4<f32>;
+#endif
+#ifdef VERTEX_UV_1
+    [[location(13)]] uv1: vec2<f32>;
+#endif
+};
+struct InstanceInput {
+    [[location(5)]] model_matrix_0: vec4<f32>;
+    [[location(6)]] model_matrix_1: vec4<f32>;
+    [[location(7)]] model_matrix_2: vec4<f32>;
+    [[location(8)]] model_matrix_3: vec4<f32>;
+};
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] uv: vec2<f32>;
+    [[location(1)]] color_alpha: f32;
+};
+
+[[stage(vertex)]]
+fn vertex(
+    vertex: Vertex,
+    instance: InstanceInput,
+) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    // This needs to be computed exactly like the opaque pass to get the same depth values
+    let world_position = model_matrix * vec4<f32>(vertex.position, 1.0);
+
+    var out: VertexOutput;
+    out.clip_position = camera.view_proj * world_position;
+    // Only the diffuse uv is needed, the transform is affine so it can be applied per vertex
+    var uv = vec2<f32>(0.0, 0.0);
+#ifdef VERTEX_UV_0
+    uv = vertex.uv;
+#endif
+#ifdef VERTEX_UV_1
+    if (material.uv_channels.x == 1u) {
+        uv = vertex.uv1;
+    }
+#endif
+    out.uv = (material.diffuse_uv_transform * vec3<f32>(uv, 1.0)).xy;
+#ifdef VERTEX_COLOR
+    out.color_alpha = vertex.color.a;
+#else
+    out.color_alpha = 1.0;
+#endif
+    return out;
+}
+
+// Alpha masked meshes need to discard the same fragments as the opaque pass
+[[stage(fragment)]]
+fn fragment(in: VertexOutput) {
+    if ((material.flags & MATERIAL_FLAGS_ALPHA_MASK) != 0u) {
+        let alpha = textureSample(t_diffuse, s_diffuse, in.uv).a * in.color_alpha * material.base_color.a;
+        if (alpha < material.alpha_cutoff) {
+            discard;
+        }
+    }
+}
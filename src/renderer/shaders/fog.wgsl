@@ -0,0 +1,45 @@
+// Imported with `#import fog` after the camera uniform
+
+struct Fog {
+    color: vec4<f32>;
+    mode: u32;
+    start: f32;
+    end: f32;
+    density: f32;
+    height_offset: f32;
+    height_density: f32;
+};
+[[group(0), binding(2)]]
+var<uniform> fog: Fog;
+
+let FOG_MODE_OFF: u32 = 0u;
+let FOG_MODE_LINEAR: u32 = 1u;
+let FOG_MODE_EXPONENTIAL: u32 = 2u;
+let FOG_MODE_EXPONENTIAL_SQUARED: u32 = 3u;
+
+fn apply_fog(color: vec3<f32>, world_position: vec3<f32>) -> vec3<f32> {
+    if (fog.mode == FOG_MODE_OFF) {
+        return color;
+    }
+
+    let distance = length(world_position - camera.view_pos.xyz);
+
+    var fog_amount: f32;
+    if (fog.mode == FOG_MODE_LINEAR) {
+        fog_amount = clamp((distance - fog.start) / (fog.end - fog.start), 0.0, 1.0);
+    } else {
+        if (fog.mode == FOG_MODE_EXPONENTIAL) {
+            fog_amount = 1.0 - exp(-distance * fog.density);
+        } else {
+            let d = distance * fog.density;
+            fog_amount = 1.0 - exp(-d * d);
+        }
+    }
+
+    if (fog.height_density > 0.0) {
+        let height = world_position.y - fog.height_offset;
+        fog_amount = fog_amount * clamp(exp(-height * fog.height_density), 0.0, 1.0);
+    }
+
+    return mix(color, fog.color.rgb, fog_amount * fog.color.a);
+}
@@ -0,0 +1,16 @@
+// Imported with `#import fullscreen` by the pipelines created with `create_fullscreen_pipeline`
+// Generates a single triangle covering the screen from the vertex_index
+
+struct FullscreenVertexOutput {
+    [[builtin(position)]] position: vec4<f32>;
+    [[location(0)]] uv: vec2<f32>;
+};
+
+[[stage(vertex)]]
+fn vertex([[builtin(vertex_index)]] vertex_index: u32) -> FullscreenVertexOutput {
+    let uv = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    var out: FullscreenVertexOutput;
+    out.position = vec4<f32>(uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0), 0.0, 1.0);
+    out.uv = uv;
+    return out;
+}
@@ -0,0 +1,22 @@
+// Resolves the weighted blended transparency targets on top of the opaque meshes
+
+[[group(0), binding(0)]]
+var accum_texture: texture_2d<f32>;
+[[group(0), binding(1)]]
+var revealage_texture: texture_2d<f32>;
+
+#import fullscreen
+
+[[stage(fragment)]]
+fn fragment(in: FullscreenVertexOutput) -> [[location(0)]] vec4<f32> {
+    let coords = vec2<i32>(in.position.xy);
+    let revealage = textureLoad(revealage_texture, coords, 0).r;
+    // Nothing transparent was drawn on this pixel
+    if (revealage >= 1.0) {
+        discard;
+    }
+
+    let accum = textureLoad(accum_texture, coords, 0);
+    let average_color = accum.rgb / max(accum.a, 0.00001);
+    return vec4<f32>(average_color, 1.0 - revealage);
+}
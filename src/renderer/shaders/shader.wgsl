@@ -12,16 +12,27 @@ struct Light {
 [[group(0), binding(1)]]
 var<uniform> light: Light;
 
+#import fog
+
 struct Material {
     base_color: vec4<f32>;
     alpha: f32;
     gloss: f32;
     specular_color: vec3<f32>;
     flags: u32;
+    emissive: vec3<f32>;
+    emissive_intensity: f32;
+    alpha_cutoff: f32;
+    diffuse_uv_transform: mat3x3<f32>;
+    normal_uv_transform: mat3x3<f32>;
+    specular_uv_transform: mat3x3<f32>;
+    emissive_uv_transform: mat3x3<f32>;
+    // diffuse, normal, specular, emissive
+    uv_channels: vec4<u32>;
 };
 
 let MATERIAL_FLAGS_USE_NORMAL_MAP: u32 = 1u;
-let MATERIAL_FLAGS_1: u32 = 2u;
+let MATERIAL_FLAGS_ALPHA_MASK: u32 = 2u;
 let MATERIAL_FLAGS_2: u32 = 4u;
 let MATERIAL_FLAGS_3: u32 = 8u;
 let MATERIAL_FLAGS_4: u32 = 16u;
@@ -50,12 +61,33 @@ var t_spec: texture_2d<f32>;
 [[group(1), binding(6)]]
 var s_spec: sampler;
 
+[[group(1), binding(7)]]
+var t_emissive: texture_2d<f32>;
+[[group(1), binding(8)]]
+var s_emissive: sampler;
+
+[[group(2), binding(0)]]
+var t_ssao: texture_2d<f32>;
+
+// Optional attributes are only declared when the mesh has them, see MeshVertexLayout::shader_defs()
 struct Vertex {
     [[location(0)]] position: vec3<f32>;
+#ifdef VERTEX_NORMAL
     [[location(1)]] normal: vec3<f32>;
+#endif
+#ifdef VERTEX_UV_0
     [[location(2)]] uv: vec2<f32>;
-    [[location(3)]] tangent: vec3<f32>;
-    [[location(4)]] bitangent: vec3<f32>;
+#endif
+#ifdef VERTEX_TANGENT
+    // w is the sign of the bitangent
+    [[location(3)]] tangent: vec4<f32>;
+#endif
+#ifdef VERTEX_COLOR
+    [[location(12)]] color: vec4<f32>;
+#endif
+#ifdef VERTEX_UV_1
+    [[location(13)]] uv1: vec2<f32>;
+#endif
 };
 struct InstanceInput {
     [[location(5)]] model_matrix_0: vec4<f32>;
@@ -65,10 +97,6 @@ struct InstanceInput {
     [[location(9)]] normal_matrix_0: vec3<f32>;
     [[location(10)]] normal_matrix_1: vec3<f32>;
     [[location(11)]] normal_matrix_2: vec3<f32>;
-    [[location(12)]] inverse_transpose_model_matrix_0: vec4<f32>;
-    [[location(13)]] inverse_transpose_model_matrix_1: vec4<f32>;
-    [[location(14)]] inverse_transpose_model_matrix_2: vec4<f32>;
-    [[location(15)]] inverse_transpose_model_matrix_3: vec4<f32>;
 };
 
 struct VertexOutput {
@@ -79,6 +107,8 @@ struct VertexOutput {
     [[location(3)]] tangent_position: vec3<f32>;
     [[location(4)]] tangent_light_position: vec3<f32>;
     [[location(5)]] tangent_view_position: vec3<f32>;
+    [[location(6)]] color: vec4<f32>;
+    [[location(7)]] uv1: vec2<f32>;
 };
 
 fn build_model_matrix(instance: InstanceInput) -> mat4x4<f32> {
@@ -98,23 +128,6 @@ fn build_normal_matrix(instance: InstanceInput) -> mat3x3<f32> {
     );
 }
 
-fn build_inverse_transpose_model_matrix(instance: InstanceInput) -> mat4x4<f32> {
-    return mat4x4<f32>(
-        instance.inverse_transpose_model_matrix_0,
-        instance.inverse_transpose_model_matrix_1,
-        instance.inverse_transpose_model_matrix_2,
-        instance.inverse_transpose_model_matrix_3,
-    );
-}
-
-fn mesh_normal_local_to_world(inverse_transpose_model_matrix: mat4x4<f32>, vertex_normal: vec3<f32>) -> vec3<f32> {
-    return mat3x3<f32>(
-        inverse_transpose_model_matrix[0].xyz,
-        inverse_transpose_model_matrix[1].xyz,
-        inverse_transpose_model_matrix[2].xyz
-    ) * vertex_normal;
-}
-
 [[stage(vertex)]]
 fn vertex(
     vertex: Vertex,
@@ -123,18 +136,39 @@ fn vertex(
     let model_matrix = build_model_matrix(instance);
     let normal_matrix = build_normal_matrix(instance);
 
+#ifdef VERTEX_NORMAL
     let world_normal = normal_matrix * vertex.normal;
+#else
+    let world_normal = normal_matrix * vec3<f32>(0.0, 1.0, 0.0);
+#endif
     let world_position = model_matrix * vec4<f32>(vertex.position, 1.0);
 
     var out: VertexOutput;
     out.clip_position = camera.view_proj * world_position;
     out.world_normal = world_normal;
     out.world_position = world_position;
+#ifdef VERTEX_UV_0
     out.uv = vertex.uv;
+#else
+    out.uv = vec2<f32>(0.0, 0.0);
+#endif
+#ifdef VERTEX_UV_1
+    out.uv1 = vertex.uv1;
+#else
+    out.uv1 = out.uv;
+#endif
+#ifdef VERTEX_COLOR
+    out.color = vertex.color;
+#else
+    out.color = vec4<f32>(1.0, 1.0, 1.0, 1.0);
+#endif
 
+#ifdef VERTEX_TANGENT
     if ((material.flags & MATERIAL_FLAGS_USE_NORMAL_MAP) != 0u) {
-        let world_tangent = normalize(normal_matrix * vertex.tangent);
-        let world_bitangent = normalize(normal_matrix * vertex.bitangent);
+        let world_tangent = normalize(normal_matrix * vertex.tangent.xyz);
+        // The uv origin is in the top left so the bitangent is flipped
+        // to use normal maps with the green channel pointing up
+        let world_bitangent = -vertex.tangent.w * normalize(cross(world_normal, world_tangent));
         let tangent_matrix = transpose(mat3x3<f32>(
             world_tangent,
             world_bitangent,
@@ -145,28 +179,63 @@ fn vertex(
         out.tangent_view_position = tangent_matrix * camera.view_pos.xyz;
         out.tangent_light_position = tangent_matrix * light.position;
     }
+#endif
 
     return out;
 }
 
-[[stage(fragment)]]
-fn fragment(in: VertexOutput) -> [[location(0)]] vec4<f32> {
-    let object_color: vec4<f32> = textureSample(t_diffuse, s_diffuse, in.uv);
-    var object_specular: vec4<f32> = textureSample(t_spec, s_spec, in.uv);
+// Selects the uv set of a texture and applies its transform
+fn texture_uv(in: VertexOutput, channel: u32, transform: mat3x3<f32>) -> vec2<f32> {
+    var uv = in.uv;
+    if (channel == 1u) {
+        uv = in.uv1;
+    }
+    return (transform * vec3<f32>(uv, 1.0)).xy;
+}
+
+// Back faces are only rasterized for double sided materials
+fn shade(in: VertexOutput, is_front: bool) -> vec4<f32> {
+    let diffuse_uv = texture_uv(in, material.uv_channels.x, material.diffuse_uv_transform);
+    let normal_uv = texture_uv(in, material.uv_channels.y, material.normal_uv_transform);
+    let specular_uv = texture_uv(in, material.uv_channels.z, material.specular_uv_transform);
+    let emissive_uv = texture_uv(in, material.uv_channels.w, material.emissive_uv_transform);
+
+    let object_color: vec4<f32> = textureSample(t_diffuse, s_diffuse, diffuse_uv) * in.color;
+    let alpha = object_color.a * material.base_color.a;
+    if ((material.flags & MATERIAL_FLAGS_ALPHA_MASK) != 0u && alpha < material.alpha_cutoff) {
+        discard;
+    }
+    var object_specular: vec4<f32> = textureSample(t_spec, s_spec, specular_uv);
     object_specular = vec4<f32>(1.0, 1.0, 1.0, 1.0) - object_specular;
+    let object_emissive: vec4<f32> = textureSample(t_emissive, s_emissive, emissive_uv);
 
     var N: vec3<f32>;
     var L: vec3<f32>;
     var V: vec3<f32>;
 
-    if ((material.flags & MATERIAL_FLAGS_USE_NORMAL_MAP) != 0u) {
-        let object_normal: vec4<f32> = textureSample(t_normal, s_normal, in.uv);
+    var world_normal = normalize(in.world_normal);
+    if (!is_front) {
+        world_normal = -world_normal;
+    }
+
+    // The normal map can't be used without the tangents of the mesh
+    var use_normal_map = false;
+#ifdef VERTEX_TANGENT
+    use_normal_map = (material.flags & MATERIAL_FLAGS_USE_NORMAL_MAP) != 0u;
+#endif
+
+    if (use_normal_map) {
+        let object_normal: vec4<f32> = textureSample(t_normal, s_normal, normal_uv);
         N = object_normal.xyz * 2.0 - 1.0;
+        // Flipping the whole tangent basis for back faces is the same as flipping the normal
+        if (!is_front) {
+            N = -N;
+        }
         L = normalize(in.tangent_light_position - in.tangent_position);
         V = normalize(in.tangent_view_position - in.tangent_position);
         // return vec4<f32>(0.0, 0.0, 1.0, 1.0);
     } else {
-        N = normalize(in.world_normal);
+        N = world_normal;
         L = normalize(light.position - in.world_position.xyz);
         V = normalize(camera.view_pos.xyz - in.world_position.xyz);
         // return vec4<f32>(1.0, 0.0, 0.0, 1.0);
@@ -179,22 +248,61 @@ fn fragment(in: VertexOutput) -> [[location(0)]] vec4<f32> {
 
     let diffuse_strength = max(dot(N, L), 0.0);
 
-    var specular_strength = max(dot(in.world_normal, H), 0.0);
+    var specular_strength = max(dot(world_normal, H), 0.0);
     // Make sure the specular light doesn't go pass the lambertian diffuse light
     // this fixes a small artifact, but introduces very sharp cutoff
     specular_strength = specular_strength * f32(diffuse_strength > 0.0);
     let specular_exp = exp2(material.gloss * 11.0) + 2.0;
     specular_strength = pow(specular_strength, specular_exp);
 
-    let ambient_color = ambient_strength * object_color.rgb * material.base_color.rgb;
+    let ambient_occlusion = textureLoad(t_ssao, vec2<i32>(in.clip_position.xy), 0).r;
+
+    let ambient_color = ambient_strength * ambient_occlusion * object_color.rgb * material.base_color.rgb;
     let diffuse_color = diffuse_strength * object_color.rgb * material.base_color.rgb;
     let specular_color = specular_strength * object_specular.rgb * material.specular_color;
-    let result = (ambient_color + diffuse_color + specular_color) * light.color;
+    // Emissive light isn't affected by the scene lights
+    let emissive_color = object_emissive.rgb * material.emissive * material.emissive_intensity;
+    var result = (ambient_color + diffuse_color + specular_color) * light.color + emissive_color;
+    result = apply_fog(result, in.world_position.xyz);
     // let result = diffuse_color;
     // let result = specular_color;
     // let result = object_color.rgb;
     // let result = material.base_color.rgb;
     // let result = N;
 
-    return vec4<f32>(result, object_color.a);
+    return vec4<f32>(result, alpha);
+}
+
+[[stage(fragment)]]
+fn fragment(
+    in: VertexOutput,
+    [[builtin(front_facing)]] is_front: bool,
+) -> [[location(0)]] vec4<f32> {
+    return shade(in, is_front);
+}
+
+struct OitOutput {
+    [[location(0)]] accum: vec4<f32>;
+    [[location(1)]] revealage: f32;
+};
+
+// Weighted blended order independent transparency
+// <https://jcgt.org/published/0002/02/09/>
+[[stage(fragment)]]
+fn fragment_oit(
+    in: VertexOutput,
+    [[builtin(front_facing)]] is_front: bool,
+) -> OitOutput {
+    let color = shade(in, is_front);
+
+    // Closer and more opaque fragments get a bigger weight
+    let depth = in.clip_position.z;
+    let a = min(1.0, color.a * 10.0) + 0.01;
+    let b = 1.0 - depth * 0.9;
+    let weight = clamp(a * a * a * 100000000.0 * b * b * b, 0.01, 3000.0);
+
+    var out: OitOutput;
+    out.accum = vec4<f32>(color.rgb * color.a, color.a) * weight;
+    out.revealage = color.a;
+    return out;
 }
@@ -0,0 +1,98 @@
+// Screen space ambient occlusion computed from the depth buffer
+// The normals are reconstructed from the neighbouring depth values
+
+struct SsaoUniform {
+    projection: mat4x4<f32>;
+    inverse_projection: mat4x4<f32>;
+    radius: f32;
+    intensity: f32;
+    bias: f32;
+    sample_count: u32;
+    samples: array<vec4<f32>, 64>;
+};
+[[group(0), binding(0)]]
+var<uniform> ssao: SsaoUniform;
+[[group(0), binding(1)]]
+var depth_texture: texture_depth_2d;
+
+#import fullscreen
+
+fn view_position(coords: vec2<i32>) -> vec3<f32> {
+    let size = textureDimensions(depth_texture);
+    let clamped = min(max(coords, vec2<i32>(0, 0)), size - vec2<i32>(1, 1));
+    let depth = textureLoad(depth_texture, clamped, 0);
+    let uv = (vec2<f32>(clamped) + vec2<f32>(0.5, 0.5)) / vec2<f32>(size);
+    let ndc = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth, 1.0);
+    let position = ssao.inverse_projection * ndc;
+    return position.xyz / position.w;
+}
+
+fn hash(p: vec2<f32>) -> f32 {
+    return fract(sin(dot(p, vec2<f32>(12.9898, 78.233))) * 43758.5453);
+}
+
+fn smooth_step(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = clamp((x - edge0) / (edge1 - edge0), 0.0, 1.0);
+    return t * t * (3.0 - 2.0 * t);
+}
+
+[[stage(fragment)]]
+fn fragment(in: FullscreenVertexOutput) -> [[location(0)]] vec4<f32> {
+    let coords = vec2<i32>(in.position.xy);
+    if (textureLoad(depth_texture, coords, 0) >= 1.0) {
+        // Nothing was rendered here
+        return vec4<f32>(1.0, 1.0, 1.0, 1.0);
+    }
+
+    let position = view_position(coords);
+
+    // Use the neighbour with the smallest depth difference to avoid artifacts on edges
+    let right = view_position(coords + vec2<i32>(1, 0)) - position;
+    let left = position - view_position(coords - vec2<i32>(1, 0));
+    let down = view_position(coords + vec2<i32>(0, 1)) - position;
+    let up = position - view_position(coords - vec2<i32>(0, 1));
+    var dx = right;
+    if (abs(left.z) < abs(right.z)) {
+        dx = left;
+    }
+    var dy = down;
+    if (abs(up.z) < abs(down.z)) {
+        dy = up;
+    }
+    var normal = normalize(cross(dy, dx));
+    // The normal should always point towards the camera
+    if (dot(normal, position) > 0.0) {
+        normal = -normal;
+    }
+
+    // Randomly rotate the kernel around the normal for each pixel
+    let random = vec3<f32>(
+        hash(in.position.xy) * 2.0 - 1.0,
+        hash(in.position.yx + vec2<f32>(1.0, 1.0)) * 2.0 - 1.0,
+        0.0,
+    );
+    let tangent = normalize(random - normal * dot(random, normal));
+    let bitangent = cross(normal, tangent);
+    let tbn = mat3x3<f32>(tangent, bitangent, normal);
+
+    let size = vec2<f32>(textureDimensions(depth_texture));
+    let sample_count = min(ssao.sample_count, 64u);
+    var occlusion = 0.0;
+    for (var i = 0u; i < sample_count; i = i + 1u) {
+        let sample_position = position + (tbn * ssao.samples[i].xyz) * ssao.radius;
+
+        let clip = ssao.projection * vec4<f32>(sample_position, 1.0);
+        let ndc = clip.xy / clip.w;
+        let uv = vec2<f32>(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+        let sample_depth = view_position(vec2<i32>(uv * size)).z;
+
+        // Avoid occlusion from geometry that is far away from the sampled point
+        let range_check = smooth_step(0.0, 1.0, ssao.radius / abs(position.z - sample_depth));
+        if (sample_depth >= sample_position.z + ssao.bias) {
+            occlusion = occlusion + range_check;
+        }
+    }
+
+    let ao = pow(1.0 - occlusion / f32(sample_count), ssao.intensity);
+    return vec4<f32>(ao, ao, ao, 1.0);
+}
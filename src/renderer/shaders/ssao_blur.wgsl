@@ -0,0 +1,23 @@
+// Box blur used to remove the noise introduced by the random rotation of the ssao kernel
+
+[[group(0), binding(0)]]
+var ao_texture: texture_2d<f32>;
+
+#import fullscreen
+
+[[stage(fragment)]]
+fn fragment(in: FullscreenVertexOutput) -> [[location(0)]] vec4<f32> {
+    let coords = vec2<i32>(in.position.xy);
+    let size = textureDimensions(ao_texture);
+
+    var result = 0.0;
+    for (var x = -2; x < 2; x = x + 1) {
+        for (var y = -2; y < 2; y = y + 1) {
+            let sample_coords = min(max(coords + vec2<i32>(x, y), vec2<i32>(0, 0)), size - vec2<i32>(1, 1));
+            result = result + textureLoad(ao_texture, sample_coords, 0).r;
+        }
+    }
+
+    let ao = result / 16.0;
+    return vec4<f32>(ao, ao, ao, 1.0);
+}
@@ -0,0 +1,349 @@
+use bevy::{
+    math::{Mat4, Vec3, Vec4},
+    prelude::*,
+    render::render_resource::{encase, ShaderType},
+};
+use wgpu::util::DeviceExt;
+
+use crate::{camera::Camera, renderer::WgpuRenderer, texture::Texture};
+
+use super::render_phase_3d::DepthTexture;
+
+// WARN this must match the size of the samples array in ssao.wgsl
+pub const MAX_SSAO_SAMPLES: usize = 64;
+const SSAO_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R8Unorm;
+
+pub struct SsaoSettings {
+    pub enabled: bool,
+    /// Radius of the sampled hemisphere in view space units
+    pub radius: f32,
+    /// Exponent applied to the final ambient occlusion factor
+    pub intensity: f32,
+    /// Used to avoid acne on flat surfaces
+    pub bias: f32,
+    pub sample_count: u32,
+}
+
+impl Default for SsaoSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            radius: 0.5,
+            intensity: 1.0,
+            bias: 0.025,
+            sample_count: 16,
+        }
+    }
+}
+
+#[derive(ShaderType)]
+struct SsaoUniform {
+    projection: Mat4,
+    inverse_projection: Mat4,
+    radius: f32,
+    intensity: f32,
+    bias: f32,
+    sample_count: u32,
+    samples: [Vec4; MAX_SSAO_SAMPLES],
+}
+
+impl SsaoUniform {
+    fn new(camera: &Camera, settings: &SsaoSettings) -> Self {
+        let projection = camera.projection.compute_matrix();
+        Self {
+            projection,
+            inverse_projection: projection.inverse(),
+            radius: settings.radius,
+            intensity: settings.intensity,
+            bias: settings.bias,
+            sample_count: settings.sample_count.min(MAX_SSAO_SAMPLES as u32),
+            samples: generate_kernel(),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = encase::UniformBuffer::new(Vec::new());
+        buffer.write(self).expect("failed to write ssao uniform");
+        let bytes: &Vec<u8> = buffer.as_ref();
+        bytes.clone()
+    }
+}
+
+/// Generates sample points in a hemisphere oriented towards +Z.
+/// The samples are more densely packed close to the origin.
+fn generate_kernel() -> [Vec4; MAX_SSAO_SAMPLES] {
+    // Uses low discrepancy sequences instead of random numbers to avoid depending on a rng
+    fn radical_inverse(i: u32) -> f32 {
+        i.reverse_bits() as f32 * 2.328_306_4e-10
+    }
+
+    let mut samples = [Vec4::ZERO; MAX_SSAO_SAMPLES];
+    for (i, sample) in samples.iter_mut().enumerate() {
+        let phi = (i as f32 * 0.618_034).fract() * std::f32::consts::TAU;
+        let cos_theta = radical_inverse(i as u32 + 1);
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        let direction = Vec3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, cos_theta);
+
+        let length = (i as f32 * 0.754_877_7).fract().max(0.1);
+        let scale = i as f32 / MAX_SSAO_SAMPLES as f32;
+        let scale = 0.1 + 0.9 * scale * scale;
+
+        *sample = (direction * length * scale).extend(0.0);
+    }
+    samples
+}
+
+/// Computes a screen space ambient occlusion texture from the depth buffer.
+/// The blurred result can be bound with `output_bind_group` and is white when ssao is disabled.
+pub struct SsaoPass {
+    uniform_buffer: wgpu::Buffer,
+    ssao_layout: wgpu::BindGroupLayout,
+    blur_layout: wgpu::BindGroupLayout,
+    pub output_layout: wgpu::BindGroupLayout,
+    ssao_pipeline: wgpu::RenderPipeline,
+    blur_pipeline: wgpu::RenderPipeline,
+    ao_texture: Texture,
+    blurred_ao_texture: Texture,
+    ssao_bind_group: wgpu::BindGroup,
+    blur_bind_group: wgpu::BindGroup,
+    pub output_bind_group: wgpu::BindGroup,
+}
+
+impl SsaoPass {
+    pub fn new(
+        renderer: &WgpuRenderer,
+        depth_texture: &Texture,
+        camera: &Camera,
+        settings: &SsaoSettings,
+    ) -> Self {
+        let device = &renderer.device;
+
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("SSAO Buffer"),
+            contents: &SsaoUniform::new(camera, settings).to_bytes(),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let ssao_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("ssao_bind_group_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let blur_layout = single_texture_layout(device, "ssao_blur_bind_group_layout");
+        let output_layout = single_texture_layout(device, "ssao_output_bind_group_layout");
+
+        let ssao_pipeline = renderer.create_fullscreen_pipeline(
+            "SSAO Pipeline",
+            include_str!("shaders/ssao.wgsl"),
+            &device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("SSAO Pipeline Layout"),
+                bind_group_layouts: &[&ssao_layout],
+                push_constant_ranges: &[],
+            }),
+            SSAO_FORMAT,
+            None,
+        );
+        let blur_pipeline = renderer.create_fullscreen_pipeline(
+            "SSAO Blur Pipeline",
+            include_str!("shaders/ssao_blur.wgsl"),
+            &device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("SSAO Blur Pipeline Layout"),
+                bind_group_layouts: &[&blur_layout],
+                push_constant_ranges: &[],
+            }),
+            SSAO_FORMAT,
+            None,
+        );
+
+        let ao_texture =
+            Texture::create_render_target(device, &renderer.config, SSAO_FORMAT, "ao_texture");
+        let blurred_ao_texture = Texture::create_render_target(
+            device,
+            &renderer.config,
+            SSAO_FORMAT,
+            "blurred_ao_texture",
+        );
+
+        let ssao_bind_group = ssao_bind_group(device, &ssao_layout, &uniform_buffer, depth_texture);
+        let blur_bind_group = single_texture_bind_group(device, &blur_layout, &ao_texture);
+        let output_bind_group =
+            single_texture_bind_group(device, &output_layout, &blurred_ao_texture);
+
+        Self {
+            uniform_buffer,
+            ssao_layout,
+            blur_layout,
+            output_layout,
+            ssao_pipeline,
+            blur_pipeline,
+            ao_texture,
+            blurred_ao_texture,
+            ssao_bind_group,
+            blur_bind_group,
+            output_bind_group,
+        }
+    }
+
+    pub fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        depth_texture: &Texture,
+    ) {
+        self.ao_texture = Texture::create_render_target(device, config, SSAO_FORMAT, "ao_texture");
+        self.blurred_ao_texture =
+            Texture::create_render_target(device, config, SSAO_FORMAT, "blurred_ao_texture");
+
+        self.ssao_bind_group = ssao_bind_group(
+            device,
+            &self.ssao_layout,
+            &self.uniform_buffer,
+            depth_texture,
+        );
+        self.blur_bind_group =
+            single_texture_bind_group(device, &self.blur_layout, &self.ao_texture);
+        self.output_bind_group =
+            single_texture_bind_group(device, &self.output_layout, &self.blurred_ao_texture);
+    }
+
+    /// Expects the depth texture to already contain the depth of every opaque mesh
+    pub fn render(&self, enabled: bool, encoder: &mut wgpu::CommandEncoder) {
+        // When disabled the passes still run to clear the textures to white
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("SSAO Render Pass"),
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: &self.ao_texture.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+            if enabled {
+                render_pass.set_pipeline(&self.ssao_pipeline);
+                render_pass.set_bind_group(0, &self.ssao_bind_group, &[]);
+                render_pass.draw(0..3, 0..1);
+            }
+        }
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("SSAO Blur Render Pass"),
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view: &self.blurred_ao_texture.view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+        if enabled {
+            render_pass.set_pipeline(&self.blur_pipeline);
+            render_pass.set_bind_group(0, &self.blur_bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+    }
+}
+
+fn single_texture_layout(device: &wgpu::Device, label: &str) -> wgpu::BindGroupLayout {
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some(label),
+        entries: &[wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        }],
+    })
+}
+
+fn single_texture_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    texture: &Texture,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: None,
+        layout,
+        entries: &[wgpu::BindGroupEntry {
+            binding: 0,
+            resource: wgpu::BindingResource::TextureView(&texture.view),
+        }],
+    })
+}
+
+fn ssao_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    uniform_buffer: &wgpu::Buffer,
+    depth_texture: &Texture,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("ssao_bind_group"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(&depth_texture.view),
+            },
+        ],
+    })
+}
+
+pub fn init_ssao_pass(
+    mut commands: Commands,
+    renderer: Res<WgpuRenderer>,
+    depth_texture: Res<DepthTexture>,
+    camera: Res<Camera>,
+    settings: Res<SsaoSettings>,
+) {
+    let ssao_pass = SsaoPass::new(&renderer, &depth_texture.0, &camera, &settings);
+    commands.insert_resource(ssao_pass);
+}
+
+pub fn update_ssao_buffer(
+    renderer: Res<WgpuRenderer>,
+    camera: Res<Camera>,
+    settings: Res<SsaoSettings>,
+    ssao_pass: Res<SsaoPass>,
+) {
+    if camera.is_changed() || settings.is_changed() {
+        renderer.queue.write_buffer(
+            &ssao_pass.uniform_buffer,
+            0,
+            &SsaoUniform::new(&camera, &settings).to_bytes(),
+        );
+    }
+}
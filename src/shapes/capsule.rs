@@ -50,6 +50,11 @@ pub enum CapsuleUvProfile {
 impl Capsule {
     #[allow(unused)]
     pub fn mesh(&self, device: &wgpu::Device) -> ModelMesh {
+        ModelMesh::from_mesh("capsule", device, &self.to_mesh())
+    }
+
+    /// Builds the mesh on the cpu without uploading it
+    pub fn to_mesh(&self) -> Mesh {
         // code adapted from https://behreajj.medium.com/making-a-capsule-mesh-via-script-in-five-3d-environments-c2214abf02db
 
         let Capsule {
@@ -367,14 +372,6 @@ impl Capsule {
         assert_eq!(vertices.len(), vert_len);
         assert_eq!(indices.len(), fs_len);
 
-        ModelMesh::from_mesh(
-            "capsule",
-            device,
-            &Mesh {
-                vertices,
-                indices: Some(indices),
-                material_id: None,
-            },
-        )
+        Mesh::from_vertices(&vertices, Some(indices))
     }
 }
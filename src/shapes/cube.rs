@@ -28,6 +28,11 @@ impl Cube {
     }
 
     pub fn mesh(&self, device: &wgpu::Device) -> ModelMesh {
+        ModelMesh::from_mesh("cube", device, &self.to_mesh())
+    }
+
+    /// Builds the mesh on the cpu without uploading it
+    pub fn to_mesh(&self) -> Mesh {
         #[rustfmt::skip]
         let vertices = vec![
             // Top
@@ -73,14 +78,6 @@ impl Cube {
             20, 21, 22, 22, 23, 20, // back
         ];
 
-        ModelMesh::from_mesh(
-            "cube",
-            device,
-            &Mesh {
-                vertices,
-                indices: Some(indices),
-                material_id: None,
-            },
-        )
+        Mesh::from_vertices(&vertices, Some(indices))
     }
 }
@@ -31,6 +31,11 @@ impl Default for Cylinder {
 impl Cylinder {
     #[allow(unused)]
     pub fn mesh(&self, device: &wgpu::Device) -> ModelMesh {
+        ModelMesh::from_mesh("cylinder", device, &self.to_mesh())
+    }
+
+    /// Builds the mesh on the cpu without uploading it
+    pub fn to_mesh(&self) -> Mesh {
         assert!(
             self.radius > 0.0 && self.height > 0.0 && self.resolution > 0 && self.subdivisions > 0
         );
@@ -123,14 +128,6 @@ impl Cylinder {
             vertices.push(Vertex::from_arrays(*position, normals[i], uvs[i]));
         }
 
-        ModelMesh::from_mesh(
-            "cylinder",
-            device,
-            &Mesh {
-                vertices,
-                indices: Some(indices),
-                material_id: None,
-            },
-        )
+        Mesh::from_vertices(&vertices, Some(indices))
     }
 }
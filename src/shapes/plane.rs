@@ -21,6 +21,11 @@ impl Default for Plane {
 impl Plane {
     #[allow(unused)]
     pub fn mesh(&self, device: &wgpu::Device) -> ModelMesh {
+        ModelMesh::from_mesh("capsule", device, &self.to_mesh())
+    }
+
+    /// Builds the mesh on the cpu without uploading it
+    pub fn to_mesh(&self) -> Mesh {
         let mut vertices = Vec::with_capacity((self.resolution + 1) * (self.resolution + 1));
         let resolution_modifier = self.size / self.resolution as f32;
         for y in 0..=self.resolution {
@@ -63,14 +68,6 @@ impl Plane {
             .map(|(position, normal, uv)| Vertex::from_arrays(*position, *normal, *uv))
             .collect();
 
-        ModelMesh::from_mesh(
-            "capsule",
-            device,
-            &Mesh {
-                vertices,
-                indices: Some(indices),
-                material_id: None,
-            },
-        )
+        Mesh::from_vertices(&vertices, Some(indices))
     }
 }
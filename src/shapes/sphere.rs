@@ -30,6 +30,11 @@ impl Default for UVSphere {
 impl UVSphere {
     #[allow(unused)]
     pub fn mesh(&self, device: &wgpu::Device) -> ModelMesh {
+        ModelMesh::from_mesh("uv_sphere", device, &self.to_mesh())
+    }
+
+    /// Builds the mesh on the cpu without uploading it
+    pub fn to_mesh(&self) -> Mesh {
         // Largely inspired from http://www.songho.ca/opengl/gl_self.html
 
         let sectors = self.sectors as f32;
@@ -88,14 +93,6 @@ impl UVSphere {
             vertices.push(Vertex::from_arrays(*position, normals[i], uvs[i]));
         }
 
-        ModelMesh::from_mesh(
-            "uv_sphere",
-            device,
-            &Mesh {
-                vertices,
-                indices: Some(indices),
-                material_id: None,
-            },
-        )
+        Mesh::from_vertices(&vertices, Some(indices))
     }
 }
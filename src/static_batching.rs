@@ -0,0 +1,26 @@
+use bevy::utils::HashMap;
+
+use crate::mesh::{Mesh, MeshVertexLayout};
+
+/// Merges the meshes that use the same material and the same vertex layout so they can be drawn with a single draw call.
+/// Every mesh of a model is in the space of the model, so merging them doesn't move any vertex.
+/// The merged meshes are in the order of the first mesh of each group
+pub fn merge_meshes(meshes: Vec<Mesh>) -> Vec<Mesh> {
+    let mut merged: Vec<Mesh> = vec![];
+    let mut batches: HashMap<(Option<usize>, MeshVertexLayout, bool), usize> = HashMap::default();
+    for mesh in meshes {
+        let key = (
+            mesh.material_id,
+            mesh.vertex_buffer_layout(),
+            mesh.indices.is_some(),
+        );
+        match batches.get(&key) {
+            Some(batch) => merged[*batch].append(&mesh),
+            None => {
+                batches.insert(key, merged.len());
+                merged.push(mesh);
+            }
+        }
+    }
+    merged
+}
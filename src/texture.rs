@@ -1,10 +1,195 @@
-use image::DynamicImage;
+use std::{
+    num::NonZeroU8,
+    sync::{Arc, RwLock},
+};
+
+use bevy::utils::HashMap;
+use image::{DynamicImage, RgbaImage};
+use wgpu::util::DeviceExt;
+
+use crate::{
+    compressed_texture::{is_ktx2, load_ktx2, CompressedImage, UploadData},
+    image_utils::{generate_mips, mip_level_count},
+    renderer::WgpuRenderer,
+};
+
+/// The cpu data of a texture
+#[derive(Debug, Clone)]
+pub enum TextureData {
+    Rgba(RgbaImage),
+    /// Usually loaded from a KTX2 file
+    Compressed(CompressedImage),
+}
+
+impl TextureData {
+    /// Decodes a texture file, the format is guessed from the path
+    pub fn from_file_bytes(path: &str, bytes: &[u8]) -> anyhow::Result<Self> {
+        Ok(if is_ktx2(path) {
+            Self::Compressed(load_ktx2(bytes)?)
+        } else {
+            Self::Rgba(image::load_from_memory(bytes)?.to_rgba8())
+        })
+    }
+}
+
+/// The features of the device, shared with the asset loaders so they can reject compressed textures
+/// that the renderer can't upload. It's set when the renderer is created,
+/// before that or in apps without a renderer every texture is accepted
+#[derive(Debug, Clone, Default)]
+pub struct DeviceFeatures(Arc<RwLock<Option<wgpu::Features>>>);
+
+impl DeviceFeatures {
+    pub fn set(&self, features: wgpu::Features) {
+        *self.0.write().unwrap() = Some(features);
+    }
+
+    /// Returns an error if the texture can't be uploaded on the device
+    pub fn check(&self, texture: &TextureData) -> anyhow::Result<()> {
+        match (texture, *self.0.read().unwrap()) {
+            (TextureData::Compressed(image), Some(features)) => image.check_supported(features),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// How the color channels of a texture are encoded.
+/// Textures with a color role like the diffuse or emissive map are usually authored in sRGB,
+/// textures containing data like normals or metallic-roughness values must not be linearized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorSpace {
+    Srgb,
+    Linear,
+    /// Linear data storing unit vectors like tangent space normals, the mips are renormalized
+    Normal,
+}
+
+impl ColorSpace {
+    /// The uncompressed format used to upload rgba images in this color space
+    pub fn rgba_format(self) -> wgpu::TextureFormat {
+        match self {
+            ColorSpace::Srgb => wgpu::TextureFormat::Rgba8UnormSrgb,
+            ColorSpace::Linear | ColorSpace::Normal => wgpu::TextureFormat::Rgba8Unorm,
+        }
+    }
+
+    /// The color space of an uncompressed format, normals can't be distinguished from linear data
+    fn from_rgba_format(format: wgpu::TextureFormat) -> Self {
+        if format.describe().srgb {
+            ColorSpace::Srgb
+        } else {
+            ColorSpace::Linear
+        }
+    }
+}
+
+impl From<RgbaImage> for TextureData {
+    fn from(image: RgbaImage) -> Self {
+        Self::Rgba(image)
+    }
+}
+
+/// Describes how a material texture is sampled.
+/// Identical descriptors share the same sampler through the `SamplerCache`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SamplerDescriptor {
+    pub address_mode_u: wgpu::AddressMode,
+    pub address_mode_v: wgpu::AddressMode,
+    pub mag_filter: wgpu::FilterMode,
+    pub min_filter: wgpu::FilterMode,
+    pub mipmap_filter: wgpu::FilterMode,
+    /// Max anisotropy used for anisotropic filtering. Valid values are 1, 2, 4, 8 and 16,
+    /// other values are rounded down to the closest valid value.
+    /// 1 disables anisotropic filtering. It's ignored if any of the filters is Nearest
+    /// or if the adapter doesn't support anisotropic filtering.
+    pub anisotropy_clamp: u8,
+}
+
+impl Default for SamplerDescriptor {
+    fn default() -> Self {
+        Self {
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            anisotropy_clamp: 16,
+        }
+    }
+}
+
+impl SamplerDescriptor {
+    pub fn clamp_to_edge() -> Self {
+        Self {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            ..Default::default()
+        }
+    }
+
+    /// wgpu only accepts 1, 2, 4, 8 or 16
+    fn valid_anisotropy_clamp(&self) -> u8 {
+        match self.anisotropy_clamp {
+            0..=1 => 1,
+            2..=3 => 2,
+            4..=7 => 4,
+            8..=15 => 8,
+            _ => 16,
+        }
+    }
+
+    fn to_wgpu(self, anisotropy_supported: bool) -> wgpu::SamplerDescriptor<'static> {
+        let all_linear = self.mag_filter == wgpu::FilterMode::Linear
+            && self.min_filter == wgpu::FilterMode::Linear
+            && self.mipmap_filter == wgpu::FilterMode::Linear;
+        let anisotropy_clamp = if all_linear && anisotropy_supported {
+            NonZeroU8::new(self.valid_anisotropy_clamp()).filter(|clamp| clamp.get() > 1)
+        } else {
+            None
+        };
+
+        wgpu::SamplerDescriptor {
+            label: None,
+            address_mode_u: self.address_mode_u,
+            address_mode_v: self.address_mode_v,
+            address_mode_w: wgpu::AddressMode::Repeat,
+            mag_filter: self.mag_filter,
+            min_filter: self.min_filter,
+            mipmap_filter: self.mipmap_filter,
+            anisotropy_clamp,
+            ..Default::default()
+        }
+    }
+}
+
+/// Dedupes the samplers of every material texture
+#[derive(Default)]
+pub struct SamplerCache(HashMap<SamplerDescriptor, wgpu::Sampler>);
+
+impl SamplerCache {
+    /// Creates the sampler if it doesn't exist yet
+    pub fn prepare(&mut self, renderer: &WgpuRenderer, descriptor: SamplerDescriptor) {
+        let anisotropy_supported = renderer
+            .downlevel_flags
+            .contains(wgpu::DownlevelFlags::ANISOTROPIC_FILTERING);
+        self.0.entry(descriptor).or_insert_with(|| {
+            renderer
+                .device
+                .create_sampler(&descriptor.to_wgpu(anisotropy_supported))
+        });
+    }
+
+    /// The sampler needs to be prepared first
+    pub fn get(&self, descriptor: &SamplerDescriptor) -> &wgpu::Sampler {
+        self.0
+            .get(descriptor)
+            .expect("Tried to get a sampler that wasn't prepared")
+    }
+}
 
 #[derive(Debug)]
 pub struct Texture {
     pub texture: wgpu::Texture,
     pub view: wgpu::TextureView,
-    pub sampler: wgpu::Sampler,
 }
 
 impl Texture {
@@ -57,6 +242,25 @@ impl Texture {
         format: Option<wgpu::TextureFormat>,
     ) -> anyhow::Result<Self> {
         let format = format.unwrap_or(wgpu::TextureFormat::Rgba8UnormSrgb);
+        Self::from_rgba(
+            device,
+            queue,
+            rgba,
+            label,
+            format,
+            ColorSpace::from_rgba_format(format),
+        )
+    }
+
+    /// The color space decides how the mips are filtered
+    fn from_rgba(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        rgba: &image::RgbaImage,
+        label: Option<&str>,
+        format: wgpu::TextureFormat,
+        color_space: ColorSpace,
+    ) -> anyhow::Result<Self> {
         let (texture_width, texture_height) = rgba.dimensions();
 
         let size = wgpu::Extent3d {
@@ -67,44 +271,114 @@ impl Texture {
         let texture = device.create_texture(&wgpu::TextureDescriptor {
             label,
             size,
-            mip_level_count: 1,
+            mip_level_count: mip_level_count(texture_width, texture_height),
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
             format,
             usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
         });
 
-        queue.write_texture(
-            wgpu::ImageCopyTexture {
-                aspect: wgpu::TextureAspect::All,
-                texture: &texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
+        let mips = generate_mips(rgba, color_space);
+        for (mip_level, image) in std::iter::once(rgba).chain(mips.iter()).enumerate() {
+            let (width, height) = image.dimensions();
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &texture,
+                    mip_level: mip_level as u32,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                image,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(4 * width),
+                    rows_per_image: std::num::NonZeroU32::new(height),
+                },
+                wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        Ok(Self { texture, view })
+    }
+
+    /// The color space is only used for rgba images, compressed images already know their format.
+    /// Compressed images are decompressed if the device doesn't support their format.
+    pub fn from_texture_data(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        data: &TextureData,
+        label: Option<&str>,
+        color_space: ColorSpace,
+    ) -> anyhow::Result<Self> {
+        match data {
+            TextureData::Rgba(rgba) => Self::from_rgba(
+                device,
+                queue,
+                rgba,
+                label,
+                color_space.rgba_format(),
+                color_space,
+            ),
+            TextureData::Compressed(image) => match image.prepare(device.features())? {
+                UploadData::Compressed { format, levels } => Ok(Self::from_compressed(
+                    device,
+                    queue,
+                    image.width,
+                    image.height,
+                    format,
+                    &levels,
+                    label,
+                )),
+                UploadData::Rgba(rgba, format) => {
+                    // Decompressed normals keep being renormalized
+                    let color_space = match color_space {
+                        ColorSpace::Normal => ColorSpace::Normal,
+                        _ => ColorSpace::from_rgba_format(format),
+                    };
+                    Self::from_rgba(device, queue, &rgba, label, format, color_space)
+                }
             },
-            rgba,
-            wgpu::ImageDataLayout {
-                offset: 0,
-                bytes_per_row: std::num::NonZeroU32::new(4 * texture_width),
-                rows_per_image: std::num::NonZeroU32::new(texture_height),
+        }
+    }
+
+    /// Uploads every mip level of a block compressed image.
+    /// The device must support the format.
+    fn from_compressed(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        width: u32,
+        height: u32,
+        format: wgpu::TextureFormat,
+        levels: &[Vec<u8>],
+        label: Option<&str>,
+    ) -> Self {
+        let texture = device.create_texture_with_data(
+            queue,
+            &wgpu::TextureDescriptor {
+                label,
+                size: wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: levels.len() as u32,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
             },
-            size,
+            &levels.concat(),
         );
 
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-            address_mode_u: wgpu::AddressMode::Repeat,
-            address_mode_v: wgpu::AddressMode::Repeat,
-            address_mode_w: wgpu::AddressMode::Repeat,
-            mag_filter: wgpu::FilterMode::Linear,
-            min_filter: wgpu::FilterMode::Linear,
-            ..Default::default()
-        });
 
-        Ok(Self {
-            texture,
-            view,
-            sampler,
-        })
+        Self { texture, view }
     }
 
     pub fn create_depth_texture(
@@ -128,17 +402,34 @@ impl Texture {
         let texture = device.create_texture(&desc);
 
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-            compare: None,
-            lod_min_clamp: -100.0,
-            lod_max_clamp: 100.0,
-            ..Default::default()
+
+        Self { texture, view }
+    }
+
+    /// Creates a texture that can be rendered to and then sampled by another pass
+    pub fn create_render_target(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        format: wgpu::TextureFormat,
+        label: &str,
+    ) -> Self {
+        let size = wgpu::Extent3d {
+            width: config.width,
+            height: config.height,
+            depth_or_array_layers: 1,
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
         });
 
-        Self {
-            texture,
-            view,
-            sampler,
-        }
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        Self { texture, view }
     }
 }
@@ -0,0 +1,32 @@
+use bevy::{math::Vec4, prelude::*, reflect::TypeUuid, render::render_resource::ShaderType};
+
+use crate::renderer::custom_material::CustomMaterial;
+
+/// An example of a CustomMaterial that shades the mesh with a few hard bands of light
+#[derive(Debug, Clone, TypeUuid)]
+#[uuid = "5e1b6c2e-8f0a-4f0b-9d7e-2b9c3a6f4e11"]
+pub struct ToonMaterial {
+    pub color: Color,
+    pub bands: u32,
+}
+
+#[derive(ShaderType)]
+pub struct ToonMaterialUniform {
+    pub color: Vec4,
+    pub bands: u32,
+}
+
+impl CustomMaterial for ToonMaterial {
+    type Uniform = ToonMaterialUniform;
+
+    fn shader() -> &'static str {
+        include_str!("toon_material.wgsl")
+    }
+
+    fn uniform(&self) -> Self::Uniform {
+        ToonMaterialUniform {
+            color: Vec4::from(self.color.as_linear_rgba_f32()),
+            bands: self.bands.max(1),
+        }
+    }
+}
@@ -0,0 +1,26 @@
+struct ToonMaterial {
+    color: vec4<f32>;
+    bands: u32;
+};
+[[group(1), binding(0)]]
+var<uniform> material: ToonMaterial;
+
+[[stage(vertex)]]
+fn vertex(vertex: Vertex, instance: InstanceInput) -> MeshVertexOutput {
+    return mesh_vertex(vertex, instance);
+}
+
+[[stage(fragment)]]
+fn fragment(in: MeshVertexOutput) -> [[location(0)]] vec4<f32> {
+    let N = normalize(in.world_normal);
+    let L = normalize(light.position - in.world_position.xyz);
+
+    let bands = f32(material.bands);
+    let diffuse = floor(max(dot(N, L), 0.0) * bands) / bands;
+
+    let ambient = 0.1;
+    var result = (ambient + diffuse) * material.color.rgb * light.color;
+    result = apply_fog(result, in.world_position.xyz);
+
+    return vec4<f32>(result, material.color.a);
+}